@@ -1,6 +1,6 @@
 use super::{
     order::Order,
-    util::{deserialize_to_string, extract_payload_and_nonce, jws},
+    util::{b64, deserialize_to_string, extract_payload_location_and_nonce, jws, AcmeError},
     Nonce,
 };
 use crate::Environment;
@@ -33,7 +33,7 @@ impl Account {
         env: &Environment,
         domain: &str,
         csr: C,
-    ) -> Result<Order, Box<dyn Error>>
+    ) -> Result<Order, Box<dyn Error + Send + Sync>>
     where
         C: Into<String>,
     {
@@ -59,10 +59,50 @@ impl Account {
             .send()
             .await?;
 
-        let (nonce, mut order): (Nonce, Order) = extract_payload_and_nonce(response).await?;
+        let (location, nonce, mut order): (String, Nonce, Order) =
+            extract_payload_location_and_nonce(response).await?;
+        order.location = location;
         order.nonce = nonce;
         order.csr = csr.into();
 
         Ok(order)
     }
+
+    /// Revokes a certificate issued to this account, `certificate` is the DER encoded
+    /// certificate and `reason` an optional RFC 5280 revocation reason code.
+    pub async fn revoke_certificate(
+        &self,
+        client: &Client,
+        revoke_url: &str,
+        env: &Environment,
+        certificate: &[u8],
+        reason: Option<u8>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let header = json!({
+            "alg": "RS256",
+            "url": revoke_url,
+            "kid": self.account_location,
+            "nonce": self.nonce,
+        });
+
+        let mut payload = json!({ "certificate": b64(certificate) });
+        if let Some(reason) = reason {
+            payload["reason"] = json!(reason);
+        }
+
+        let payload = jws(payload, header, env).await?;
+
+        let response = client
+            .post(revoke_url)
+            .header("Content-Type", "application/jose+json")
+            .body(serde_json::to_string_pretty(&payload)?)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Box::new(AcmeError::from_response(response).await));
+        }
+
+        Ok(())
+    }
 }
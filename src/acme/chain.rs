@@ -0,0 +1,201 @@
+use base64::{engine, Engine};
+use std::{error::Error, fmt};
+use time::OffsetDateTime;
+use tracing::info;
+use x509_parser::{
+    certification_request::X509CertificationRequest, extensions::GeneralName, pem::Pem, prelude::FromDer,
+};
+
+/// How far the clocks of the CA and this app may drift apart before a certificate that
+/// just became valid is rejected as not yet valid.
+const CLOCK_SKEW_SECONDS: i64 = 300;
+
+/// Why a downloaded certificate chain was rejected.
+#[derive(Debug)]
+pub enum ChainError {
+    /// The download isn't a PEM chain of certificates.
+    Parse(String),
+    Empty,
+    /// The CSR from Key Vault couldn't be read to compare the public key with.
+    Csr(String),
+    /// The leaf certificate is for a different key than the one in Key Vault.
+    KeyMismatch,
+    NamesMismatch { expected: Vec<String>, found: Vec<String> },
+    /// A certificate of the chain isn't valid right now, `index` 0 is the leaf.
+    Validity { index: usize, subject: String, not_before: i64, not_after: i64 },
+    /// A certificate isn't signed by the next certificate in the chain.
+    Signature { index: usize, subject: String, issuer: String, error: String },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The downloaded certificate chain is invalid: ")?;
+        match self {
+            ChainError::Parse(error) => write!(f, "it could not be parsed ({})", error),
+            ChainError::Empty => write!(f, "it contains no certificates"),
+            ChainError::Csr(error) => write!(f, "the CSR could not be parsed ({})", error),
+            ChainError::KeyMismatch => write!(f, "the public key of the certificate doesn't match the CSR"),
+            ChainError::NamesMismatch { expected, found } => write!(
+                f,
+                "the certificate is for [{}] instead of [{}]",
+                found.join(", "),
+                expected.join(", ")
+            ),
+            ChainError::Validity { index, subject, not_before, not_after } => {
+                let format = |timestamp: &i64| match OffsetDateTime::from_unix_timestamp(*timestamp) {
+                    Ok(date) => date.to_string(),
+                    Err(_) => timestamp.to_string(),
+                };
+                write!(
+                    f,
+                    "certificate {} ({}) is only valid from {} to {}",
+                    index,
+                    subject,
+                    format(not_before),
+                    format(not_after)
+                )
+            }
+            ChainError::Signature { index, subject, issuer, error } => write!(
+                f,
+                "certificate {} ({}) is not signed by {} ({})",
+                index, subject, issuer, error
+            ),
+        }
+    }
+}
+
+impl Error for ChainError {}
+
+/// Makes sure the PEM chain downloaded from the CA is what was ordered before it gets
+/// merged into Key Vault: the leaf certificate is for the key of the base64 encoded `csr`
+/// and exactly the `names` of the order, every certificate is valid right now and signed
+/// by the next one in the chain. Returns the subjects of the chain, starting with the leaf.
+pub fn validate_chain(pem: &str, csr: &str, names: &[String]) -> Result<Vec<String>, ChainError> {
+    let pems = Pem::iter_from_buffer(pem.as_bytes())
+        .collect::<Result<Vec<Pem>, _>>()
+        .map_err(|error| ChainError::Parse(error.to_string()))?;
+    let certificates = pems
+        .iter()
+        .map(|pem| pem.parse_x509())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| ChainError::Parse(error.to_string()))?;
+    let leaf = certificates.first().ok_or(ChainError::Empty)?;
+
+    let csr = engine::general_purpose::STANDARD
+        .decode(csr)
+        .map_err(|error| ChainError::Csr(error.to_string()))?;
+    let (_, csr) = X509CertificationRequest::from_der(&csr).map_err(|error| ChainError::Csr(error.to_string()))?;
+    if leaf.public_key().raw != csr.certification_request_info.subject_pki.raw {
+        return Err(ChainError::KeyMismatch);
+    }
+
+    let mut found: Vec<String> = match leaf.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_lowercase()),
+                _ => None,
+            })
+            .collect(),
+        Ok(None) => Vec::new(),
+        Err(error) => return Err(ChainError::Parse(error.to_string())),
+    };
+    let mut expected: Vec<String> = names.iter().map(|name| name.to_lowercase()).collect();
+    found.sort();
+    found.dedup();
+    expected.sort();
+    expected.dedup();
+    if found != expected {
+        return Err(ChainError::NamesMismatch { expected, found });
+    }
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    for (index, certificate) in certificates.iter().enumerate() {
+        let not_before = certificate.validity().not_before.timestamp();
+        let not_after = certificate.validity().not_after.timestamp();
+        if not_before > now + CLOCK_SKEW_SECONDS || not_after <= now || not_after <= not_before {
+            return Err(ChainError::Validity {
+                index,
+                subject: certificate.subject().to_string(),
+                not_before,
+                not_after,
+            });
+        }
+    }
+
+    // the root isn't part of the chain, so the last certificate is left to the clients trusting it
+    for (index, pair) in certificates.windows(2).enumerate() {
+        if let Err(error) = pair[0].verify_signature(Some(pair[1].public_key())) {
+            return Err(ChainError::Signature {
+                index,
+                subject: pair[0].subject().to_string(),
+                issuer: pair[1].subject().to_string(),
+                error: error.to_string(),
+            });
+        }
+    }
+
+    info!("Certificate chain of {} certificates for {} is valid", certificates.len(), expected.join(", "));
+    Ok(certificates.iter().map(|certificate| certificate.subject().to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a leaf for www.example.com and example.com signed by an intermediate, both valid
+    // until 2125, and the base64 DER CSRs of the leaf's key and of another key
+    const CHAIN: &str = include_str!("testdata/chain.pem");
+    const EXPIRED: &str = include_str!("testdata/expired.pem");
+    const WRONG_ISSUER: &str = include_str!("testdata/wrong-issuer.pem");
+    const CSR: &str = include_str!("testdata/leaf.csr");
+    const STRANGER_CSR: &str = include_str!("testdata/stranger.csr");
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_a_valid_chain() {
+        let subjects = validate_chain(CHAIN, CSR, &names(&["example.com", "WWW.example.com"])).unwrap();
+        assert_eq!(subjects, ["CN=www.example.com", "CN=Test Intermediate"]);
+    }
+
+    #[test]
+    fn rejects_unreadable_input() {
+        assert!(matches!(validate_chain("", CSR, &names(&["example.com"])), Err(ChainError::Empty)));
+        assert!(matches!(validate_chain(CHAIN, "not base64!", &names(&["example.com"])), Err(ChainError::Csr(_))));
+        assert!(matches!(validate_chain(CHAIN, "AAAA", &names(&["example.com"])), Err(ChainError::Csr(_))));
+    }
+
+    #[test]
+    fn rejects_a_certificate_for_another_key() {
+        let result = validate_chain(CHAIN, STRANGER_CSR, &names(&["www.example.com", "example.com"]));
+        assert!(matches!(result, Err(ChainError::KeyMismatch)));
+    }
+
+    #[test]
+    fn rejects_a_certificate_for_other_names() {
+        match validate_chain(CHAIN, CSR, &names(&["www.example.com"])) {
+            Err(ChainError::NamesMismatch { expected, found }) => {
+                assert_eq!(expected, ["www.example.com"]);
+                assert_eq!(found, ["example.com", "www.example.com"]);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_an_expired_certificate() {
+        let result = validate_chain(EXPIRED, CSR, &names(&["www.example.com", "example.com"]));
+        assert!(matches!(result, Err(ChainError::Validity { index: 0, .. })));
+    }
+
+    #[test]
+    fn rejects_a_chain_with_the_wrong_issuer() {
+        let result = validate_chain(WRONG_ISSUER, CSR, &names(&["www.example.com", "example.com"]));
+        assert!(matches!(result, Err(ChainError::Signature { index: 0, .. })));
+    }
+}
@@ -1,5 +1,6 @@
 use super::{
-    util::{b64, jwk, jws},
+    self_check::self_check,
+    util::{b64, jwk, jws, AcmeError},
     Nonce,
 };
 use crate::Environment;
@@ -47,6 +48,13 @@ pub struct ChallengeAuthorization {
 }
 
 impl ChallengeAuthorization {
+    /// Returns the http-01 challenge of this authorization if the server offered one.
+    pub fn http_challenge(&self) -> Option<&Challenge> {
+        self.challenges
+            .iter()
+            .find(|challenge| challenge.challenge_type == "http-01")
+    }
+
     /// Completes the http challenge by opening an `http` server which returns the needed token
     /// under the specified path.
     pub async fn complete_http_challenge(
@@ -55,7 +63,14 @@ impl ChallengeAuthorization {
         account_url: &str,
         account_key: &KeyVaultKey,
         env: &Environment,
-    ) -> Result<Nonce, Box<dyn Error>> {
+    ) -> Result<Nonce, Box<dyn Error + Send + Sync>> {
+        let domain = self
+            .identifier
+            .get("value")
+            .and_then(|domain| domain.as_str())
+            .ok_or("The authorization doesn't name a domain")?
+            .to_string();
+
         let http_challenge = self
             .challenges
             .into_iter()
@@ -64,6 +79,7 @@ impl ChallengeAuthorization {
 
         ChallengeAuthorization::complete_challenge(
             client,
+            &domain,
             http_challenge,
             self.nonce,
             account_url,
@@ -76,19 +92,31 @@ impl ChallengeAuthorization {
     /// Actually opens the server and kicks of the challenge.
     async fn complete_challenge(
         client: &Client,
+        domain: &str,
         challenge_infos: Challenge,
         nonce: Nonce,
         acc_url: &str,
         account_key: &KeyVaultKey,
         env: &Environment,
-    ) -> Result<Nonce, Box<dyn Error>> {
+    ) -> Result<Nonce, Box<dyn Error + Send + Sync>> {
         let thumbprint = jwk(account_key)?;
         let mut hasher = Sha256::new();
-        hasher.update(&thumbprint.to_string().into_bytes());
+        hasher.update(thumbprint.to_string().into_bytes());
         let thumbprint = hasher.finalize();
 
         let challenge_content = format!("{}.{}", challenge_infos.token, b64(thumbprint));
-        env.challenge_store.write().unwrap().insert(challenge_infos.token.clone(), challenge_content);
+        env.challenge_store
+            .put(&challenge_infos.token, &challenge_content, env.challenge_ttl)
+            .await
+            .map_err(|error| error.to_string())?;
+
+        // make sure the challenge is reachable before the CA tries and fails the validation
+        if env.http01_self_check {
+            if let Err(error) = self_check(domain, &challenge_infos.token, &challenge_content).await {
+                env.challenge_store.remove(&challenge_infos.token).await.map_err(|error| error.to_string())?;
+                return Err(error.into());
+            }
+        }
 
         ChallengeAuthorization::kick_off_http_challenge(
             client,
@@ -107,7 +135,7 @@ impl ChallengeAuthorization {
         nonce: Nonce,
         acc_url: &str,
         env: &Environment,
-    ) -> Result<Nonce, Box<dyn Error>> {
+    ) -> Result<Nonce, Box<dyn Error + Send + Sync>> {
         let header = json!({
             "alg": "RS256",
             "kid": acc_url,
@@ -119,12 +147,18 @@ impl ChallengeAuthorization {
 
         let jws = jws(payload, header, env).await?;
 
-        Ok(client
+        let response = client
             .post(&challenge_infos.url)
             .header("Content-Type", "application/jose+json")
             .body(serde_json::to_string_pretty(&jws)?)
             .send()
-            .await?
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Box::new(AcmeError::from_response(response).await));
+        }
+
+        Ok(response
             .headers()
             .get("replay-nonce")
             .ok_or("Response received didn't match the challenge's requirements")?
@@ -29,7 +29,7 @@ pub struct Directory {
 impl Directory {
     /// Fetches the directory information from a specific server. This is the first request
     /// that's send to the server as it's return value holds information about the endpoints.
-    pub async fn fetch_dir(client: &Client, server_url: &str) -> Result<Self, Box<dyn Error>> {
+    pub async fn fetch_dir(client: &Client, server_url: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
         let result = client.get(server_url).send().await?;
         let mut dir_infos = result.json::<Self>().await?;
 
@@ -56,7 +56,7 @@ impl Directory {
         account_key: &KeyVaultKey,
         email: &str,
         env: &Environment,
-    ) -> Result<Account, Box<dyn Error>> {
+    ) -> Result<Account, Box<dyn Error + Send + Sync>> {
         let jwk = jwk(account_key)?;
         let header = json!({
             "alg": "RS256",
@@ -0,0 +1,171 @@
+use crate::{keyvault::Tags, store::query::Query, Environment};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+use tracing::info;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+const CONTAINER: &str = "jobs";
+/// The dashboard lists at most this many recent jobs.
+const RECENT_JOBS: usize = 50;
+/// How long finished jobs are kept, if the container has time to live enabled. A done job
+/// knows the CA and chain of the certificate it issued, so it is kept longer than
+/// certificates live.
+const DONE_TTL: Duration = Duration::days(366);
+const FAILED_TTL: Duration = Duration::days(30);
+
+/// The steps of issuing a certificate, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Ordering,
+    Validating,
+    Finalizing,
+    Merging,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed)
+    }
+
+    /// The state as it is stored, to query jobs by.
+    fn value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Tracks the issuance of a certificate, so it can run in the background while the
+/// caller polls its state. Everything needed to pick up the order again is saved with
+/// the job, so an issuance interrupted by a restart can be resumed.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Job {
+    pub id: Uuid,
+    pub certificate: String,
+    pub domain: String,
+    /// Set if the job renews an existing certificate rather than issuing a new one.
+    #[serde(default)]
+    pub renewal: bool,
+    pub state: JobState,
+    pub error: Option<String>,
+    /// The tags to set on the new certificate version.
+    #[serde(default)]
+    pub tags: Tags,
+    /// The ACME order, set once it was created.
+    pub order_url: Option<String>,
+    #[serde(default)]
+    pub authorizations: Vec<String>,
+    /// The directory url of the CA the certificate was ordered from.
+    pub ca: Option<String>,
+    /// The certificate profile the CA issued the certificate with, if it supports profiles.
+    pub profile: Option<String>,
+    /// The subjects of the issued chain, starting with the certificate itself.
+    #[serde(default)]
+    pub chain: Vec<String>,
+    /// Set once the CA was asked to validate the challenge, a failure after that counts
+    /// against the CA's failed validations.
+    #[serde(default)]
+    pub challenge_submitted: bool,
+    /// Set once the order was finalized, from then on the CA has issued the certificate.
+    #[serde(default)]
+    pub finalized: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated: OffsetDateTime,
+    /// Seconds until Cosmos removes the finished job.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(ignore)]
+    pub ttl: Option<i64>,
+}
+
+impl Job {
+    pub fn new(domain: &str, certificate: &str, tags: Tags) -> Self {
+        let now = OffsetDateTime::now_utc();
+        Job {
+            id: Uuid::new_v4(),
+            certificate: certificate.to_string(),
+            domain: domain.to_string(),
+            renewal: false,
+            state: JobState::Queued,
+            error: None,
+            tags,
+            order_url: None,
+            authorizations: Vec::new(),
+            ca: None,
+            profile: None,
+            chain: Vec::new(),
+            challenge_submitted: false,
+            finalized: false,
+            created: now,
+            updated: now,
+            ttl: None,
+        }
+    }
+
+    pub async fn get(env: &Environment, id: Uuid) -> Result<Option<Job>, Box<dyn Error>> {
+        env.store.get(CONTAINER, &id.to_string()).await
+    }
+
+    /// Returns the jobs that changed within the last day, newest first. Running jobs change
+    /// with every step, and stalled ones are resumed by the next check.
+    pub async fn recent(env: &Environment) -> Result<Vec<Job>, Box<dyn Error>> {
+        let since = (OffsetDateTime::now_utc() - Duration::days(1)).format(&Rfc3339)?;
+        let query = Query::new().greater_than("updated", since).order_by_desc("created").limit(RECENT_JOBS);
+        Ok(env.store.query(CONTAINER, &query).await?.documents)
+    }
+
+    /// Returns the latest job of a certificate, only considering jobs in `state` if it is set.
+    pub async fn latest(env: &Environment, certificate: &str, state: Option<JobState>) -> Result<Option<Job>, Box<dyn Error>> {
+        let mut query = Query::new().equals("certificate", certificate);
+        if let Some(state) = state {
+            query = query.equals("state", state.value());
+        }
+        let query = query.order_by_desc("created").limit(1);
+        Ok(env.store.query(CONTAINER, &query).await?.documents.into_iter().next())
+    }
+
+    /// Returns the unfinished jobs that haven't moved for longer than `idle`, most likely
+    /// because the instance running them stopped.
+    pub async fn stalled(env: &Environment, idle: std::time::Duration) -> Result<Vec<Job>, Box<dyn Error>> {
+        let since = (OffsetDateTime::now_utc() - idle).format(&Rfc3339)?;
+        let query = Query::new()
+            .not_in("state", [JobState::Done.value(), JobState::Failed.value()])
+            .less_than("updated", since);
+        let mut jobs: Vec<Job> = env.store.query(CONTAINER, &query).await?.documents;
+        jobs.sort_by_key(|job| job.created);
+        Ok(jobs)
+    }
+
+    pub async fn save(&self, env: &Environment) -> Result<(), Box<dyn Error>> {
+        env.store.upsert(CONTAINER, &self.id.to_string(), self).await
+    }
+
+    /// Moves the job to its next state. A job that can't be saved keeps running, only
+    /// its state is out of date.
+    pub async fn advance(&mut self, env: &Environment, state: JobState) {
+        self.state = state;
+        self.updated = OffsetDateTime::now_utc();
+        self.ttl = match state {
+            JobState::Done => Some(DONE_TTL.whole_seconds()),
+            JobState::Failed => Some(FAILED_TTL.whole_seconds()),
+            _ => None,
+        };
+
+        info!("Job {} for {} is {:?}", self.id, self.certificate, state);
+
+        if let Err(error) = self.save(env).await {
+            info!("Could not save job {}: {}", self.id, error);
+        }
+    }
+
+    pub async fn fail(&mut self, env: &Environment, error: String) {
+        self.error = Some(error);
+        self.advance(env, JobState::Failed).await;
+    }
+}
@@ -1,5 +1,5 @@
-use self::{account::Account, directory::Directory};
-use crate::Environment;
+use self::{account::Account, chain::validate_chain, directory::Directory, job::{Job, JobState}, order::Order, rate_limit::RateLimited, updated_order::UpdatedOrder};
+use crate::{deploy, events::{self, CloudEvent, EventType}, notify::{expiry, Event, Notification, Outbox}, renewal::state::RenewalState, store::lease::{Lease, LeaseTaken}, Environment};
 use azure_security_keyvault::prelude::{JsonWebKeyType, KeyVaultGetCertificateResponse};
 use tracing::info;
 use std::error::Error;
@@ -7,9 +7,13 @@ use std::error::Error;
 pub type Nonce = String;
 
 pub mod account;
+pub mod chain;
 pub mod challenge;
 pub mod directory;
+pub mod job;
 pub mod order;
+pub mod rate_limit;
+pub mod self_check;
 pub mod updated_order;
 pub mod util;
 
@@ -18,84 +22,342 @@ pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-staging-v02.api.letsencry
 #[cfg(not(debug_assertions))]
 pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
 
+/// Asks the CA to revoke the DER encoded `certificate`, which must have been issued to
+/// this app's account.
+pub async fn revoke(certificate: &[u8], reason: Option<u8>, env: &Environment) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let http_client = reqwest::Client::new();
+    let account_key = env.key_client.get("letsencrypt").await?;
+    let dir_infos = Directory::fetch_dir(&http_client, LETS_ENCRYPT_DIRECTORY).await?;
+    let account = dir_infos
+        .create_account(&http_client, &account_key, env.account_email.as_ref(), env)
+        .await?;
+
+    account
+        .revoke_certificate(&http_client, &dir_infos.revoke_cert, env, certificate, reason)
+        .await?;
+
+    info!("Certificate revoked");
+    Ok(())
+}
+
+/// Runs the issuance `job`, which orders a certificate for its domain and merges it into
+/// its Key Vault certificate. The job's tags are set on the new certificate version, so
+/// callers renewing a certificate should pass the existing tags along to keep them.
+///
+/// The outcome is sent to the certificate's subscribers through `outbox`, and a new
+/// version is deployed to the certificate's deployment targets in the background.
+///
+/// Fails with `LeaseTaken` if another instance is already issuing the certificate, and with
+/// `RateLimited` if a new order would exceed one of the CA's rate limits.
 pub async fn cert_new(
-    domain: &str,
-    id: &str,
+    job: &mut Job,
     env: &Environment,
+    outbox: &Outbox,
 ) -> Result<KeyVaultGetCertificateResponse, Box<dyn Error>> {
-    info!(
-        "Creating certificate for domain: {} with id: {}",
-        domain,
-        id
-    );
+    check_rate_limit(job, env).await?;
+    // the boxed error keeps its type, so callers can still tell what failed
+    let cert = run(job, env, outbox).await.map_err(|error| error as Box<dyn Error>)?;
+    match cert {
+        Some(cert) => Ok(cert),
+        None => {
+            let error = LeaseTaken(format!("certificate-{}", job.certificate));
+            job.fail(env, error.to_string()).await;
+            Err(Box::new(error))
+        }
+    }
+}
+
+/// Fails the job if ordering its certificate would exceed one of the CA's rate limits. A
+/// job that already has an order was counted when it started.
+async fn check_rate_limit(job: &mut Job, env: &Environment) -> Result<(), RateLimited> {
+    if job.order_url.is_some() {
+        return Ok(());
+    }
+    match rate_limit::exceeded(env, std::slice::from_ref(&job.domain), job.renewal).await {
+        Some(limited) => {
+            job.fail(env, limited.to_string()).await;
+            Err(limited)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Resumes the issuance jobs that stopped before they were done, e.g. because their
+/// instance was restarted between finalizing the order and merging the certificate.
+/// A job still running elsewhere holds its certificate's lease and is left alone.
+pub async fn resume_jobs(env: &Environment, outbox: &Outbox) {
+    let jobs = match Job::stalled(env, env.lease_duration).await {
+        Ok(jobs) => jobs,
+        Err(error) => {
+            info!("Could not list stalled jobs: {}", error);
+            return;
+        }
+    };
+
+    for mut job in jobs {
+        info!("Resuming job {} for {} from {:?}", job.id, job.certificate, job.state);
+
+        if let Err(limited) = check_rate_limit(&mut job, env).await {
+            info!("Resumed job {} failed: {}", job.id, limited);
+            continue;
+        }
+
+        match tokio::time::timeout(env.renewal_timeout, run(&mut job, env, outbox)).await {
+            Ok(Ok(Some(_))) => info!("Resumed job {} is done", job.id),
+            Ok(Ok(None)) => info!("Job {} is already running on another instance", job.id),
+            Ok(Err(error)) => info!("Resumed job {} failed: {}", job.id, error),
+            Err(_) => info!("Resumed job {} timed out after {:?}", job.id, env.renewal_timeout),
+        }
+    }
+}
+
+/// Runs the job while holding its certificate's lease, returns `None` if another
+/// instance holds the lease.
+async fn run(
+    job: &mut Job,
+    env: &Environment,
+    outbox: &Outbox,
+) -> Result<Option<KeyVaultGetCertificateResponse>, Box<dyn Error + Send + Sync>> {
+    // only one instance may work on a certificate, otherwise orders and pending operations collide
+    let lease_name = format!("certificate-{}", job.certificate);
+    let lease = Lease::acquire(env, &lease_name, env.lease_duration).await.map_err(|error| error.to_string())?;
+    let lease = match lease {
+        Some(lease) => lease,
+        None => return Ok(None),
+    };
+
+    let result = issue(job, env).await;
+
+    lease.release(env).await;
+
+    let notification = match &result {
+        Ok(cert) => Notification::new(Event::Issued, &job.certificate).expires_on(cert.attributes.expires_on),
+        Err(error) => Notification::new(Event::Failed, &job.certificate).error(&error.to_string()),
+    }
+    .domain(&job.domain)
+    .job(job.id);
+
+    match result {
+        Ok(cert) => {
+            rate_limit::record_issuance(env, std::slice::from_ref(&job.domain)).await;
+            job.advance(env, JobState::Done).await;
+            outbox.send(env, notification).await;
+            let event_type = if job.renewal { EventType::Renewed } else { EventType::Issued };
+            events::publish(env, CloudEvent::new(env, event_type, &job.certificate).job(job.id).certificate(&cert)).await;
+            // the new version is rolled out to the services using it, a backoff of the
+            // previous version doesn't apply
+            deploy::start(env, &job.certificate, true);
+            Ok(Some(cert))
+        }
+        Err(error) => {
+            let message = error.to_string();
+            // the CA only counts failures of challenges it tried to validate, and a
+            // certificate that couldn't be downloaded or merged was issued all the same
+            if job.finalized {
+                rate_limit::record_issuance(env, std::slice::from_ref(&job.domain)).await;
+            } else if job.challenge_submitted {
+                rate_limit::record_failure(env, &job.domain).await;
+            }
+            job.fail(env, message.clone()).await;
+            outbox.send(env, notification).await;
+            // nothing changed for consumers if a new domain couldn't be issued
+            if job.renewal {
+                let event = CloudEvent::new(env, EventType::RenewalFailed, &job.certificate).domain(&job.domain).job(job.id);
+                events::publish(env, event.error(&message)).await;
+            }
+            Err(error)
+        }
+    }
+}
+
+async fn issue(
+    job: &mut Job,
+    env: &Environment,
+) -> Result<KeyVaultGetCertificateResponse, Box<dyn Error + Send + Sync>> {
+    let domain = job.domain.clone();
+    let domain = domain.as_str();
+    let id = job.certificate.clone();
+    let id = id.as_str();
 
     let http_client = reqwest::Client::new();
     let account_key = env.key_client.get("letsencrypt").await?;
 
     info!("Got account key");
 
-    // create csr
-    let csr = env
-        .certificate_client
-        .create(id, format!("CN={}", domain), "Unknown")
-        .dns_names(vec![domain.to_string()])
-        .kty(JsonWebKeyType::Rsa)
-        .key_size(2048)
-        .await?;
-
-    info!("Created CSR");
-
     // Get directory
     let dir_infos = Directory::fetch_dir(&http_client, LETS_ENCRYPT_DIRECTORY).await?;
 
     info!("Got directory");
 
-    // Create account and accept terms of service
+    // Create account and accept terms of service, an existing account is returned as is
     let new_acc: Account = dir_infos
         .create_account(&http_client, &account_key, env.account_email.as_ref(), env)
         .await?;
 
     info!("Created account");
 
-    // create certificate order
-    let order = new_acc
-        .create_new_order(&http_client, &dir_infos.new_order, env, domain, csr.csr)
-        .await?;
+    let mut order = match job.order_url.clone() {
+        Some(order_url) => {
+            // the csr lives in the pending operation, without it the order can't be finalized
+            let operation = env.certificate_client.get_operation(id).await;
+            match operation {
+                Ok(operation) if operation.status != "completed" => {
+                    let mut order = Order::fetch(&http_client, &order_url, &new_acc.account_location, new_acc.nonce.clone(), env).await?;
+                    order.csr = operation.csr;
 
-    info!("Created certificate order");
+                    info!("Resuming order {} which is {}", order_url, order.status);
+                    order
+                }
+                // the merge went through before the job could be marked as done
+                Ok(_) => {
+                    info!("Certificate was merged already");
+                    return Ok(env.certificate_client.get(id).await?);
+                }
+                Err(_) if job.state == JobState::Merging => {
+                    info!("Certificate was merged already");
+                    return Ok(env.certificate_client.get(id).await?);
+                }
+                Err(_) => return Err("The pending certificate operation is gone, the order can't be resumed".into()),
+            }
+        }
+        None => {
+            job.advance(env, JobState::Ordering).await;
 
-    // fetch the auth challenges
-    let challenge = order
-        .fetch_auth_challenges(&http_client, &new_acc.account_location, env)
-        .await?;
+            info!(
+                "Creating certificate for domain: {} with id: {}",
+                domain,
+                id
+            );
 
-    info!("Fetched auth challenges");
+            // remove pending operation if exists, the lease makes sure it isn't ours
+            match env.certificate_client.get_operation(id).await {
+                Ok(_) => {
+                    info!("Pending certificate operation exists");
+                    let _ = env.certificate_client.delete_operation(id).await?;
+                    info!("Pending certificate operation deleted");
+                }
+                Err(_) => info!("No certificate operation pending"),
+            };
 
-    // complete the challenge and save the nonce that's needed for further authentication
-    let new_nonce = challenge
-        .complete_http_challenge(&http_client, &new_acc.account_location, &account_key, env)
-        .await?;
+            // create csr, the key stays in the vault unless a deployment target writes it to files
+            let mut create = env
+                .certificate_client
+                .create(id, format!("CN={}", domain), "Unknown")
+                .dns_names(vec![domain.to_string()])
+                .kty(JsonWebKeyType::Rsa)
+                .key_size(2048)
+                .tags(job.tags.clone());
+            if deploy::exports_key(env, id).await {
+                create = create.exportable(true);
+            }
+            let csr = create.await?;
 
-    info!("Setup http challenge");
+            info!("Created CSR");
 
-    std::thread::sleep(std::time::Duration::from_secs(10));
+            // create certificate order
+            let order = new_acc
+                .create_new_order(&http_client, &dir_infos.new_order, env, domain, csr.csr)
+                .await?;
 
-    // finalize the order to retrieve location of the final cert
-    let updated_order = order
-        .finalize_order(&http_client, &new_acc.account_location, new_nonce, env)
-        .await?;
+            info!("Created certificate order");
+
+            // remember the order, so it can be picked up again if we stop before it's done
+            job.order_url = Some(order.location.clone());
+            job.authorizations = order.authorizations.clone();
+            job.ca = Some(LETS_ENCRYPT_DIRECTORY.to_string());
+            job.profile = order.profile.clone();
+            order
+        }
+    };
+
+    // kept to check the issued certificate against, finalizing consumes the order
+    let csr = order.csr.clone();
+    let names: Vec<String> = order
+        .identifiers
+        .as_array()
+        .map(|identifiers| {
+            identifiers
+                .iter()
+                .filter_map(|identifier| identifier.get("value").and_then(|value| value.as_str()))
+                .map(|value| value.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let (certificate_url, nonce) = match order.status.as_str() {
+        "pending" | "ready" => {
+            let mut token = None;
+            if order.status == "pending" {
+                job.advance(env, JobState::Validating).await;
+
+                // fetch the auth challenges
+                let challenge = order
+                    .fetch_auth_challenges(&http_client, &new_acc.account_location, env)
+                    .await?;
+
+                info!("Fetched auth challenges");
 
-    info!("Finalized order");
+                token = challenge.http_challenge().map(|challenge| challenge.token.clone());
+
+                // complete the challenge and save the nonce that's needed for further authentication
+                order.nonce = challenge
+                    .complete_http_challenge(&http_client, &new_acc.account_location, &account_key, env)
+                    .await?;
+                job.challenge_submitted = true;
+
+                info!("Setup http challenge");
+
+                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            }
+
+            job.advance(env, JobState::Finalizing).await;
+
+            // finalize the order to retrieve location of the final cert
+            let new_nonce = order.nonce.clone();
+            let updated_order = order
+                .finalize_order(&http_client, &new_acc.account_location, new_nonce, env)
+                .await?;
+            job.finalized = true;
+
+            info!("Finalized order");
+
+            // the challenge was validated, nobody needs the token anymore. The CA issued the
+            // certificate at this point, so a token that can't be removed is left to its ttl
+            if let Some(token) = token {
+                if let Err(error) = env.challenge_store.remove(&token).await.map_err(|error| error.to_string()) {
+                    info!("Could not remove challenge token {}: {}", token, error);
+                }
+            }
+
+            (updated_order.certificate, updated_order.nonce)
+        }
+        "valid" => {
+            job.finalized = true;
+            (order.certificate.ok_or("The order is valid but has no certificate")?, order.nonce)
+        }
+        status => return Err(format!("The order {} is {}", order.location, status).into()),
+    };
 
     // retrieve the x5c
-    let cert_chain = updated_order
-        .download_certificate(&http_client, &new_acc.account_location, env)
-        .await?;
+    let cert_chain = UpdatedOrder::download_certificate(&http_client, &certificate_url, &new_acc.account_location, nonce, env).await?;
 
     info!("Retrieved x5c");
 
-    // merge x5c
-    let cert = env.certificate_client.merge(id, vec![cert_chain]).await?;
+    // a broken chain is better reported here than as a merge error, or not at all
+    job.chain = validate_chain(&cert_chain, &csr, &names)?;
+
+    job.advance(env, JobState::Merging).await;
+
+    // merge x5c, the new version starts without the failures of previous renewal attempts
+    // and without the expiry warnings of the previous version
+    let mut tags = job.tags.clone();
+    RenewalState::clear_tags(&mut tags);
+    tags.remove(expiry::NOTIFIED_TAG);
+    let cert = env
+        .certificate_client
+        .merge(id, vec![cert_chain])
+        .tags(tags)
+        .await?;
 
     info!("x5c merged");
 
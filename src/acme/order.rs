@@ -21,6 +21,13 @@ pub struct Order {
     pub identifiers: serde_json::Value,
     pub authorizations: Vec<String>,
     pub finalize: String,
+    /// The certificate profile chosen by the CA, only sent by CAs supporting profiles.
+    pub profile: Option<String>,
+    /// Only set once the order is valid.
+    pub certificate: Option<String>,
+    /// The url of the order itself, which is needed to pick it up again later.
+    #[serde(skip)]
+    pub location: String,
     #[serde(skip)]
     pub nonce: Nonce,
     #[serde(skip)]
@@ -28,13 +35,46 @@ pub struct Order {
 }
 
 impl Order {
+    /// Fetches the current state of an existing order.
+    pub async fn fetch(
+        client: &Client,
+        order_url: &str,
+        account_url: &str,
+        nonce: Nonce,
+        env: &Environment,
+    ) -> Result<Order, Box<dyn Error + Send + Sync>> {
+        let header = json!({
+            "alg": "RS256",
+            "url": order_url,
+            "kid": account_url,
+            "nonce": nonce,
+        });
+
+        let payload = json!("");
+
+        let jws = jws(payload, header, env).await?;
+
+        let response = client
+            .post(order_url)
+            .header("Content-Type", "application/jose+json")
+            .body(serde_json::to_string_pretty(&jws)?)
+            .send()
+            .await?;
+
+        let (nonce, mut order): (Nonce, Order) = extract_payload_and_nonce(response).await?;
+        order.nonce = nonce;
+        order.location = order_url.to_string();
+
+        Ok(order)
+    }
+
     /// Fetches the available authorization options from the server for a certain order.
     pub async fn fetch_auth_challenges(
         &self,
         client: &Client,
         account_url: &str,
         env: &Environment,
-    ) -> Result<ChallengeAuthorization, Box<dyn Error>> {
+    ) -> Result<ChallengeAuthorization, Box<dyn Error + Send + Sync>> {
         let auth_url = self
             .authorizations
             .first()
@@ -75,7 +115,7 @@ impl Order {
         account_url: &str,
         new_nonce: Nonce,
         env: &Environment,
-    ) -> Result<UpdatedOrder, Box<dyn Error>> {
+    ) -> Result<UpdatedOrder, Box<dyn Error + Send + Sync>> {
         let header = json!({
         "alg": "RS256",
         "url": self.finalize,
@@ -0,0 +1,301 @@
+use crate::Environment;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt};
+use time::{Duration, OffsetDateTime};
+use tracing::info;
+
+const CONTAINER: &str = "rate-limits";
+
+// Let's Encrypt's limits, see https://letsencrypt.org/docs/rate-limits/
+/// New certificates per registered domain, e.g. everything under example.com.
+pub const CERTIFICATES_PER_DOMAIN: usize = 50;
+/// Certificates for the exact same set of names.
+pub const DUPLICATE_CERTIFICATES: usize = 5;
+const CERTIFICATES_WINDOW: Duration = Duration::days(7);
+/// Failed validations per name.
+pub const FAILED_VALIDATIONS: usize = 5;
+const FAILURES_WINDOW: Duration = Duration::hours(1);
+
+/// Returns the registered domain of a name according to the Public Suffix List, e.g.
+/// `example.co.uk` for `www.example.co.uk`. Let's Encrypt counts certificates by it.
+pub fn registered_domain(name: &str) -> String {
+    let name = name.trim_start_matches("*.").to_lowercase();
+    psl::domain_str(&name).map(|domain| domain.to_string()).unwrap_or(name)
+}
+
+/// The names of a certificate in the order Let's Encrypt compares them.
+fn identifier_set(names: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = names.iter().map(|name| name.to_lowercase()).collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Issuance {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Failure {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub name: String,
+}
+
+/// The certificates issued and validations failed for a registered domain within the
+/// windows Let's Encrypt counts them in. Older entries are dropped on every update.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ledger {
+    pub registered_domain: String,
+    #[serde(default)]
+    pub issued: Vec<Issuance>,
+    #[serde(default)]
+    pub failures: Vec<Failure>,
+}
+
+impl Ledger {
+    fn new(registered_domain: &str) -> Self {
+        Ledger { registered_domain: registered_domain.to_string(), issued: Vec::new(), failures: Vec::new() }
+    }
+
+    fn prune(&mut self, now: OffsetDateTime) {
+        self.issued.retain(|issuance| issuance.time > now - CERTIFICATES_WINDOW);
+        self.failures.retain(|failure| failure.time > now - FAILURES_WINDOW);
+    }
+
+    /// Certificates that may still be issued for the registered domain this week.
+    pub fn certificates_left(&self, now: OffsetDateTime) -> usize {
+        let issued = self.issued.iter().filter(|issuance| issuance.time > now - CERTIFICATES_WINDOW).count();
+        CERTIFICATES_PER_DOMAIN.saturating_sub(issued)
+    }
+
+    /// Failed validations within the last hour, of any name.
+    pub fn recent_failures(&self, now: OffsetDateTime) -> usize {
+        self.failures.iter().filter(|failure| failure.time > now - FAILURES_WINDOW).count()
+    }
+
+    /// When the oldest counted certificate leaves the window and frees a slot.
+    pub fn next_slot(&self) -> Option<OffsetDateTime> {
+        self.issued.iter().map(|issuance| issuance.time + CERTIFICATES_WINDOW).min()
+    }
+
+    /// Returns why a certificate for `names` would exceed a limit, if it would. Let's
+    /// Encrypt doesn't limit renewals of the same names by the certificates per domain.
+    fn exceeded(&self, names: &[String], renewal: bool, now: OffsetDateTime) -> Option<RateLimited> {
+        let issued: Vec<&Issuance> = self.issued.iter().filter(|issuance| issuance.time > now - CERTIFICATES_WINDOW).collect();
+        if !renewal && issued.len() >= CERTIFICATES_PER_DOMAIN {
+            return Some(RateLimited {
+                message: format!(
+                    "{} certificates were issued for {} within the last week, Let's Encrypt allows {}",
+                    issued.len(), self.registered_domain, CERTIFICATES_PER_DOMAIN
+                ),
+                retry_after: issued.iter().map(|issuance| issuance.time + CERTIFICATES_WINDOW).min(),
+            });
+        }
+
+        let set = identifier_set(names);
+        let duplicates: Vec<&&Issuance> = issued.iter().filter(|issuance| identifier_set(&issuance.names) == set).collect();
+        if duplicates.len() >= DUPLICATE_CERTIFICATES {
+            return Some(RateLimited {
+                message: format!(
+                    "{} certificates for {} were issued within the last week, Let's Encrypt allows {} duplicates",
+                    duplicates.len(), set.join(", "), DUPLICATE_CERTIFICATES
+                ),
+                retry_after: duplicates.iter().map(|issuance| issuance.time + CERTIFICATES_WINDOW).min(),
+            });
+        }
+
+        for name in set.iter() {
+            let failures: Vec<&Failure> = self
+                .failures
+                .iter()
+                .filter(|failure| &failure.name == name && failure.time > now - FAILURES_WINDOW)
+                .collect();
+            if failures.len() >= FAILED_VALIDATIONS {
+                return Some(RateLimited {
+                    message: format!(
+                        "Validating {} failed {} times within the last hour, Let's Encrypt allows {}",
+                        name, failures.len(), FAILED_VALIDATIONS
+                    ),
+                    retry_after: failures.iter().map(|failure| failure.time + FAILURES_WINDOW).min(),
+                });
+            }
+        }
+
+        None
+    }
+
+    /// Returns the ledgers of every registered domain with recent certificates or failures.
+    pub async fn list(env: &Environment) -> Result<Vec<Ledger>, Box<dyn Error>> {
+        let now = OffsetDateTime::now_utc();
+        let mut ledgers: Vec<Ledger> = env.store.list(CONTAINER).await?;
+        for ledger in ledgers.iter_mut() {
+            ledger.prune(now);
+        }
+        ledgers.retain(|ledger| !ledger.issued.is_empty() || !ledger.failures.is_empty());
+        ledgers.sort_by(|a, b| a.registered_domain.cmp(&b.registered_domain));
+        Ok(ledgers)
+    }
+
+    /// Applies `change` to the ledger of a registered domain. Instances may update the same
+    /// ledger at once, a change that lost the race is applied to the newer ledger again.
+    async fn update(env: &Environment, registered_domain: &str, change: impl Fn(&mut Ledger)) -> Result<(), Box<dyn Error>> {
+        let now = OffsetDateTime::now_utc();
+        for _ in 0..5 {
+            let current = env.store.get_versioned::<Ledger>(CONTAINER, registered_domain).await?;
+            let updated = match current {
+                Some((mut ledger, version)) => {
+                    change(&mut ledger);
+                    ledger.prune(now);
+                    env.store.replace(CONTAINER, registered_domain, &ledger, &version).await?
+                }
+                None => {
+                    let mut ledger = Ledger::new(registered_domain);
+                    change(&mut ledger);
+                    env.store.insert(CONTAINER, registered_domain, &ledger).await?
+                }
+            };
+            if updated {
+                return Ok(());
+            }
+        }
+        Err(format!("The rate limit ledger of {} keeps changing", registered_domain).into())
+    }
+}
+
+/// Issuing a certificate now would exceed one of Let's Encrypt's rate limits.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub message: String,
+    /// When the limit allows the certificate again.
+    pub retry_after: Option<OffsetDateTime>,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.retry_after {
+            Some(retry_after) => write!(f, "{}, try again after {}", self.message, retry_after),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for RateLimited {}
+
+/// Returns why a certificate for `names` would exceed a rate limit, if it would, where
+/// `renewal` is set for a certificate renewing the same names. The CA enforces the limits
+/// either way, so a ledger that can't be read doesn't stop issuance.
+pub async fn exceeded(env: &Environment, names: &[String], renewal: bool) -> Option<RateLimited> {
+    let now = OffsetDateTime::now_utc();
+    let mut registered: Vec<String> = names.iter().map(|name| registered_domain(name)).collect();
+    registered.sort();
+    registered.dedup();
+
+    for domain in registered {
+        let ledger = env.store.get::<Ledger>(CONTAINER, &domain).await.map_err(|error| error.to_string());
+        match ledger {
+            Ok(Some(ledger)) => {
+                if let Some(limited) = ledger.exceeded(names, renewal, now) {
+                    return Some(limited);
+                }
+            }
+            Ok(None) => {}
+            Err(error) => info!("Could not read the rate limit ledger of {}: {}", domain, error),
+        }
+    }
+    None
+}
+
+/// Counts a certificate issued for `names`. Failing to count it is only logged, the
+/// certificate exists either way.
+pub async fn record_issuance(env: &Environment, names: &[String]) {
+    let issuance = Issuance { time: OffsetDateTime::now_utc(), names: identifier_set(names) };
+    let mut registered: Vec<String> = names.iter().map(|name| registered_domain(name)).collect();
+    registered.sort();
+    registered.dedup();
+
+    for domain in registered {
+        let result = Ledger::update(env, &domain, |ledger| ledger.issued.push(issuance.clone())).await;
+        if let Err(error) = result.map_err(|error| error.to_string()) {
+            info!("Could not count the certificate for {}: {}", domain, error);
+        }
+    }
+}
+
+/// Counts a failed validation of `name`.
+pub async fn record_failure(env: &Environment, name: &str) {
+    let failure = Failure { time: OffsetDateTime::now_utc(), name: name.to_lowercase() };
+    let domain = registered_domain(name);
+
+    let result = Ledger::update(env, &domain, |ledger| ledger.failures.push(failure.clone())).await;
+    if let Err(error) = result.map_err(|error| error.to_string()) {
+        info!("Could not count the failed validation of {}: {}", name, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn issuance(now: OffsetDateTime, age: Duration, issued: &[&str]) -> Issuance {
+        Issuance { time: now - age, names: identifier_set(&names(issued)) }
+    }
+
+    #[test]
+    fn finds_the_registered_domain() {
+        assert_eq!(registered_domain("www.example.com"), "example.com");
+        assert_eq!(registered_domain("*.shop.Example.co.uk"), "example.co.uk");
+        assert_eq!(registered_domain("example.com"), "example.com");
+        assert_eq!(registered_domain("app.azurewebsites.net"), "app.azurewebsites.net");
+    }
+
+    #[test]
+    fn limits_certificates_per_domain_except_renewals() {
+        let now = OffsetDateTime::now_utc();
+        let mut ledger = Ledger::new("example.com");
+        for index in 0..CERTIFICATES_PER_DOMAIN {
+            let name = format!("host{}.example.com", index);
+            ledger.issued.push(issuance(now, Duration::hours(1), &[name.as_str()]));
+        }
+
+        let limited = ledger.exceeded(&names(&["new.example.com"]), false, now).unwrap();
+        assert_eq!(limited.retry_after, Some(now - Duration::hours(1) + CERTIFICATES_WINDOW));
+        assert!(ledger.exceeded(&names(&["host1.example.com"]), true, now).is_none());
+
+        // certificates leave the count after a week
+        ledger.issued[0].time = now - CERTIFICATES_WINDOW - Duration::minutes(1);
+        assert!(ledger.exceeded(&names(&["new.example.com"]), false, now).is_none());
+    }
+
+    #[test]
+    fn limits_duplicate_certificates_for_renewals_too() {
+        let now = OffsetDateTime::now_utc();
+        let mut ledger = Ledger::new("example.com");
+        for _ in 0..DUPLICATE_CERTIFICATES {
+            ledger.issued.push(issuance(now, Duration::days(1), &["www.example.com", "example.com"]));
+        }
+
+        assert!(ledger.exceeded(&names(&["EXAMPLE.com", "www.example.com"]), true, now).is_some());
+        assert!(ledger.exceeded(&names(&["www.example.com"]), true, now).is_none());
+    }
+
+    #[test]
+    fn limits_failed_validations_for_renewals_too() {
+        let now = OffsetDateTime::now_utc();
+        let mut ledger = Ledger::new("example.com");
+        for minutes in 0..FAILED_VALIDATIONS as i64 {
+            ledger.failures.push(Failure { time: now - Duration::minutes(minutes), name: "www.example.com".to_string() });
+        }
+
+        assert!(ledger.exceeded(&names(&["www.example.com"]), true, now).is_some());
+        assert!(ledger.exceeded(&names(&["api.example.com"]), false, now).is_none());
+        assert!(ledger.exceeded(&names(&["www.example.com"]), false, now + FAILURES_WINDOW).is_none());
+    }
+}
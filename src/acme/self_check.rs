@@ -0,0 +1,245 @@
+use reqwest::{redirect::Policy, Client, StatusCode};
+use std::{error::Error, fmt, time::Duration};
+use tracing::info;
+use url::Url;
+
+/// Let's Encrypt follows at most 10 redirects when validating a challenge.
+const MAX_REDIRECTS: usize = 10;
+/// Let's Encrypt only follows redirects to the standard http and https ports.
+const REDIRECT_PORTS: [u16; 2] = [80, 443];
+
+/// Why the http-01 self-check failed.
+#[derive(Debug)]
+pub enum Failure {
+    /// The domain doesn't resolve.
+    Dns(String),
+    /// The request couldn't be sent or answered, e.g. a refused connection or a timeout.
+    Request(String),
+    /// A redirect without a usable location, or one to something the CA won't follow.
+    Redirect(String),
+    TooManyRedirects,
+    Status(StatusCode),
+    BodyMismatch { expected: String, found: String },
+}
+
+/// A failed self-check with everything needed to find out what's wrong with the setup.
+#[derive(Debug)]
+pub struct SelfCheckError {
+    pub domain: String,
+    /// Every url requested, starting with the challenge url on the domain itself.
+    pub redirects: Vec<String>,
+    pub failure: Failure,
+}
+
+impl fmt::Display for SelfCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP-01 self-check for {} failed: ", self.domain)?;
+        match &self.failure {
+            Failure::Dns(error) => write!(f, "the domain could not be resolved ({})", error)?,
+            Failure::Request(error) => write!(f, "the request failed ({})", error)?,
+            Failure::Redirect(error) => write!(f, "invalid redirect ({})", error)?,
+            Failure::TooManyRedirects => write!(f, "more than {} redirects", MAX_REDIRECTS)?,
+            Failure::Status(status) => write!(f, "the challenge url answered with status {}", status)?,
+            Failure::BodyMismatch { expected, found } => {
+                write!(f, "the challenge url answered with '{}' instead of '{}'", found, expected)?
+            }
+        }
+        write!(f, ". Requested: {}", self.redirects.join(" -> "))
+    }
+}
+
+impl Error for SelfCheckError {}
+
+/// Requests the challenge from `http://<domain>/.well-known/acme-challenge/<token>` like the
+/// CA does, following redirects to http and https urls on port 80 or 443, and makes sure it answers with the
+/// expected key authorization. Fails with a `SelfCheckError` describing the problem.
+pub async fn self_check(domain: &str, token: &str, key_authorization: &str) -> Result<(), SelfCheckError> {
+    let mut error = SelfCheckError {
+        domain: domain.to_string(),
+        redirects: Vec::new(),
+        failure: Failure::TooManyRedirects,
+    };
+
+    if let Err(dns_error) = tokio::net::lookup_host((domain, 80)).await {
+        error.failure = Failure::Dns(dns_error.to_string());
+        return Err(error);
+    }
+
+    let url = match Url::parse(&format!("http://{}/.well-known/acme-challenge/{}", domain, token)) {
+        Ok(url) => url,
+        Err(parse_error) => {
+            error.failure = Failure::Redirect(parse_error.to_string());
+            return Err(error);
+        }
+    };
+    follow(url, key_authorization, &REDIRECT_PORTS, error).await
+}
+
+/// Requests `url` and follows its redirects to http and https urls on one of `ports`, until
+/// a response has the expected key authorization. `error` has the domain checked.
+async fn follow(mut url: Url, key_authorization: &str, ports: &[u16], mut error: SelfCheckError) -> Result<(), SelfCheckError> {
+    // redirects are followed by hand to report the chain, https certificates aren't checked
+    // because the CA doesn't check them either
+    let client = Client::builder()
+        .redirect(Policy::none())
+        .danger_accept_invalid_certs(true)
+        .timeout(Duration::from_secs(10))
+        .build();
+    let client = match client {
+        Ok(client) => client,
+        Err(request_error) => {
+            error.failure = Failure::Request(request_error.to_string());
+            return Err(error);
+        }
+    };
+
+    for _ in 0..=MAX_REDIRECTS {
+        error.redirects.push(url.to_string());
+
+        let response = match client.get(url.clone()).send().await {
+            Ok(response) => response,
+            Err(request_error) => {
+                error.failure = Failure::Request(request_error.to_string());
+                return Err(error);
+            }
+        };
+
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get("location")
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| format!("status {} without a location", status))
+                .and_then(|location| url.join(location).map_err(|parse_error| parse_error.to_string()));
+
+            url = match location {
+                Ok(next) if next.scheme() != "http" && next.scheme() != "https" => {
+                    error.failure = Failure::Redirect(format!("unsupported scheme in {}", next));
+                    return Err(error);
+                }
+                Ok(next) if !next.port_or_known_default().is_some_and(|port| ports.contains(&port)) => {
+                    let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+                    error.failure = Failure::Redirect(format!("the CA only follows redirects to port {}, not {}", ports.join(" or "), next));
+                    return Err(error);
+                }
+                Ok(next) => next,
+                Err(location_error) => {
+                    error.failure = Failure::Redirect(location_error);
+                    return Err(error);
+                }
+            };
+            continue;
+        }
+
+        if status != StatusCode::OK {
+            error.failure = Failure::Status(status);
+            return Err(error);
+        }
+
+        let body = match response.text().await {
+            Ok(body) => body,
+            Err(request_error) => {
+                error.failure = Failure::Request(request_error.to_string());
+                return Err(error);
+            }
+        };
+
+        if body.trim() != key_authorization {
+            error.failure = Failure::BodyMismatch {
+                expected: key_authorization.to_string(),
+                found: body.chars().take(200).collect(),
+            };
+            return Err(error);
+        }
+
+        info!("HTTP-01 self-check for {} passed via {}", error.domain, error.redirects.join(" -> "));
+        return Ok(());
+    }
+
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Path, http::StatusCode as Status, response::Redirect, routing::get, Router};
+
+    const KEY_AUTHORIZATION: &str = "token.thumbprint";
+
+    /// Starts a site answering challenges and redirecting on a free local port, returns it.
+    async fn site() -> Url {
+        let routes = Router::new()
+            .route("/.well-known/acme-challenge/token", get(|| async { format!("{}\n", KEY_AUTHORIZATION) }))
+            .route("/.well-known/acme-challenge/other", get(|| async { "other.thumbprint" }))
+            .route("/.well-known/acme-challenge/broken", get(|| async { (Status::INTERNAL_SERVER_ERROR, "broken") }))
+            .route(
+                "/hops/:hops",
+                get(|Path(hops): Path<usize>| async move {
+                    match hops {
+                        0 => Redirect::temporary("/.well-known/acme-challenge/token"),
+                        hops => Redirect::permanent(&format!("/hops/{}", hops - 1)),
+                    }
+                }),
+            )
+            .route("/loop", get(|| async { Redirect::to("/loop") }))
+            .route("/ftp", get(|| async { Redirect::to("ftp://127.0.0.1/.well-known/acme-challenge/token") }))
+            .route("/port", get(|| async { Redirect::to("http://127.0.0.1:8080/.well-known/acme-challenge/token") }))
+            .route("/nowhere", get(|| async { Status::FOUND }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", listener.local_addr().unwrap().port())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, routes).await });
+        url
+    }
+
+    /// Checks `path` on the site, allowing redirects to the site's own port only.
+    async fn check(path: &str) -> Result<(), SelfCheckError> {
+        let site = site().await;
+        let error = SelfCheckError { domain: "www.example.com".to_string(), redirects: Vec::new(), failure: Failure::TooManyRedirects };
+        follow(site.join(path).unwrap(), KEY_AUTHORIZATION, &[site.port().unwrap()], error).await
+    }
+
+    #[tokio::test]
+    async fn passes_with_the_key_authorization() {
+        check("/.well-known/acme-challenge/token").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_with_another_body_or_status() {
+        let error = check("/.well-known/acme-challenge/other").await.unwrap_err();
+        assert!(matches!(&error.failure, Failure::BodyMismatch { found, .. } if found == "other.thumbprint"), "{}", error);
+
+        let error = check("/.well-known/acme-challenge/broken").await.unwrap_err();
+        assert!(matches!(error.failure, Failure::Status(StatusCode::INTERNAL_SERVER_ERROR)), "{}", error);
+        let error = check("/.well-known/acme-challenge/missing").await.unwrap_err();
+        assert!(matches!(error.failure, Failure::Status(StatusCode::NOT_FOUND)), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn follows_redirects() {
+        check("/hops/3").await.unwrap();
+        check(&format!("/hops/{}", MAX_REDIRECTS - 1)).await.unwrap();
+
+        let error = check(&format!("/hops/{}", MAX_REDIRECTS)).await.unwrap_err();
+        assert!(matches!(error.failure, Failure::TooManyRedirects), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn stops_redirect_loops() {
+        let error = check("/loop").await.unwrap_err();
+        assert!(matches!(error.failure, Failure::TooManyRedirects), "{}", error);
+        assert_eq!(error.redirects.len(), MAX_REDIRECTS + 1);
+        assert!(error.to_string().contains("/loop -> "), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn rejects_redirects_the_ca_does_not_follow() {
+        for path in ["/ftp", "/port", "/nowhere"] {
+            let error = check(path).await.unwrap_err();
+            assert!(matches!(error.failure, Failure::Redirect(_)), "{}", error);
+            assert_eq!(error.redirects.len(), 1);
+        }
+        let error = check("/port").await.unwrap_err();
+        assert!(error.to_string().contains("127.0.0.1:8080"), "{}", error);
+    }
+}
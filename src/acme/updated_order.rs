@@ -1,5 +1,5 @@
 use super::{
-    util::{deserialize_to_string, jws},
+    util::{deserialize_to_string, jws, AcmeError},
     Nonce,
 };
 use crate::Environment;
@@ -24,31 +24,36 @@ pub struct UpdatedOrder {
 }
 
 impl UpdatedOrder {
-    /// Downloads an issued certificate.
+    /// Downloads an issued certificate from the certificate url of a valid order.
     pub async fn download_certificate(
-        &self,
         client: &Client,
+        certificate_url: &str,
         account_url: &str,
+        nonce: Nonce,
         env: &Environment,
-    ) -> Result<String, Box<dyn Error>> {
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
         let header = json!({
             "alg": "RS256",
-            "url": self.certificate,
+            "url": certificate_url,
             "kid": account_url,
-            "nonce": self.nonce,
+            "nonce": nonce,
         });
         let payload = json!("");
 
         let jws = jws(payload, header, env).await?;
 
-        Ok(client
-            .post(&self.certificate)
+        let response = client
+            .post(certificate_url)
             .header("Content-Type", "application/jose+json")
             .header("Accept", "application/pem-certificate-chain")
             .body(serde_json::to_string_pretty(&jws)?)
             .send()
-            .await?
-            .text()
-            .await?)
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Box::new(AcmeError::from_response(response).await));
+        }
+
+        Ok(response.text().await?)
     }
 }
@@ -6,9 +6,59 @@ use reqwest::Response;
 use serde::{de::DeserializeOwned, Deserialize, Deserializer};
 use serde_json::json;
 use sha2::{Digest, Sha256};
-use std::error::Error;
+use std::{error::Error, fmt};
+
+/// An error document sent by the ACME server, see RFC 8555 section 6.7.
+#[derive(Debug, Deserialize)]
+pub struct AcmeError {
+    /// e.g. `urn:ietf:params:acme:error:rateLimited`
+    #[serde(rename = "type", default)]
+    pub error_type: String,
+    #[serde(default)]
+    pub detail: String,
+    #[serde(skip)]
+    pub status: u16,
+}
+
+impl AcmeError {
+    /// The error type without the `urn:ietf:params:acme:error:` prefix.
+    pub fn kind(&self) -> &str {
+        self.error_type
+            .strip_prefix("urn:ietf:params:acme:error:")
+            .unwrap_or(&self.error_type)
+    }
+
+    /// Reads the error document of a failed response. Servers answering with something
+    /// else still produce an error, with the body as detail.
+    pub async fn from_response(response: Response) -> Self {
+        let status = response.status().as_u16();
+        let body = response.text().await.unwrap_or_default();
+        let mut error = serde_json::from_str(&body).unwrap_or_else(|_| AcmeError {
+            error_type: String::new(),
+            detail: body.chars().take(200).collect(),
+            status,
+        });
+        error.status = status;
+        error
+    }
+}
+
+impl fmt::Display for AcmeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The ACME server answered with status {}", self.status)?;
+        if !self.error_type.is_empty() {
+            write!(f, " ({})", self.kind())?;
+        }
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
 
-pub fn jwk(account_key: &KeyVaultKey) -> Result<serde_json::Value, Box<dyn Error>> {
+impl Error for AcmeError {}
+
+pub fn jwk(account_key: &KeyVaultKey) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
     let e = b64(account_key.key.e.as_ref().unwrap());
     let n = b64(account_key.key.n.as_ref().unwrap());
 
@@ -23,7 +73,7 @@ pub async fn jws(
     payload: serde_json::Value,
     header: serde_json::Value,
     env: &Environment,
-) -> Result<serde_json::Value, Box<dyn Error>> {
+) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
     // edge case when the payload needs to be empty, e.g. for
     // fetching the challenges or downloading the certificate
     let empty_payload = payload == json!("");
@@ -40,7 +90,7 @@ pub async fn jws(
     hasher.update(result);
     let result_hash = hasher.finalize();
 
-    let signature = sign(env, b64(&result_hash[..])).await?;
+    let signature = sign(env, b64(&result_hash[..])).await.map_err(|error| error.to_string())?;
 
     Ok(json!({
         "protected": header64,
@@ -65,10 +115,14 @@ where
 
 /// Extracts the payload and `replay-nonce` header field from a given http `Response`.
 #[inline]
-pub async fn extract_payload_and_nonce<T>(response: Response) -> Result<(Nonce, T), Box<dyn Error>>
+pub async fn extract_payload_and_nonce<T>(response: Response) -> Result<(Nonce, T), Box<dyn Error + Send + Sync>>
 where
     T: DeserializeOwned,
 {
+    if !response.status().is_success() {
+        return Err(Box::new(AcmeError::from_response(response).await));
+    }
+
     let replay_nonce = response
         .headers()
         .get("replay-nonce")
@@ -90,10 +144,14 @@ where
 #[inline]
 pub async fn extract_payload_location_and_nonce<T>(
     response: Response,
-) -> Result<(String, Nonce, T), Box<dyn Error>>
+) -> Result<(String, Nonce, T), Box<dyn Error + Send + Sync>>
 where
     T: DeserializeOwned,
 {
+    if !response.status().is_success() {
+        return Err(Box::new(AcmeError::from_response(response).await));
+    }
+
     let replay_nonce = response
         .headers()
         .get("replay-nonce")
@@ -0,0 +1,329 @@
+use super::PREFIX;
+use crate::{
+    acme::job::Job,
+    audit::{Action, Actor, AuditEvent},
+    certificate::{self, CertificateInfo},
+    deploy::{self, Deployment, Target},
+    notify::subscription::Subscription,
+    renewal::policy::RenewalPolicy,
+    utils::{app_error::{AppError, Problem}, principal::{Principal, Role}},
+    Environment,
+};
+use axum::{
+    body::Bytes,
+    extract::{rejection::JsonRejection, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct IssueRequest {
+    #[schema(example = "www.example.com")]
+    pub domain: String,
+    /// Defaults to the app's renewal policy.
+    #[schema(example = "days:30")]
+    pub renewal_policy: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct RevokeRequest {
+    /// RFC 5280 revocation reason code, e.g. 1 for a compromised key.
+    pub reason: Option<u8>,
+}
+
+impl RevokeRequest {
+    /// Checks the reason is one of the RFC 5280 codes, 7 is unused.
+    fn validate(&self) -> Result<(), String> {
+        match self.reason {
+            Some(reason) if reason > 10 || reason == 7 => Err(format!("{} is not an RFC 5280 revocation reason code", reason)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Answers with 202 and the job, pointing the caller to the job to poll.
+fn accepted(job: Job) -> Response {
+    let location = format!("{}/jobs/{}", PREFIX, job.id);
+    (StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(job)).into_response()
+}
+
+/// Lists the certificates in the vault.
+#[utoipa::path(
+    get,
+    path = "/api/v1/certificates",
+    tag = "certificates",
+    responses(
+        (status = 200, body = [CertificateInfo]),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 502, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn list(
+    State(env): State<Environment>,
+    principal: Principal,
+) -> Result<Json<Vec<CertificateInfo>>, AppError> {
+    principal.require(Role::Viewer)?;
+    Ok(Json(certificate::list(&env).await?))
+}
+
+/// Returns a single certificate.
+#[utoipa::path(
+    get,
+    path = "/api/v1/certificates/{name}",
+    tag = "certificates",
+    params(("name" = String, Path, description = "The Key Vault certificate name")),
+    responses(
+        (status = 200, body = CertificateInfo),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn get(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(name): Path<String>,
+) -> Result<Json<CertificateInfo>, AppError> {
+    principal.require(Role::Viewer)?;
+    Ok(Json(certificate::get(&env, &name).await?))
+}
+
+/// Starts issuing a certificate for a new domain.
+#[utoipa::path(
+    post,
+    path = "/api/v1/certificates",
+    tag = "certificates",
+    request_body = IssueRequest,
+    responses(
+        (status = 202, body = Job, description = "Issuing in the background, the Location header points to the job"),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 429, body = Problem, content_type = "application/problem+json", description = "Issuing would exceed a Let's Encrypt rate limit"),
+    )
+)]
+pub async fn issue(
+    State(env): State<Environment>,
+    principal: Principal,
+    body: Result<Json<IssueRequest>, JsonRejection>,
+) -> Result<Response, AppError> {
+    principal.require(Role::Operator)?;
+    let Json(body) = body.map_err(|rejection| AppError::Validation(rejection.body_text()))?;
+
+    let policy = match body.renewal_policy.filter(|policy| !policy.trim().is_empty()) {
+        Some(policy) => Some(policy.parse::<RenewalPolicy>().map_err(AppError::Validation)?),
+        None => None,
+    };
+
+    let job = certificate::issue(&env, &body.domain, policy, Actor::from(&principal)).await?;
+    Ok(accepted(job))
+}
+
+/// Starts renewing a certificate right away, regardless of its renewal policy.
+#[utoipa::path(
+    post,
+    path = "/api/v1/certificates/{name}/renew",
+    tag = "certificates",
+    params(("name" = String, Path, description = "The Key Vault certificate name")),
+    responses(
+        (status = 202, body = Job, description = "Renewing in the background, the Location header points to the job"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 429, body = Problem, content_type = "application/problem+json", description = "Renewing would exceed a Let's Encrypt rate limit"),
+    )
+)]
+pub async fn renew(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(name): Path<String>,
+) -> Result<Response, AppError> {
+    principal.require(Role::Operator)?;
+    let job = certificate::renew(&env, &name, Actor::from(&principal)).await?;
+    Ok(accepted(job))
+}
+
+/// Revokes the current version of a certificate with the CA.
+#[utoipa::path(
+    post,
+    path = "/api/v1/certificates/{name}/revoke",
+    tag = "certificates",
+    params(("name" = String, Path, description = "The Key Vault certificate name")),
+    request_body(content = Option<RevokeRequest>),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 502, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn revoke(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(name): Path<String>,
+    body: Bytes,
+) -> Result<StatusCode, AppError> {
+    principal.require(Role::Admin)?;
+    // without a body the certificate is revoked without a reason, a body has to be valid
+    let body = match body.is_empty() {
+        true => RevokeRequest::default(),
+        false => {
+            let Json(body) = Json::<RevokeRequest>::from_bytes(&body).map_err(|rejection| AppError::Validation(rejection.body_text()))?;
+            body
+        }
+    };
+    body.validate().map_err(AppError::Validation)?;
+    let event = AuditEvent::new(Actor::from(&principal), Action::Revoke, &name);
+    let result = certificate::revoke(&env, &name, body.reason).await.map_err(AppError::from);
+    match &result {
+        Ok(cert) => event.succeeded(Some(cert)).record(&env).await,
+        Err(error) => event.failed(error.message()).record(&env).await,
+    }
+    result?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Changes which notifications of a certificate are sent where.
+#[utoipa::path(
+    put,
+    path = "/api/v1/certificates/{name}/notifications",
+    tag = "certificates",
+    params(("name" = String, Path, description = "The Key Vault certificate name")),
+    request_body = Subscription,
+    responses(
+        (status = 200, body = Subscription),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn notifications(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(name): Path<String>,
+    body: Result<Json<Subscription>, JsonRejection>,
+) -> Result<Json<Subscription>, AppError> {
+    principal.require(Role::Operator)?;
+    let Json(subscription) = body.map_err(|rejection| AppError::Validation(rejection.body_text()))?;
+    subscription.validate().map_err(AppError::Validation)?;
+
+    let event = AuditEvent::new(Actor::from(&principal), Action::Notifications, &name);
+    let result = certificate::set_subscription(&env, &name, &subscription).await.map_err(AppError::from);
+    match &result {
+        Ok(()) => event.succeeded(None).record(&env).await,
+        Err(error) => event.failed(error.message()).record(&env).await,
+    }
+    result?;
+    Ok(Json(subscription))
+}
+
+/// Returns the services a certificate is deployed to and how deploying to them went.
+#[utoipa::path(
+    get,
+    path = "/api/v1/certificates/{name}/deployments",
+    tag = "certificates",
+    params(("name" = String, Path, description = "The Key Vault certificate name")),
+    responses(
+        (status = 200, body = [Deployment]),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn deployments(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<Deployment>>, AppError> {
+    principal.require(Role::Viewer)?;
+    Ok(Json(deploy::list(&env, &name).await?))
+}
+
+/// Replaces the services a certificate is deployed to. New targets get the current
+/// version in the background, later versions are deployed once they are merged.
+#[utoipa::path(
+    put,
+    path = "/api/v1/certificates/{name}/deployments",
+    tag = "certificates",
+    params(("name" = String, Path, description = "The Key Vault certificate name")),
+    request_body = [Target],
+    responses(
+        (status = 200, body = [Deployment]),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn set_deployments(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(name): Path<String>,
+    body: Result<Json<Vec<Target>>, JsonRejection>,
+) -> Result<Json<Vec<Deployment>>, AppError> {
+    principal.require(Role::Operator)?;
+    let Json(targets) = body.map_err(|rejection| AppError::Validation(rejection.body_text()))?;
+    for target in targets.iter() {
+        target.as_target().validate(&env).map_err(AppError::Validation)?;
+    }
+
+    let event = AuditEvent::new(Actor::from(&principal), Action::Deployments, &name);
+    let result = certificate::set_deployments(&env, &name, targets).await.map_err(AppError::from);
+    match &result {
+        Ok(_) => event.succeeded(None).record(&env).await,
+        Err(error) => event.failed(error.message()).record(&env).await,
+    }
+    Ok(Json(result?))
+}
+
+/// Deletes a certificate from the vault.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/certificates/{name}",
+    tag = "certificates",
+    params(("name" = String, Path, description = "The Key Vault certificate name")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn delete(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    principal.require(Role::Admin)?;
+    let event = AuditEvent::new(Actor::from(&principal), Action::Delete, &name);
+    let result = certificate::delete(&env, &name).await.map_err(AppError::from);
+    match &result {
+        Ok(cert) => event.succeeded(Some(cert)).record(&env).await,
+        Err(error) => event.failed(error.message()).record(&env).await,
+    }
+    result?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_environment, utils::principal::Principal};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn rejects_invalid_revoke_requests() {
+        let env = Arc::new(test_environment());
+        for body in ["{", "[]", r#"{"reason": "keyCompromise"}"#, r#"{"reason": 300}"#, r#"{"reason": 7}"#] {
+            let result = revoke(State(env.clone()), Principal::local(), Path("www-example-com".to_string()), Bytes::from(body)).await;
+            assert!(matches!(result, Err(AppError::Validation(_))), "{} was accepted", body);
+        }
+    }
+
+    #[test]
+    fn accepts_rfc_5280_reasons() {
+        for reason in [None, Some(0), Some(1), Some(4), Some(10)] {
+            assert!(RevokeRequest { reason }.validate().is_ok());
+        }
+        for reason in [7, 11, 255] {
+            assert!(RevokeRequest { reason: Some(reason) }.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use crate::{acme::job::Job, utils::{app_error::{AppError, Problem}, principal::{Principal, Role}}, Environment};
+use axum::{extract::{Path, State}, Json};
+use uuid::Uuid;
+
+/// Returns the state of an issuance job.
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}",
+    tag = "jobs",
+    params(("id" = Uuid, Path, description = "The job id")),
+    responses(
+        (status = 200, body = Job),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn get(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Job>, AppError> {
+    principal.require(Role::Viewer)?;
+
+    match Job::get(&env, id).await? {
+        Some(job) => Ok(Json(job)),
+        None => Err(AppError::NotFound(format!("Job {} not found", id))),
+    }
+}
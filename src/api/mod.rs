@@ -0,0 +1,58 @@
+use crate::{
+    acme::job::{Job, JobState},
+    certificate::CertificateInfo,
+    deploy::{app_service::AppService, application_gateway::ApplicationGateway, file::FileExport, front_door::FrontDoor, Deployment, DeploymentStatus, Target},
+    notify::{subscription::Subscription, Event},
+    renewal::state::RenewalStatus,
+    utils::app_error::Problem,
+};
+use axum::Json;
+use utoipa::OpenApi;
+
+pub mod certificates;
+pub mod jobs;
+
+/// The version prefix of every api route.
+pub const PREFIX: &str = "/api/v1";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Key Vault Let's Encrypt", description = "Issues and renews Let's Encrypt certificates stored in Azure Key Vault."),
+    paths(
+        certificates::list,
+        certificates::get,
+        certificates::issue,
+        certificates::renew,
+        certificates::revoke,
+        certificates::notifications,
+        certificates::deployments,
+        certificates::set_deployments,
+        certificates::delete,
+        jobs::get,
+    ),
+    components(schemas(
+        CertificateInfo,
+        RenewalStatus,
+        Job,
+        JobState,
+        Problem,
+        Subscription,
+        Event,
+        Target,
+        AppService,
+        ApplicationGateway,
+        FrontDoor,
+        FileExport,
+        Deployment,
+        DeploymentStatus,
+        certificates::IssueRequest,
+        certificates::RevokeRequest,
+    )),
+    tags((name = "certificates"), (name = "jobs"))
+)]
+pub struct ApiDoc;
+
+/// Serves the OpenAPI document describing the api.
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
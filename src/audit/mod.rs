@@ -0,0 +1,205 @@
+use crate::{keyvault, store::query::{Page, Query}, utils::principal::Principal, Environment};
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
+use base64::{engine, Engine};
+use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize};
+use std::{error::Error, fmt};
+use time::OffsetDateTime;
+use tracing::info;
+use uuid::Uuid;
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+const CONTAINER: &str = "audit";
+/// The audit page shows this many events at a time.
+const PAGE_SIZE: usize = 200;
+
+/// Who did something, a signed-in user, an api client or the timer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Actor {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+impl Actor {
+    /// The daily certificate check.
+    pub fn timer() -> Self {
+        Actor { id: "timer".to_string(), name: None }
+    }
+
+    /// A Key Vault event reporting a certificate near expiry or expired.
+    pub fn key_vault() -> Self {
+        Actor { id: "keyvault-event".to_string(), name: None }
+    }
+}
+
+impl From<&Principal> for Actor {
+    fn from(principal: &Principal) -> Self {
+        Actor { id: principal.id.clone(), name: principal.name.clone() }
+    }
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Issuing a certificate for a new domain.
+    Register,
+    Renew,
+    Revoke,
+    Delete,
+    /// Changing the renewal policy of a certificate.
+    Policy,
+    /// Changing which notifications of a certificate are sent where.
+    Notifications,
+    /// Changing the services a certificate is deployed to.
+    Deployments,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+}
+
+/// A record of an action on a certificate. Events are only ever added, never changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub actor: Actor,
+    pub action: Action,
+    /// The Key Vault certificate name.
+    pub certificate: String,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    /// The issuance job, for actions running in the background.
+    pub job: Option<Uuid>,
+    /// The certificate version the action resulted in, or acted on if it didn't create one.
+    pub version: Option<String>,
+    pub serial: Option<String>,
+    /// The SHA-1 thumbprint, as Azure shows it.
+    pub thumbprint: Option<String>,
+}
+
+/// Empty form fields mean the filter isn't set.
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    match Option::<String>::deserialize(deserializer)?.filter(|value| !value.trim().is_empty()) {
+        Some(value) => T::deserialize(value.trim().to_string().into_deserializer()).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// What the audit page filters events by, every field narrows the events down.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub certificate: Option<String>,
+    /// Matches the id or name of the actor.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub actor: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub action: Option<Action>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub outcome: Option<Outcome>,
+    /// Continues after the page this was returned with.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub continuation: Option<String>,
+}
+
+impl AuditFilter {
+    /// The query for a page of the matching events, newest first. Fails if the
+    /// continuation isn't one the query returned.
+    pub fn query(&self) -> Result<Query, String> {
+        let mut query = Query::new().order_by_desc("time").then_by_id().limit(PAGE_SIZE);
+        if let Some(certificate) = &self.certificate {
+            query = query.contains(&["certificate"], certificate);
+        }
+        if let Some(actor) = &self.actor {
+            query = query.contains(&["actor.id", "actor.name"], actor);
+        }
+        if let Some(action) = self.action {
+            query = query.equals("action", serde_json::to_value(action).map_err(|error| error.to_string())?);
+        }
+        if let Some(outcome) = self.outcome {
+            query = query.equals("outcome", serde_json::to_value(outcome).map_err(|error| error.to_string())?);
+        }
+        match &self.continuation {
+            Some(continuation) => query.continuation(continuation),
+            None => Ok(query),
+        }
+    }
+}
+
+impl AuditEvent {
+    pub fn new(actor: Actor, action: Action, certificate: &str) -> Self {
+        AuditEvent {
+            id: Uuid::new_v4(),
+            time: OffsetDateTime::now_utc(),
+            actor,
+            action,
+            certificate: certificate.to_string(),
+            outcome: Outcome::Succeeded,
+            error: None,
+            job: None,
+            version: None,
+            serial: None,
+            thumbprint: None,
+        }
+    }
+
+    pub fn job(mut self, job: Uuid) -> Self {
+        self.job = Some(job);
+        self
+    }
+
+    /// Marks the action as done, `cert` is the certificate version it resulted in or acted on.
+    pub fn succeeded(mut self, cert: Option<&KeyVaultGetCertificateResponse>) -> Self {
+        self.outcome = Outcome::Succeeded;
+        if let Some(cert) = cert {
+            self.version = keyvault::version(cert);
+            self.thumbprint = keyvault::thumbprint(cert);
+            self.serial = engine::general_purpose::STANDARD
+                .decode(cert.cer.secret())
+                .ok()
+                .and_then(|der| {
+                    X509Certificate::from_der(&der)
+                        .ok()
+                        .map(|(_, x509)| x509.raw_serial_as_string().to_uppercase())
+                });
+        }
+        self
+    }
+
+    pub fn failed(mut self, error: &str) -> Self {
+        self.outcome = Outcome::Failed;
+        self.error = Some(error.to_string());
+        self
+    }
+
+    /// Adds the event to the audit log. The action already happened at this point, so a
+    /// failure to record it is logged rather than failing the action.
+    pub async fn record(self, env: &Environment) {
+        info!("audit: {} {:?} {} {:?}", self.actor, self.action, self.certificate, self.outcome);
+        if let Err(error) = env.store.insert(CONTAINER, &self.id.to_string(), &self).await.map_err(|error| error.to_string()) {
+            info!("Could not record audit event {}: {}", self.id, error);
+        }
+    }
+
+    /// Returns a page of the events `query` selects, see `AuditFilter::query`.
+    pub async fn list(env: &Environment, query: &Query) -> Result<Page<AuditEvent>, Box<dyn Error>> {
+        env.store.query(CONTAINER, query).await
+    }
+}
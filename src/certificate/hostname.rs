@@ -0,0 +1,157 @@
+use std::{error::Error, fmt};
+use url::Host;
+
+/// RFC 1035 limits a name to 253 characters without the trailing dot.
+const MAX_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// A domain the app won't request a certificate for.
+#[derive(Debug)]
+pub struct DomainError(pub String);
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for DomainError {}
+
+fn invalid(domain: &str, reason: &str) -> DomainError {
+    DomainError(format!("'{}' is not a valid domain: {}", domain, reason))
+}
+
+/// Checks a domain entered by a user and returns it the way the CA and Key Vault need it:
+/// lower case, with international names converted to punycode. Wildcard names are only
+/// accepted with `wildcards`, validating them needs a DNS-01 challenge.
+pub fn validate_domain(input: &str, wildcards: bool) -> Result<String, DomainError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(DomainError("Please enter a domain".to_string()));
+    }
+    if input.ends_with('.') {
+        return Err(invalid(input, "remove the trailing dot"));
+    }
+
+    let (wildcard, name) = match input.strip_prefix("*.") {
+        Some(name) if wildcards => (true, name),
+        Some(_) => return Err(invalid(input, "wildcard certificates need a DNS-01 challenge, which isn't available")),
+        None => (false, input),
+    };
+    if name.contains('*') {
+        return Err(invalid(input, "only the whole leftmost label may be a wildcard"));
+    }
+
+    // parsing as a url host maps international names to punycode and recognizes addresses
+    let name = match Host::parse(name) {
+        Ok(Host::Domain(name)) => name,
+        Ok(Host::Ipv4(_)) | Ok(Host::Ipv6(_)) => return Err(invalid(input, "certificates for IP addresses aren't supported")),
+        Err(error) => return Err(invalid(input, &error.to_string())),
+    };
+    if name.parse::<std::net::IpAddr>().is_ok() {
+        return Err(invalid(input, "certificates for IP addresses aren't supported"));
+    }
+
+    // RFC 1123 host names: letters, digits and hyphens, labels don't start or end with a hyphen
+    if name.len() > MAX_LENGTH {
+        return Err(invalid(input, "the name is longer than 253 characters"));
+    }
+    let labels: Vec<&str> = name.split('.').collect();
+    if labels.len() < 2 {
+        return Err(invalid(input, "the name needs at least two labels, e.g. example.com"));
+    }
+    for label in labels.iter() {
+        if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+            return Err(invalid(input, "every label needs between 1 and 63 characters"));
+        }
+        if !label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+            return Err(invalid(input, "only letters, digits and hyphens are allowed"));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid(input, "labels can't start or end with a hyphen"));
+        }
+    }
+    if labels.last().is_some_and(|tld| tld.chars().all(|c| c.is_ascii_digit())) {
+        return Err(invalid(input, "the top level domain can't be numeric"));
+    }
+
+    Ok(match wildcard {
+        true => format!("*.{}", name),
+        false => name,
+    })
+}
+
+/// Checks that `domain` is in one of the `zones`, e.g. `www.example.com` is in
+/// `example.com`. Every domain is allowed if no zones are configured.
+pub fn check_allowed(domain: &str, zones: &[String]) -> Result<(), DomainError> {
+    let name = domain.strip_prefix("*.").unwrap_or(domain);
+    let allowed = zones.is_empty()
+        || zones
+            .iter()
+            .any(|zone| name == zone || name.ends_with(&format!(".{}", zone)));
+
+    match allowed {
+        true => Ok(()),
+        false => Err(DomainError(format!(
+            "Certificates for '{}' aren't allowed, the domain has to be in one of these zones: {}",
+            domain,
+            zones.join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_valid_domains() {
+        assert_eq!(validate_domain(" WWW.Example.com ", false).unwrap(), "www.example.com");
+        assert_eq!(validate_domain("bücher.example", false).unwrap(), "xn--bcher-kva.example");
+        assert_eq!(validate_domain("a-1.example.co.uk", false).unwrap(), "a-1.example.co.uk");
+        assert_eq!(validate_domain("*.Example.com", true).unwrap(), "*.example.com");
+    }
+
+    #[test]
+    fn rejects_invalid_domains() {
+        let long_label = format!("{}.com", "a".repeat(64));
+        let long_name = format!("{}.com", vec!["a".repeat(60); 5].join("."));
+        for domain in [
+            "",
+            "   ",
+            "example.com.",
+            "*.example.com",
+            "www.*.example.com",
+            "w*w.example.com",
+            "localhost",
+            "127.0.0.1",
+            "[::1]",
+            "1.2.3.4.5",
+            "example.123",
+            "-www.example.com",
+            "www-.example.com",
+            "www..example.com",
+            "www_1.example.com",
+            "https://example.com",
+            "example.com/path",
+            long_label.as_str(),
+            long_name.as_str(),
+        ] {
+            assert!(validate_domain(domain, false).is_err(), "{} was accepted", domain);
+        }
+        assert!(validate_domain("*.*.example.com", true).is_err());
+        assert!(validate_domain("*.com", true).is_err());
+    }
+
+    #[test]
+    fn allows_domains_in_the_zones() {
+        let zones = vec!["example.com".to_string(), "example.org".to_string()];
+        for domain in ["example.com", "www.example.com", "a.b.example.org", "*.example.com"] {
+            assert!(check_allowed(domain, &zones).is_ok(), "{} was refused", domain);
+        }
+        for domain in ["example.net", "badexample.com", "example.com.evil.net", "*.com"] {
+            assert!(check_allowed(domain, &zones).is_err(), "{} was allowed", domain);
+        }
+        assert!(check_allowed("anything.net", &[]).is_ok());
+    }
+}
@@ -0,0 +1,402 @@
+use crate::{
+    acme::{self, cert_new, job::{Job, JobState}, rate_limit, util::URL_SAFE_ENGINE},
+    audit::{Action, Actor, AuditEvent},
+    deploy::{self, Deployment, Target},
+    events::{self, CloudEvent, EventType},
+    keyvault::{cert_name, domain, get_certs, get_tags, list_tags, set_tags, Tags},
+    notify::{self, subscription::Subscription, Event, Notification, Outbox},
+    renewal::{policy::{RenewalPolicy, POLICY_TAG}, state::{RenewalState, RenewalStatus}},
+    Environment,
+};
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
+use base64::{engine, Engine};
+use futures::StreamExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use time::OffsetDateTime;
+use tracing::{info, instrument::WithSubscriber};
+use utoipa::ToSchema;
+use x509_parser::{extensions::GeneralName, prelude::FromDer, certificate::X509Certificate};
+
+pub mod hostname;
+
+/// A certificate in the vault together with its renewal policy and state. This is what
+/// the dashboard shows and the api returns, so both always agree.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CertificateInfo {
+    pub name: String,
+    /// The Key Vault id of the certificate.
+    pub id: String,
+    /// Only set when a single certificate is requested, the certificate list doesn't include it.
+    pub domain: Option<String>,
+    pub enabled: bool,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub not_before: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_on: Option<OffsetDateTime>,
+    #[schema(value_type = String, example = "days:30")]
+    pub renewal_policy: RenewalPolicy,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_renewal: Option<OffsetDateTime>,
+    pub status: RenewalStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_retry: Option<OffsetDateTime>,
+    pub notifications: Subscription,
+}
+
+impl CertificateInfo {
+    fn new(
+        name: String,
+        id: String,
+        enabled: bool,
+        not_before: Option<OffsetDateTime>,
+        expires_on: Option<OffsetDateTime>,
+        tags: Option<&Tags>,
+        env: &Environment,
+    ) -> Self {
+        let (policy, state) = match tags {
+            Some(tags) => (RenewalPolicy::from_tags(tags, env.renewal_policy), RenewalState::from_tags(tags)),
+            None => (env.renewal_policy, RenewalState::default()),
+        };
+
+        CertificateInfo {
+            name,
+            id,
+            domain: None,
+            enabled,
+            not_before,
+            expires_on,
+            renewal_policy: policy,
+            next_renewal: expires_on.map(|expires_on| policy.renewal_date(not_before, expires_on)),
+            status: state.status,
+            attempts: state.attempts,
+            last_error: state.last_error,
+            next_retry: state.next_retry,
+            notifications: tags.map(Subscription::from_tags).unwrap_or_default(),
+        }
+    }
+}
+
+/// Lists the certificates in the vault.
+pub async fn list(env: &Environment) -> Result<Vec<CertificateInfo>, Box<dyn Error>> {
+    let certs = get_certs(env).await?.value;
+    let tags = list_tags(env).await?;
+
+    info!("{} certificates found", certs.len());
+
+    certs
+        .into_iter()
+        .map(|cert| {
+            let name = cert_name(&cert).ok_or("certificate name not found")?;
+            let tags = tags.get(&name);
+            Ok(CertificateInfo::new(
+                name,
+                cert.id,
+                cert.attributes.enabled,
+                cert.attributes.not_before,
+                cert.attributes.expires_on,
+                tags,
+                env,
+            ))
+        })
+        .collect()
+}
+
+/// Returns a single certificate, fails with a Key Vault not found error if it doesn't exist.
+pub async fn get(env: &Environment, name: &str) -> Result<CertificateInfo, Box<dyn Error>> {
+    let cert = env.certificate_client.get(name).await?;
+    info_of(env, name, &cert).await
+}
+
+async fn info_of(
+    env: &Environment,
+    name: &str,
+    cert: &KeyVaultGetCertificateResponse,
+) -> Result<CertificateInfo, Box<dyn Error>> {
+    let tags = get_tags(env, name).await?;
+
+    let mut info = CertificateInfo::new(
+        name.to_string(),
+        cert.id.clone(),
+        cert.attributes.enabled,
+        cert.attributes.not_before,
+        cert.attributes.expires_on,
+        Some(&tags),
+        env,
+    );
+    info.domain = domain(cert).map(|domain| domain.to_string());
+    Ok(info)
+}
+
+/// Everything known about a certificate, shown on its detail page.
+#[derive(Debug)]
+pub struct CertificateDetails {
+    pub info: CertificateInfo,
+    /// The parsed current version, `None` while the certificate is pending.
+    pub x509: Option<X509Details>,
+    /// The status of the Key Vault operation issuing the current version, if it is pending.
+    pub pending: Option<String>,
+    /// The most recent issuance job, whatever its outcome.
+    pub last_job: Option<Job>,
+    /// The job that issued the current version, which knows the CA and chain.
+    pub issued_by: Option<Job>,
+    /// Newest first.
+    pub versions: Vec<CertificateVersion>,
+    pub deployments: Vec<Deployment>,
+}
+
+/// The X.509 data of an issued certificate version.
+#[derive(Debug)]
+pub struct X509Details {
+    pub version: String,
+    pub subject: String,
+    pub issuer: String,
+    pub sans: Vec<String>,
+    /// Hex encoded with colons, like browsers show it.
+    pub serial: String,
+    pub sha1_fingerprint: String,
+    pub sha256_fingerprint: String,
+    pub key_type: String,
+    pub key_size: u64,
+}
+
+#[derive(Debug)]
+pub struct CertificateVersion {
+    pub version: String,
+    pub current: bool,
+    pub enabled: bool,
+    pub created_on: OffsetDateTime,
+    pub not_before: Option<OffsetDateTime>,
+    pub expires_on: Option<OffsetDateTime>,
+}
+
+/// Formats bytes as upper case hex separated by colons.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
+
+/// Returns a certificate with its parsed X.509 data, the jobs that issued it, its Key
+/// Vault versions and where it is deployed to. A pending certificate has no X.509 data
+/// and no versions, only the status of its Key Vault operation.
+pub async fn details(env: &Environment, name: &str) -> Result<CertificateDetails, Box<dyn Error>> {
+    let cert = env.certificate_client.get(name).await;
+    let issued = matches!(&cert, Ok(cert) if !cert.cer.secret().is_empty());
+
+    // a version that is still being issued has no certificate yet, the SDK can't even
+    // read it without one
+    let operation = match issued {
+        true => None,
+        false => env
+            .certificate_client
+            .get_operation(name)
+            .await
+            .ok()
+            .filter(|operation| operation.status != "completed"),
+    };
+
+    let last_job = Job::latest(env, name, None).await?;
+    let issued_by = Job::latest(env, name, Some(JobState::Done)).await?;
+
+    let (info, x509, versions, pending) = match operation {
+        Some(operation) => {
+            let tags = get_tags(env, name).await?;
+            let mut info = CertificateInfo::new(name.to_string(), operation.id, false, None, None, Some(&tags), env);
+            info.domain = last_job.as_ref().map(|job| job.domain.clone());
+            let pending = match operation.status_details.is_empty() {
+                true => operation.status,
+                false => format!("{}: {}", operation.status, operation.status_details),
+            };
+            (info, None, Vec::new(), Some(pending))
+        }
+        None => {
+            let cert = cert?;
+            let info = info_of(env, name, &cert).await?;
+            let x509 = x509_details(&cert)?;
+            let versions = versions(env, name, &x509.version).await?;
+            (info, Some(x509), versions, None)
+        }
+    };
+
+    Ok(CertificateDetails {
+        info,
+        x509,
+        pending,
+        last_job,
+        issued_by,
+        versions,
+        deployments: deploy::list(env, name).await?,
+    })
+}
+
+fn x509_details(cert: &KeyVaultGetCertificateResponse) -> Result<X509Details, Box<dyn Error>> {
+    let der = engine::general_purpose::STANDARD.decode(cert.cer.secret())?;
+    if der.is_empty() {
+        return Err(format!("{} has no issued certificate", cert.id).into());
+    }
+    let (_, x509) = X509Certificate::from_der(&der)?;
+    let sans = match x509.subject_alternative_name()? {
+        Some(san) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Key Vault's x5t is the base64url encoded SHA-1 fingerprint
+    let sha1_fingerprint = hex(&URL_SAFE_ENGINE.decode(&cert.x5t)?);
+    let sha256_fingerprint = hex(&Sha256::digest(&der));
+
+    Ok(X509Details {
+        version: cert.id.rsplit('/').next().unwrap_or_default().to_string(),
+        subject: x509.subject().to_string(),
+        issuer: x509.issuer().to_string(),
+        sans,
+        serial: x509.raw_serial_as_string().to_uppercase(),
+        sha1_fingerprint,
+        sha256_fingerprint,
+        key_type: cert.policy.key_props.kty.clone(),
+        key_size: cert.policy.key_props.key_size,
+    })
+}
+
+/// Returns the Key Vault versions of a certificate, newest first.
+async fn versions(env: &Environment, name: &str, current: &str) -> Result<Vec<CertificateVersion>, Box<dyn Error>> {
+    let mut versions = Vec::new();
+    let mut pages = env.certificate_client.get_versions(name).into_stream();
+    while let Some(page) = pages.next().await {
+        for entry in page?.value {
+            let version = entry.id.rsplit('/').next().unwrap_or_default().to_string();
+            versions.push(CertificateVersion {
+                current: version == current,
+                version,
+                enabled: entry.attributes.enabled,
+                created_on: entry.attributes.created_on,
+                not_before: entry.attributes.not_before,
+                expires_on: entry.attributes.expires_on,
+            });
+        }
+    }
+    versions.sort_by_key(|version| std::cmp::Reverse(version.created_on));
+    Ok(versions)
+}
+
+/// Starts issuing a new certificate for `domain` in the background and returns its job.
+/// Fails with a `DomainError` if the domain is invalid or outside the allowed zones, and
+/// with `RateLimited` if issuing it would exceed one of the CA's rate limits.
+pub async fn issue(
+    env: &Environment,
+    domain: &str,
+    policy: Option<RenewalPolicy>,
+    actor: Actor,
+) -> Result<Job, Box<dyn Error>> {
+    // only HTTP-01 challenges are implemented, which can't validate wildcard names
+    let domain = hostname::validate_domain(domain, false)?;
+    hostname::check_allowed(&domain, &env.allowed_zones)?;
+    let cert_name = domain.replace('.', "-");
+
+    // refused right away rather than failing the job once it runs
+    if let Some(limited) = rate_limit::exceeded(env, std::slice::from_ref(&domain), false).await {
+        return Err(Box::new(limited));
+    }
+
+    // the renewal policy is optional, certificates without one use the default policy
+    let mut tags = Tags::new();
+    if let Some(policy) = policy {
+        tags.insert(POLICY_TAG.to_string(), policy.to_string());
+    }
+
+    let job = Job::new(&domain, &cert_name, tags);
+    job.save(env).await?;
+
+    start(env, job.clone(), AuditEvent::new(actor, Action::Register, &cert_name));
+    Ok(job)
+}
+
+/// Creates the job renewing an existing certificate, keeping its domain and tags. Fails
+/// with `RateLimited` if renewing it would exceed one of the CA's rate limits.
+pub async fn renewal_job(env: &Environment, name: &str) -> Result<Job, Box<dyn Error>> {
+    let cert = env.certificate_client.get(name).await?;
+    let domain = domain(&cert).ok_or("could not extract domain from subject")?;
+    let tags = get_tags(env, name).await?;
+
+    if let Some(limited) = rate_limit::exceeded(env, &[domain.to_string()], true).await {
+        return Err(Box::new(limited));
+    }
+
+    let job = Job { renewal: true, ..Job::new(domain, name, tags) };
+    job.save(env).await?;
+    Ok(job)
+}
+
+/// Starts renewing a certificate in the background and returns its job.
+pub async fn renew(env: &Environment, name: &str, actor: Actor) -> Result<Job, Box<dyn Error>> {
+    let job = renewal_job(env, name).await?;
+    start(env, job.clone(), AuditEvent::new(actor, Action::Renew, name));
+    Ok(job)
+}
+
+/// Runs the job in its own task, the outcome is recorded on the job and in the audit log.
+fn start(env: &Environment, mut job: Job, event: AuditEvent) {
+    let env = env.clone();
+    let event = event.job(job.id);
+    tokio::spawn(
+        async move {
+            let event = match cert_new(&mut job, &env, &Outbox::Immediate).await.map_err(|error| error.to_string()) {
+                Ok(cert) => event.succeeded(Some(&cert)),
+                Err(error) => event.failed(&error),
+            };
+            event.record(&env).await;
+        }
+        .with_current_subscriber(),
+    );
+}
+
+/// Revokes the current version of a certificate with the CA and returns it. The
+/// certificate stays in the vault, renewing it issues a replacement.
+pub async fn revoke(
+    env: &Environment,
+    name: &str,
+    reason: Option<u8>,
+) -> Result<KeyVaultGetCertificateResponse, Box<dyn Error>> {
+    let cert = env.certificate_client.get(name).await?;
+    let der = engine::general_purpose::STANDARD.decode(cert.cer.secret())?;
+
+    acme::revoke(&der, reason, env).await.map_err(|error| error as Box<dyn Error>)?;
+    notify::send(env, Notification::new(Event::Revoked, name).expires_on(cert.attributes.expires_on)).await;
+    events::publish(env, CloudEvent::new(env, EventType::Revoked, name).certificate(&cert)).await;
+    Ok(cert)
+}
+
+/// Changes which notifications of a certificate are sent where.
+pub async fn set_subscription(env: &Environment, name: &str, subscription: &Subscription) -> Result<(), Box<dyn Error>> {
+    let mut tags = get_tags(env, name).await?;
+    subscription.write_tags(&mut tags);
+    set_tags(env, name, &tags).await
+}
+
+/// Changes the services a certificate is deployed to and deploys the current version to
+/// the new ones in the background.
+pub async fn set_deployments(env: &Environment, name: &str, targets: Vec<Target>) -> Result<Vec<Deployment>, Box<dyn Error>> {
+    // only certificates in the vault can be deployed
+    env.certificate_client.get(name).await?;
+    let deployments = deploy::configure(env, name, targets).await?;
+    deploy::start(env, name, false);
+    Ok(deployments)
+}
+
+/// Deletes a certificate from the vault and returns the version it was at.
+pub async fn delete(env: &Environment, name: &str) -> Result<KeyVaultGetCertificateResponse, Box<dyn Error>> {
+    let cert = env.certificate_client.get(name).await?;
+    env.certificate_client.delete(name).await?;
+    deploy::remove(env, name).await;
+    events::publish(env, CloudEvent::new(env, EventType::Deleted, name).certificate(&cert)).await;
+    Ok(cert)
+}
@@ -0,0 +1,173 @@
+use super::{arm::{resource_name, validate_resource_id, Arm}, DeploymentTarget};
+use crate::{keyvault, Environment};
+use async_trait::async_trait;
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::error::Error;
+use tracing::info;
+use utoipa::ToSchema;
+
+const API_VERSION: &str = "2023-12-01";
+
+/// A custom domain of an App Service app, bound to a certificate the app imported from
+/// Key Vault. App Service syncs Key Vault certificates only once a day, so the import is
+/// refreshed and the binding moved to the new thumbprint right away.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct AppService {
+    /// The resource id of the app, a `Microsoft.Web/sites` resource.
+    pub site: String,
+    /// The custom domain whose binding uses the certificate.
+    #[schema(example = "www.example.com")]
+    pub hostname: String,
+    /// The resource id of the imported certificate, a `Microsoft.Web/certificates` resource.
+    pub certificate: String,
+}
+
+#[async_trait]
+impl DeploymentTarget for AppService {
+    fn describe(&self) -> String {
+        format!("App Service {} ({})", resource_name(&self.site), self.hostname)
+    }
+
+    fn validate(&self, _env: &Environment) -> Result<(), String> {
+        validate_resource_id(&self.site, "Microsoft.Web/sites")?;
+        validate_resource_id(&self.certificate, "Microsoft.Web/certificates")?;
+        match self.hostname.trim().is_empty() {
+            true => Err("The App Service target needs a hostname".to_string()),
+            false => Ok(()),
+        }
+    }
+
+    async fn deploy(&self, env: &Environment, cert: &KeyVaultGetCertificateResponse) -> Result<(), Box<dyn Error>> {
+        let arm = Arm::new(env);
+        let thumbprint = keyvault::thumbprint(cert).ok_or("The certificate has no thumbprint")?;
+
+        let imported = arm.get(&self.certificate, API_VERSION).await?;
+        let properties = &imported["properties"];
+        if !thumbprint.eq_ignore_ascii_case(properties["thumbprint"].as_str().unwrap_or_default()) {
+            // putting the import again makes App Service fetch the current version
+            let body = json!({
+                "location": imported["location"],
+                "properties": {
+                    "keyVaultId": properties["keyVaultId"],
+                    "keyVaultSecretName": properties["keyVaultSecretName"],
+                    "serverFarmId": properties["serverFarmId"],
+                },
+            });
+            let imported = arm.put(&self.certificate, API_VERSION, &body).await?;
+            let synced = imported["properties"]["thumbprint"].as_str().unwrap_or_default();
+            if !thumbprint.eq_ignore_ascii_case(synced) {
+                return Err(format!("App Service still has certificate {} instead of {}", synced, thumbprint).into());
+            }
+            info!("Refreshed {} to {}", self.certificate, thumbprint);
+        }
+
+        let binding = format!("{}/hostNameBindings/{}", self.site.trim_end_matches('/'), self.hostname);
+        let body = json!({ "properties": { "sslState": "SniEnabled", "thumbprint": thumbprint } });
+        arm.put(&binding, API_VERSION, &body).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{acme::util::URL_SAFE_ENGINE, deploy::arm::ArmError, test_environment, EnvironmentInner};
+    use axum::{extract::State, http::{Method, StatusCode, Uri}, response::IntoResponse, Json, Router};
+    use base64::Engine;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+    use url::Url;
+
+    const SITE: &str = "/subscriptions/sub/resourceGroups/rg/providers/Microsoft.Web/sites/app";
+    const IMPORTED: &str = "/subscriptions/sub/resourceGroups/rg/providers/Microsoft.Web/certificates/www-example-com";
+
+    type Requests = Arc<Mutex<Vec<(Method, String, Value)>>>;
+
+    /// Answers like ARM with an imported certificate that has the `OLD` thumbprint until
+    /// it is put again, or with `status` for every request.
+    async fn stub(State((requests, status)): State<(Requests, StatusCode)>, method: Method, uri: Uri, body: String) -> impl IntoResponse {
+        let body = serde_json::from_str(&body).unwrap_or(Value::Null);
+        requests.lock().unwrap().push((method.clone(), uri.to_string(), body));
+        if !status.is_success() {
+            let error = json!({ "error": { "code": "AuthorizationFailed", "message": "The client has no access" } });
+            return (status, Json(error));
+        }
+        let thumbprint = if method == Method::GET { "OLD".to_string() } else { "AB".repeat(20) };
+        let resource = json!({
+            "location": "westeurope",
+            "properties": {
+                "thumbprint": thumbprint,
+                "keyVaultId": "/subscriptions/sub/resourceGroups/rg/providers/Microsoft.KeyVault/vaults/vault",
+                "keyVaultSecretName": "www-example-com",
+                "serverFarmId": "/subscriptions/sub/resourceGroups/rg/providers/Microsoft.Web/serverfarms/plan",
+            },
+        });
+        (StatusCode::OK, Json(resource))
+    }
+
+    /// Starts the ARM stub on a free local port and returns an environment deploying through it.
+    async fn start_stub(status: StatusCode) -> (Environment, Requests) {
+        let requests = Requests::default();
+        let app = Router::new().fallback(stub).with_state((requests.clone(), status));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Url::parse(&format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let env = Arc::new(EnvironmentInner { arm_endpoint: endpoint, ..test_environment() });
+        (env, requests)
+    }
+
+    fn certificate() -> KeyVaultGetCertificateResponse {
+        let id = "https://test.vault.azure.net/certificates/www-example-com/0123456789";
+        serde_json::from_value(json!({
+            "kid": id.replace("certificates", "keys"),
+            "sid": id.replace("certificates", "secrets"),
+            "x5t": URL_SAFE_ENGINE.encode([0xAB; 20]),
+            "cer": "",
+            "id": id,
+            "attributes": { "enabled": true, "created": 1700000000, "updated": 1700000000, "recoveryLevel": "Recoverable" },
+            "policy": {
+                "id": format!("{}/policy", id),
+                "key_props": { "exportable": false, "kty": "RSA", "key_size": 2048, "reuse_key": false },
+                "secret_props": { "contentType": "application/x-pkcs12" },
+                "x509_props": { "subject": "CN=www.example.com", "validity_months": 3 },
+                "issuer": { "name": "Unknown" },
+                "attributes": { "enabled": true, "created": 1700000000, "updated": 1700000000 },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn target() -> AppService {
+        AppService { site: SITE.to_string(), hostname: "www.example.com".to_string(), certificate: IMPORTED.to_string() }
+    }
+
+    #[tokio::test]
+    async fn refreshes_the_import_and_moves_the_binding() {
+        let (env, requests) = start_stub(StatusCode::OK).await;
+        target().deploy(&env, &certificate()).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let calls: Vec<(&Method, &str)> = requests.iter().map(|(method, uri, _)| (method, uri.as_str())).collect();
+        let imported = format!("{}?api-version={}", IMPORTED, API_VERSION);
+        let binding = format!("{}/hostNameBindings/www.example.com?api-version={}", SITE, API_VERSION);
+        assert_eq!(calls, [(&Method::GET, imported.as_str()), (&Method::PUT, imported.as_str()), (&Method::PUT, binding.as_str())]);
+
+        assert_eq!(requests[1].2["properties"]["keyVaultSecretName"], "www-example-com");
+        assert_eq!(requests[1].2["location"], "westeurope");
+        assert_eq!(requests[2].2, json!({ "properties": { "sslState": "SniEnabled", "thumbprint": "AB".repeat(20) } }));
+    }
+
+    #[tokio::test]
+    async fn reports_arm_errors() {
+        let (env, requests) = start_stub(StatusCode::FORBIDDEN).await;
+        let error = target().deploy(&env, &certificate()).await.unwrap_err();
+
+        let error = error.downcast_ref::<ArmError>().unwrap();
+        assert_eq!(error.status, StatusCode::FORBIDDEN);
+        assert_eq!(error.code.as_deref(), Some("AuthorizationFailed"));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+}
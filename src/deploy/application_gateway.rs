@@ -0,0 +1,58 @@
+use super::{arm::{resource_name, validate_resource_id, Arm}, DeploymentTarget};
+use crate::Environment;
+use async_trait::async_trait;
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use utoipa::ToSchema;
+
+const API_VERSION: &str = "2024-05-01";
+
+/// A listener certificate of an Application Gateway that references a Key Vault secret
+/// with its version, which the gateway never updates on its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ApplicationGateway {
+    /// The resource id of the gateway, a `Microsoft.Network/applicationGateways` resource.
+    pub gateway: String,
+    /// The name of the SSL certificate in the gateway.
+    pub certificate: String,
+}
+
+#[async_trait]
+impl DeploymentTarget for ApplicationGateway {
+    fn describe(&self) -> String {
+        format!("Application Gateway {} ({})", resource_name(&self.gateway), self.certificate)
+    }
+
+    fn validate(&self, _env: &Environment) -> Result<(), String> {
+        validate_resource_id(&self.gateway, "Microsoft.Network/applicationGateways")?;
+        match self.certificate.trim().is_empty() {
+            true => Err("The Application Gateway target needs the name of its SSL certificate".to_string()),
+            false => Ok(()),
+        }
+    }
+
+    async fn deploy(&self, env: &Environment, cert: &KeyVaultGetCertificateResponse) -> Result<(), Box<dyn Error>> {
+        let arm = Arm::new(env);
+        // the gateway is only updated as a whole, its other settings go back as they are
+        let mut gateway = arm.get(&self.gateway, API_VERSION).await?;
+        let ssl_certificate = gateway["properties"]["sslCertificates"]
+            .as_array_mut()
+            .and_then(|certificates| {
+                certificates
+                    .iter_mut()
+                    .find(|certificate| certificate["name"].as_str() == Some(self.certificate.as_str()))
+            })
+            .ok_or_else(|| format!("The gateway has no SSL certificate {}", self.certificate))?;
+
+        let secret_id = &mut ssl_certificate["properties"]["keyVaultSecretId"];
+        if secret_id.as_str() == Some(cert.secret_id.as_str()) {
+            return Ok(());
+        }
+        *secret_id = Value::String(cert.secret_id.clone());
+
+        arm.put(&self.gateway, API_VERSION, &gateway).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::Environment;
+use serde_json::Value;
+use std::{error::Error, fmt};
+use url::Url;
+
+/// The scope of the app's token for Azure Resource Manager.
+const ARM_SCOPE: &str = "https://management.azure.com/.default";
+
+/// An error answered by Azure Resource Manager.
+#[derive(Debug)]
+pub struct ArmError {
+    pub status: reqwest::StatusCode,
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ArmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "Azure Resource Manager answered {} ({}): {}", self.status, code, self.message),
+            None => write!(f, "Azure Resource Manager answered {}: {}", self.status, self.message),
+        }
+    }
+}
+
+impl Error for ArmError {}
+
+/// Reads and writes Azure resources by their resource id.
+pub struct Arm<'a> {
+    env: &'a Environment,
+    client: reqwest::Client,
+}
+
+impl<'a> Arm<'a> {
+    pub fn new(env: &'a Environment) -> Self {
+        Arm { env, client: reqwest::Client::new() }
+    }
+
+    pub async fn get(&self, resource: &str, api_version: &str) -> Result<Value, Box<dyn Error>> {
+        self.send(reqwest::Method::GET, resource, api_version, None).await
+    }
+
+    /// Replaces the resource, ARM answers with the resource as it is being provisioned.
+    pub async fn put(&self, resource: &str, api_version: &str, body: &Value) -> Result<Value, Box<dyn Error>> {
+        self.send(reqwest::Method::PUT, resource, api_version, Some(body)).await
+    }
+
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        resource: &str,
+        api_version: &str,
+        body: Option<&Value>,
+    ) -> Result<Value, Box<dyn Error>> {
+        let mut url = self.url(resource)?;
+        url.query_pairs_mut().append_pair("api-version", api_version);
+
+        let mut request = self.client.request(method, url);
+        // a stub of the ARM endpoints on this machine doesn't check tokens
+        if !is_local(&self.env.arm_endpoint) {
+            let token = self.env.credential.get_token(&[ARM_SCOPE]).await?;
+            request = request.bearer_auth(token.token.secret());
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let body: Value = match response.text().await? {
+            text if text.is_empty() => Value::Null,
+            text => serde_json::from_str(&text).unwrap_or(Value::String(text)),
+        };
+        if !status.is_success() {
+            let error = body.get("error");
+            return Err(Box::new(ArmError {
+                status,
+                code: error.and_then(|error| error.get("code")).and_then(Value::as_str).map(str::to_string),
+                message: error
+                    .and_then(|error| error.get("message"))
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| body.to_string()),
+            }));
+        }
+        Ok(body)
+    }
+
+    fn url(&self, resource: &str) -> Result<Url, Box<dyn Error>> {
+        let endpoint = self.env.arm_endpoint.as_str().trim_end_matches('/');
+        Ok(Url::parse(&format!("{}/{}", endpoint, resource.trim_start_matches('/')))?)
+    }
+}
+
+/// The last segment of a resource id, the name of the resource.
+pub fn resource_name(resource: &str) -> &str {
+    resource.trim_end_matches('/').rsplit('/').next().unwrap_or(resource)
+}
+
+fn is_local(endpoint: &Url) -> bool {
+    matches!(endpoint.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"))
+}
+
+/// Checks that `resource` is the id of an Azure resource of type `resource_type`, e.g.
+/// `Microsoft.Web/sites`.
+pub fn validate_resource_id(resource: &str, resource_type: &str) -> Result<(), String> {
+    let segments: Vec<&str> = resource.trim_end_matches('/').split('/').collect();
+    let valid = resource.starts_with("/subscriptions/")
+        && segments.len() >= 9
+        && segments.len() % 2 == 1
+        && segments[5].eq_ignore_ascii_case("providers")
+        && {
+            // after the namespace the types of nested resources alternate with their names
+            let types: Vec<&str> = std::iter::once(segments[6]).chain(segments[7..].iter().step_by(2).copied()).collect();
+            types.join("/").eq_ignore_ascii_case(resource_type)
+        };
+    match valid {
+        true => Ok(()),
+        false => Err(format!("'{}' is not the id of a {} resource", resource, resource_type)),
+    }
+}
@@ -0,0 +1,272 @@
+use super::DeploymentTarget;
+use crate::{keyvault::get_secret, Environment};
+use async_trait::async_trait;
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
+use base64::{engine, Engine};
+use p12_keystore::{KeyStore, Pkcs12ImportPolicy};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use tokio::{fs, io::AsyncWriteExt, process::Command};
+use tracing::info;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// The content type of certificate secrets holding a PKCS#12 file, the default.
+const PKCS12: &str = "application/x-pkcs12";
+/// How long the reload command may run.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(60);
+/// Only the owner may read files with the private key, everyone may read the chain.
+const KEY_MODE: u32 = 0o600;
+const CHAIN_MODE: u32 = 0o644;
+
+/// Files on the machine the app runs on, for servers like nginx or HAProxy that read the
+/// certificate from disk. The files are only written inside `EXPORT_DIRECTORY`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct FileExport {
+    /// Where the certificate and its chain are written as PEM.
+    #[schema(example = "/etc/nginx/certs/www.example.com/fullchain.pem")]
+    pub fullchain: Option<String>,
+    /// Where the private key is written as PEM, required with `fullchain`.
+    #[schema(example = "/etc/nginx/certs/www.example.com/privkey.pem")]
+    pub privkey: Option<String>,
+    /// Where the key, certificate and chain are written as a PKCS#12 file without password.
+    pub pfx: Option<String>,
+    /// Run after the files were written, one of `EXPORT_RELOAD_COMMANDS`.
+    #[schema(example = "systemctl reload nginx")]
+    pub reload: Option<String>,
+}
+
+/// Checks that `path` is an absolute path inside `directory` without `..` in it.
+fn validate_path(path: &str, directory: &Path) -> Result<(), String> {
+    let path = Path::new(path);
+    if !path.is_absolute() || path.components().any(|component| component == Component::ParentDir) {
+        return Err(format!("'{}' has to be an absolute path without '..'", path.display()));
+    }
+    match path.starts_with(directory) && path != directory {
+        true => Ok(()),
+        false => Err(format!("'{}' is outside of {}", path.display(), directory.display())),
+    }
+}
+
+/// Checks that the directory `path` is written to is inside `directory` once symlinks are
+/// resolved, `validate_path` only looks at the path as written.
+fn check_resolved(path: &Path, directory: &Path) -> Result<(), String> {
+    let parent = path.parent().unwrap_or(path);
+    let resolved = std::fs::canonicalize(parent).map_err(|error| format!("Could not resolve {}: {}", parent.display(), error))?;
+    let directory = std::fs::canonicalize(directory).map_err(|error| format!("Could not resolve {}: {}", directory.display(), error))?;
+    match resolved.starts_with(&directory) {
+        true => Ok(()),
+        false => Err(format!("'{}' is outside of {} through {}", path.display(), directory.display(), resolved.display())),
+    }
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = engine::general_purpose::STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem += std::str::from_utf8(line).unwrap_or_default();
+        pem += "\n";
+    }
+    pem + &format!("-----END {}-----\n", label)
+}
+
+/// A file written next to its destination, renamed over it once every file is written.
+struct Staged {
+    temp: PathBuf,
+    path: PathBuf,
+}
+
+async fn stage(path: &str, directory: &Path, contents: &[u8], mode: u32) -> Result<Staged, String> {
+    let path = PathBuf::from(path);
+    // checked right before writing, a directory may have been swapped for a symlink since
+    check_resolved(&path, directory)?;
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, Uuid::new_v4()));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // the file has its permissions from the start, the key is never readable by others
+    #[cfg(unix)]
+    options.mode(mode).custom_flags(libc::O_NOFOLLOW);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let mut file = options.open(&temp).await.map_err(|error| error.to_string())?;
+    let written = async {
+        file.write_all(contents).await?;
+        file.sync_all().await
+    }
+    .await;
+    drop(file);
+    if let Err(error) = written {
+        let _ = fs::remove_file(&temp).await;
+        return Err(error.to_string());
+    }
+    Ok(Staged { temp, path })
+}
+
+async fn discard(staged: &[Staged]) {
+    for file in staged {
+        let _ = fs::remove_file(&file.temp).await;
+    }
+}
+
+async fn reload(command: &str) -> Result<(), Box<dyn Error>> {
+    let output = Command::new("sh").arg("-c").arg(command).kill_on_drop(true).output();
+    let output = tokio::time::timeout(RELOAD_TIMEOUT, output)
+        .await
+        .map_err(|_| format!("'{}' didn't finish within {:?}", command, RELOAD_TIMEOUT))??;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("'{}' failed with {}: {}", command, output.status, stderr.trim()).into());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl DeploymentTarget for FileExport {
+    fn describe(&self) -> String {
+        let files: Vec<&str> = [&self.fullchain, &self.privkey, &self.pfx].into_iter().flatten().map(String::as_str).collect();
+        match &self.reload {
+            Some(reload) => format!("Files {}, then {}", files.join(", "), reload),
+            None => format!("Files {}", files.join(", ")),
+        }
+    }
+
+    fn validate(&self, env: &Environment) -> Result<(), String> {
+        let directory = env
+            .export_directory
+            .as_ref()
+            .ok_or("Exporting certificates to files is disabled, set EXPORT_DIRECTORY to allow it")?;
+        match (&self.fullchain, &self.privkey, &self.pfx) {
+            (None, None, None) => return Err("The file target needs fullchain and privkey, pfx or both".to_string()),
+            (Some(_), None, _) | (None, Some(_), _) => return Err("The file target needs both fullchain and privkey".to_string()),
+            _ => {}
+        }
+        for path in [&self.fullchain, &self.privkey, &self.pfx].into_iter().flatten() {
+            validate_path(path, directory)?;
+            check_resolved(Path::new(path), directory)?;
+        }
+        match &self.reload {
+            Some(reload) if !env.export_reload_commands.contains(reload) => {
+                Err(format!("'{}' is not one of the commands in EXPORT_RELOAD_COMMANDS", reload))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn deploy(&self, env: &Environment, cert: &KeyVaultGetCertificateResponse) -> Result<(), Box<dyn Error>> {
+        // checked again, the settings may have changed since the target was saved
+        self.validate(env)?;
+        let directory = env.export_directory.as_ref().ok_or("Exporting certificates to files is disabled")?;
+        if !cert.policy.key_props.exportable {
+            return Err("The key isn't exportable, renew the certificate to issue it with an exportable key".into());
+        }
+        if cert.policy.secret_props.content_type != PKCS12 {
+            return Err(format!("Certificates stored as {} can't be exported", cert.policy.secret_props.content_type).into());
+        }
+
+        let pfx = engine::general_purpose::STANDARD.decode(get_secret(env, &cert.secret_id).await?)?;
+
+        let mut files: Vec<(&str, Vec<u8>, u32)> = Vec::new();
+        if let (Some(fullchain), Some(privkey)) = (&self.fullchain, &self.privkey) {
+            let keystore = KeyStore::from_pkcs12(&pfx, "", Pkcs12ImportPolicy::Strict)?;
+            let (_, chain) = keystore.private_key_chain().ok_or("The certificate secret has no private key")?;
+            let certificates: String = chain.certs().iter().map(|certificate| pem("CERTIFICATE", certificate.as_der())).collect();
+            files.push((fullchain, certificates.into_bytes(), CHAIN_MODE));
+            files.push((privkey, pem("PRIVATE KEY", chain.key().as_der()).into_bytes(), KEY_MODE));
+        }
+        if let Some(path) = &self.pfx {
+            files.push((path, pfx.clone(), KEY_MODE));
+        }
+
+        // every file is written before any is replaced, so a server never sees a key that
+        // doesn't match its certificate for longer than the renames take
+        let mut staged = Vec::new();
+        for (path, contents, mode) in files {
+            match stage(path, directory, &contents, mode).await {
+                Ok(file) => staged.push(file),
+                Err(error) => {
+                    discard(&staged).await;
+                    return Err(format!("Could not write {}: {}", path, error).into());
+                }
+            }
+        }
+        for (index, file) in staged.iter().enumerate() {
+            if let Err(error) = fs::rename(&file.temp, &file.path).await {
+                discard(&staged[index..]).await;
+                return Err(format!("Could not replace {}: {}", file.path.display(), error).into());
+            }
+        }
+        info!("Exported {} to {}", cert.id, self.describe());
+
+        if let Some(command) = &self.reload {
+            reload(command).await?;
+            info!("Ran {}", command);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{test_environment, EnvironmentInner};
+    use std::{os::unix::fs::symlink, sync::Arc};
+
+    /// An export directory with a `certs` directory inside and a symlink `escape` to a
+    /// directory outside of it.
+    fn directories() -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("file-export-{}", Uuid::new_v4()));
+        let export = root.join("export");
+        std::fs::create_dir_all(export.join("certs")).unwrap();
+        std::fs::create_dir_all(root.join("outside")).unwrap();
+        symlink(root.join("outside"), export.join("escape")).unwrap();
+        (root, export)
+    }
+
+    fn export(directory: &Path, name: &str) -> FileExport {
+        FileExport {
+            fullchain: None,
+            privkey: None,
+            pfx: Some(directory.join(name).join("cert.pfx").to_string_lossy().to_string()),
+            reload: None,
+        }
+    }
+
+    #[test]
+    fn checks_paths_lexically() {
+        let directory = Path::new("/etc/nginx/certs");
+        assert!(validate_path("/etc/nginx/certs/example/fullchain.pem", directory).is_ok());
+        assert!(validate_path("/etc/nginx/certs", directory).is_err());
+        assert!(validate_path("/etc/nginx/certs/../nginx.conf", directory).is_err());
+        assert!(validate_path("/etc/nginx/certificates/fullchain.pem", directory).is_err());
+        assert!(validate_path("certs/fullchain.pem", directory).is_err());
+    }
+
+    #[test]
+    fn rejects_symlinks_out_of_the_directory() {
+        let (root, directory) = directories();
+        let env = Arc::new(EnvironmentInner { export_directory: Some(directory.clone()), ..test_environment() });
+
+        assert!(export(&directory, "certs").validate(&env).is_ok());
+        let error = export(&directory, "escape").validate(&env).unwrap_err();
+        assert!(error.contains("outside"), "{}", error);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stages_only_inside_the_directory() {
+        let (root, directory) = directories();
+
+        let staged = stage(&export(&directory, "certs").pfx.unwrap(), &directory, b"pfx", KEY_MODE).await.unwrap();
+        assert_eq!(std::fs::read(&staged.temp).unwrap(), b"pfx");
+        assert!(stage(&export(&directory, "escape").pfx.unwrap(), &directory, b"pfx", KEY_MODE).await.is_err());
+        assert_eq!(std::fs::read_dir(root.join("outside")).unwrap().count(), 0);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+use super::{arm::{resource_name, validate_resource_id, Arm}, DeploymentTarget};
+use crate::{keyvault, Environment};
+use async_trait::async_trait;
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::error::Error;
+use utoipa::ToSchema;
+
+const API_VERSION: &str = "2024-02-01";
+
+/// A customer certificate secret of a Front Door profile pinned to a version, which its
+/// custom domains use.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct FrontDoor {
+    /// The resource id of the secret, a `Microsoft.Cdn/profiles/secrets` resource.
+    pub secret: String,
+}
+
+#[async_trait]
+impl DeploymentTarget for FrontDoor {
+    fn describe(&self) -> String {
+        format!("Front Door secret {}", resource_name(&self.secret))
+    }
+
+    fn validate(&self, _env: &Environment) -> Result<(), String> {
+        validate_resource_id(&self.secret, "Microsoft.Cdn/profiles/secrets")
+    }
+
+    async fn deploy(&self, env: &Environment, cert: &KeyVaultGetCertificateResponse) -> Result<(), Box<dyn Error>> {
+        let arm = Arm::new(env);
+        let version = keyvault::version(cert).ok_or("The certificate has no version")?;
+
+        let secret = arm.get(&self.secret, API_VERSION).await?;
+        let mut parameters = secret["properties"]["parameters"].clone();
+        if parameters["type"].as_str() != Some("CustomerCertificate") {
+            return Err(format!("{} is not a customer certificate secret", self.secret).into());
+        }
+        if parameters["secretVersion"].as_str() == Some(version.as_str()) {
+            return Ok(());
+        }
+        parameters["secretVersion"] = json!(version);
+        parameters["useLatestVersion"] = json!(false);
+
+        arm.put(&self.secret, API_VERSION, &json!({ "properties": { "parameters": parameters } })).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use self::{app_service::AppService, application_gateway::ApplicationGateway, file::FileExport, front_door::FrontDoor};
+use crate::{keyvault, Environment};
+use async_trait::async_trait;
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt};
+use time::OffsetDateTime;
+use tracing::{info, instrument::WithSubscriber};
+use utoipa::ToSchema;
+
+pub mod app_service;
+pub mod application_gateway;
+pub mod arm;
+pub mod file;
+pub mod front_door;
+
+/// The targets are kept in storage rather than in certificate tags, resource ids don't
+/// fit into the 256 characters of a tag.
+const CONTAINER: &str = "deployments";
+
+/// A service or server that uses a certificate and has to be pointed at each new version.
+#[async_trait]
+pub trait DeploymentTarget: Send + Sync + fmt::Debug {
+    /// A human readable description for the certificate page and the logs.
+    fn describe(&self) -> String;
+
+    /// Checks the target before it is saved, `env` has the settings that limit targets.
+    fn validate(&self, env: &Environment) -> Result<(), String>;
+
+    /// Makes the target use `cert`. Deploying the version the target already has changes
+    /// nothing, so a deployment that failed halfway can simply be run again.
+    async fn deploy(&self, env: &Environment, cert: &KeyVaultGetCertificateResponse) -> Result<(), Box<dyn Error>>;
+}
+
+/// A deployment target as it is configured for a certificate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Target {
+    AppService(AppService),
+    ApplicationGateway(ApplicationGateway),
+    FrontDoor(FrontDoor),
+    File(FileExport),
+}
+
+impl Target {
+    pub fn as_target(&self) -> &dyn DeploymentTarget {
+        match self {
+            Target::AppService(target) => target,
+            Target::ApplicationGateway(target) => target,
+            Target::FrontDoor(target) => target,
+            Target::File(target) => target,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeploymentStatus {
+    /// The target wasn't deployed to yet.
+    Pending,
+    Deployed,
+    /// The last deployment failed and is retried with backoff.
+    Retrying,
+    /// Deployment failed too many times in a row and needs attention, it is still retried.
+    Failing,
+}
+
+/// A target of a certificate and how deploying to it went.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Deployment {
+    pub target: Target,
+    pub status: DeploymentStatus,
+    /// The certificate version last deployed to the target.
+    pub version: Option<String>,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_retry: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub updated: Option<OffsetDateTime>,
+}
+
+impl Deployment {
+    fn new(target: Target) -> Self {
+        Deployment {
+            target,
+            status: DeploymentStatus::Pending,
+            version: None,
+            attempts: 0,
+            last_error: None,
+            next_retry: None,
+            updated: None,
+        }
+    }
+
+    /// Whether the target should get `version` now, `force` ignores the backoff.
+    fn is_due(&self, version: &str, force: bool, now: OffsetDateTime) -> bool {
+        self.version.as_deref() != Some(version) && (force || self.next_retry.is_none_or(|next_retry| next_retry <= now))
+    }
+}
+
+/// The deployment targets of a certificate.
+#[derive(Debug, Serialize, Deserialize)]
+struct Deployments {
+    certificate: String,
+    deployments: Vec<Deployment>,
+}
+
+/// Returns the deployment targets of a certificate with their state.
+pub async fn list(env: &Environment, name: &str) -> Result<Vec<Deployment>, Box<dyn Error>> {
+    let deployments: Option<Deployments> = env.store.get(CONTAINER, name).await?;
+    Ok(deployments.map(|deployments| deployments.deployments).unwrap_or_default())
+}
+
+/// Replaces the deployment targets of a certificate. Targets that were configured before
+/// keep their state, new ones are pending until they are deployed to.
+pub async fn configure(env: &Environment, name: &str, targets: Vec<Target>) -> Result<Vec<Deployment>, Box<dyn Error>> {
+    let mut existing = list(env, name).await?;
+    let deployments: Vec<Deployment> = targets
+        .into_iter()
+        .map(|target| match existing.iter().position(|deployment| deployment.target == target) {
+            Some(index) => existing.swap_remove(index),
+            None => Deployment::new(target),
+        })
+        .collect();
+
+    match deployments.is_empty() {
+        true => env.store.delete(CONTAINER, name).await?,
+        false => {
+            let document = Deployments { certificate: name.to_string(), deployments: deployments.clone() };
+            env.store.upsert(CONTAINER, name, &document).await?
+        }
+    }
+    Ok(deployments)
+}
+
+/// Whether a certificate has to be issued with an exportable key, because one of its
+/// targets needs the private key outside of Key Vault.
+pub async fn exports_key(env: &Environment, name: &str) -> bool {
+    match list(env, name).await.map_err(|error| error.to_string()) {
+        Ok(deployments) => deployments.iter().any(|deployment| matches!(deployment.target, Target::File(_))),
+        Err(error) => {
+            info!("Could not read the deployment targets of {}: {}", name, error);
+            false
+        }
+    }
+}
+
+/// Removes the deployment targets of a deleted certificate.
+pub async fn remove(env: &Environment, name: &str) {
+    if let Err(error) = env.store.delete(CONTAINER, name).await.map_err(|error| error.to_string()) {
+        info!("Could not remove the deployment targets of {}: {}", name, error);
+    }
+}
+
+/// Deploys the current version of a certificate to its targets in the background, e.g.
+/// after it was merged. With `force` the targets backing off are deployed to as well.
+pub fn start(env: &Environment, name: &str, force: bool) {
+    let env = env.clone();
+    let name = name.to_string();
+    tokio::spawn(
+        async move {
+            if let Err(error) = deploy(&env, &name, force).await.map_err(|error| error.to_string()) {
+                info!("Could not deploy {}: {}", name, error);
+            }
+        }
+        .with_current_subscriber(),
+    );
+}
+
+/// Retries the failed deployments whose backoff is over and the ones that never ran,
+/// e.g. because the instance stopped right after merging.
+pub async fn retry(env: &Environment) {
+    let all: Vec<Deployments> = match env.store.list(CONTAINER).await.map_err(|error| error.to_string()) {
+        Ok(all) => all,
+        Err(error) => {
+            info!("Could not list the deployment targets: {}", error);
+            return;
+        }
+    };
+
+    for deployments in all {
+        if let Err(error) = deploy(env, &deployments.certificate, false).await.map_err(|error| error.to_string()) {
+            info!("Could not deploy {}: {}", deployments.certificate, error);
+        }
+    }
+}
+
+/// Deploys the current version of a certificate to every target that doesn't have it yet.
+async fn deploy(env: &Environment, name: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    let Some(mut deployments) = env.store.get::<Deployments>(CONTAINER, name).await? else {
+        return Ok(());
+    };
+    let cert = env.certificate_client.get(name).await?;
+    let version = keyvault::version(&cert).ok_or("The certificate has no version")?;
+    let now = OffsetDateTime::now_utc();
+    if !deployments.deployments.iter().any(|deployment| deployment.is_due(&version, force, now)) {
+        return Ok(());
+    }
+
+    for deployment in deployments.deployments.iter_mut().filter(|deployment| deployment.is_due(&version, force, now)) {
+        let target = deployment.target.as_target();
+        let result = target.deploy(env, &cert).await.map_err(|error| error.to_string());
+        deployment.updated = Some(OffsetDateTime::now_utc());
+        match result {
+            Ok(()) => {
+                info!("Deployed {} version {} to {}", name, version, target.describe());
+                deployment.status = DeploymentStatus::Deployed;
+                deployment.version = Some(version.clone());
+                deployment.attempts = 0;
+                deployment.last_error = None;
+                deployment.next_retry = None;
+            }
+            Err(error) => {
+                info!("Could not deploy {} to {}: {}", name, target.describe(), error);
+                deployment.attempts += 1;
+                deployment.status = match deployment.attempts >= env.retry_policy.max_attempts {
+                    true => DeploymentStatus::Failing,
+                    false => DeploymentStatus::Retrying,
+                };
+                deployment.last_error = Some(error);
+                deployment.next_retry = Some(OffsetDateTime::now_utc() + env.retry_policy.delay(deployment.attempts));
+            }
+        }
+    }
+
+    // the targets may have been changed while deploying, only the state of the ones
+    // still configured is kept
+    let mut current = list(env, name).await?;
+    for deployment in current.iter_mut() {
+        if let Some(deployed) = deployments.deployments.iter().find(|deployed| deployed.target == deployment.target) {
+            *deployment = deployed.clone();
+        }
+    }
+    if !current.is_empty() {
+        deployments.deployments = current;
+        env.store.upsert(CONTAINER, name, &deployments).await?;
+    }
+    Ok(())
+}
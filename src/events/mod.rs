@@ -0,0 +1,262 @@
+use crate::{keyvault, Environment};
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::error::Error;
+use time::OffsetDateTime;
+use tracing::info;
+use url::Url;
+use uuid::Uuid;
+
+/// The scope of the app's token for Event Grid topics without an access key.
+const EVENT_GRID_SCOPE: &str = "https://eventgrid.azure.net/.default";
+
+/// The kinds of certificate changes other services can react to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    /// A certificate was issued for a new domain.
+    Issued,
+    Renewed,
+    RenewalFailed,
+    Revoked,
+    Deleted,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::Issued => "certificate.issued",
+            EventType::Renewed => "certificate.renewed",
+            EventType::RenewalFailed => "certificate.renewalFailed",
+            EventType::Revoked => "certificate.revoked",
+            EventType::Deleted => "certificate.deleted",
+        }
+    }
+}
+
+/// The data of an event, enough for consumers to load the certificate version from Key
+/// Vault without listing the certificates first.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CertificateData {
+    /// The Key Vault certificate name.
+    pub name: String,
+    /// The Key Vault id of the certificate version, including the version.
+    pub id: Option<String>,
+    pub version: Option<String>,
+    /// The SHA-1 thumbprint, as Azure shows it.
+    pub thumbprint: Option<String>,
+    pub domain: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_on: Option<OffsetDateTime>,
+    pub error: Option<String>,
+    /// The issuance job, for issued, renewed and failed certificates.
+    pub job: Option<Uuid>,
+}
+
+/// A CloudEvents 1.0 event in the structured JSON format.
+#[derive(Debug, Clone, Serialize)]
+pub struct CloudEvent {
+    pub specversion: &'static str,
+    pub id: Uuid,
+    /// The Key Vault the certificate is in.
+    pub source: String,
+    #[serde(rename = "type")]
+    pub event_type: &'static str,
+    /// The Key Vault certificate name.
+    pub subject: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub datacontenttype: &'static str,
+    pub data: CertificateData,
+}
+
+impl CloudEvent {
+    pub fn new(env: &Environment, event_type: EventType, name: &str) -> Self {
+        CloudEvent {
+            specversion: "1.0",
+            id: Uuid::new_v4(),
+            source: env.keyvault_url.as_str().trim_end_matches('/').to_string(),
+            event_type: event_type.as_str(),
+            subject: name.to_string(),
+            time: OffsetDateTime::now_utc(),
+            datacontenttype: "application/json",
+            data: CertificateData { name: name.to_string(), ..Default::default() },
+        }
+    }
+
+    /// Adds the id, version and thumbprint of the certificate version the event is about.
+    pub fn certificate(mut self, cert: &KeyVaultGetCertificateResponse) -> Self {
+        self.data.id = Some(cert.id.clone());
+        self.data.version = keyvault::version(cert);
+        self.data.thumbprint = keyvault::thumbprint(cert);
+        self.data.domain = keyvault::domain(cert).map(|domain| domain.to_string());
+        self.data.expires_on = cert.attributes.expires_on;
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.data.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn error(mut self, error: &str) -> Self {
+        self.data.error = Some(error.to_string());
+        self
+    }
+
+    pub fn job(mut self, job: Uuid) -> Self {
+        self.data.job = Some(job);
+        self
+    }
+}
+
+/// Where events are published to.
+#[derive(Debug)]
+pub enum EventSink {
+    /// Any HTTP endpoint, each event is posted on its own in structured mode.
+    Http(Url),
+    /// An Event Grid topic with the CloudEvents schema. Without a key the app signs in with
+    /// its identity, which needs the EventGrid Data Sender role on the topic.
+    EventGrid { endpoint: Url, key: Option<String> },
+}
+
+impl EventSink {
+    async fn publish(&self, env: &Environment, event: &CloudEvent) -> Result<(), Box<dyn Error>> {
+        let request = match self {
+            EventSink::Http(url) => reqwest::Client::new()
+                .post(url.clone())
+                .header(CONTENT_TYPE, "application/cloudevents+json; charset=utf-8")
+                .body(serde_json::to_vec(event)?),
+            EventSink::EventGrid { endpoint, key } => {
+                let request = reqwest::Client::new()
+                    .post(endpoint.clone())
+                    .header(CONTENT_TYPE, "application/cloudevents-batch+json; charset=utf-8")
+                    .body(serde_json::to_vec(&[event])?);
+                match key {
+                    Some(key) => request.header("aeg-sas-key", key),
+                    None => {
+                        let token = env.credential.get_token(&[EVENT_GRID_SCOPE]).await?;
+                        request.bearer_auth(token.token.secret())
+                    }
+                }
+            }
+        };
+
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Publishes an event to every configured sink. The change already happened, so failing
+/// to publish it is only logged.
+pub async fn publish(env: &Environment, event: CloudEvent) {
+    for sink in env.event_sinks.iter() {
+        match sink.publish(env, &event).await.map_err(|error| error.to_string()) {
+            Ok(()) => info!("Published {} for {}", event.event_type, event.subject),
+            Err(error) => info!("Could not publish {} for {}: {}", event.event_type, event.subject, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_environment, EnvironmentInner};
+    use axum::{extract::State, http::{HeaderMap, Uri}, Router};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+    use time::macros::datetime;
+
+    type Received = Arc<Mutex<Vec<(String, HeaderMap, Value)>>>;
+
+    fn event() -> CloudEvent {
+        let env = Arc::new(test_environment());
+        let mut event = CloudEvent::new(&env, EventType::RenewalFailed, "www-example-com")
+            .domain("www.example.com")
+            .error("The CA refused the order")
+            .job(Uuid::nil());
+        event.time = datetime!(2030-01-31 04:00:00.5 UTC);
+        event
+    }
+
+    #[test]
+    fn serializes_as_cloudevents() {
+        let event = event();
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "specversion": "1.0",
+                "id": event.id.to_string(),
+                "source": "https://test.vault.azure.net",
+                "type": "certificate.renewalFailed",
+                "subject": "www-example-com",
+                "time": "2030-01-31T04:00:00.5Z",
+                "datacontenttype": "application/json",
+                "data": {
+                    "name": "www-example-com",
+                    "id": null,
+                    "version": null,
+                    "thumbprint": null,
+                    "domain": "www.example.com",
+                    "expires_on": null,
+                    "error": "The CA refused the order",
+                    "job": "00000000-0000-0000-0000-000000000000",
+                },
+            })
+        );
+        // the time parses as RFC 3339 again
+        let time = json["time"].as_str().unwrap();
+        assert_eq!(OffsetDateTime::parse(time, &time::format_description::well_known::Rfc3339).unwrap(), event.time);
+    }
+
+    /// Starts an endpoint recording what is posted to it, returns its url.
+    async fn endpoint(received: Received) -> Url {
+        let record = |State(received): State<Received>, uri: Uri, headers: HeaderMap, body: String| async move {
+            received.lock().unwrap().push((uri.path().to_string(), headers, serde_json::from_str(&body).unwrap()));
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", listener.local_addr().unwrap().port())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, Router::new().fallback(record).with_state(received)).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn publishes_to_every_sink() {
+        let received = Received::default();
+        let url = endpoint(received.clone()).await;
+        let env = Arc::new(EnvironmentInner {
+            event_sinks: vec![
+                EventSink::Http(url.join("http").unwrap()),
+                EventSink::EventGrid { endpoint: url.join("keyed").unwrap(), key: Some("topic-key".to_string()) },
+                EventSink::EventGrid { endpoint: url.join("identity").unwrap(), key: None },
+            ],
+            ..test_environment()
+        });
+        let event = event();
+        let expected = serde_json::to_value(&event).unwrap();
+        publish(&env, event).await;
+
+        let received = received.lock().unwrap();
+        let [(http, http_headers, http_body), (keyed, keyed_headers, keyed_body), (identity, identity_headers, identity_body)] =
+            &received[..]
+        else {
+            panic!("{:?}", received);
+        };
+
+        // a single event in structured mode
+        assert_eq!(http, "/http");
+        assert_eq!(http_headers[CONTENT_TYPE], "application/cloudevents+json; charset=utf-8");
+        assert_eq!(*http_body, expected);
+
+        // Event Grid takes batches, with the topic key or the app's token
+        assert_eq!(keyed, "/keyed");
+        assert_eq!(keyed_headers[CONTENT_TYPE], "application/cloudevents-batch+json; charset=utf-8");
+        assert_eq!(keyed_headers["aeg-sas-key"], "topic-key");
+        assert_eq!(*keyed_body, json!([expected]));
+        assert_eq!(identity, "/identity");
+        assert_eq!(identity_headers["authorization"], "Bearer test-token");
+        assert!(!identity_headers.contains_key("aeg-sas-key"));
+        assert_eq!(*identity_body, json!([expected]));
+    }
+}
@@ -0,0 +1,17 @@
+use axum::{extract::Path, http::{header, StatusCode}, response::{IntoResponse, Response}};
+
+/// The static files are compiled into the binary, so the app doesn't depend on a CDN.
+static BOOTSTRAP_CSS: &[u8] = include_bytes!("../../static/bootstrap.min.css");
+
+pub async fn run(Path(file): Path<String>) -> Response {
+    let (content_type, body) = match file.as_str() {
+        "bootstrap.min.css" => ("text/css; charset=utf-8", BOOTSTRAP_CSS),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    (
+        [(header::CONTENT_TYPE, content_type), (header::CACHE_CONTROL, "public, max-age=86400")],
+        body,
+    )
+        .into_response()
+}
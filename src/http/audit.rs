@@ -0,0 +1,97 @@
+use super::page::render;
+use crate::{
+    audit::{AuditEvent, AuditFilter, Outcome},
+    utils::{app_error::AppError, principal::{Principal, Role}},
+    Environment,
+};
+use askama::Template;
+use axum::{extract::{rejection::QueryRejection, Query, State}, http::StatusCode, response::Response};
+use time::format_description;
+
+struct EventRow {
+    time: String,
+    actor: String,
+    action: String,
+    certificate: String,
+    failed: bool,
+    outcome: String,
+    error: String,
+    job: Option<String>,
+    version: String,
+    serial: String,
+    thumbprint: String,
+}
+
+#[derive(Template)]
+#[template(path = "audit.html")]
+struct AuditPage {
+    title: &'static str,
+    /// The filter as entered, to fill in the form again.
+    certificate: String,
+    actor: String,
+    action: String,
+    outcome: String,
+    actions: &'static [&'static str],
+    outcomes: &'static [&'static str],
+    events: Vec<EventRow>,
+    /// The link to the next page of older events, if there is one.
+    older: Option<String>,
+}
+
+pub async fn run(
+    State(env): State<Environment>,
+    principal: Principal,
+    filter: Result<Query<AuditFilter>, QueryRejection>,
+) -> Result<Response, AppError> {
+    principal.require(Role::Viewer)?;
+
+    let Query(filter) = filter.map_err(|rejection| AppError::Validation(rejection.body_text()))?;
+    let query = filter.query().map_err(AppError::Validation)?;
+    let page = AuditEvent::list(&env, &query).await?;
+
+    let certificate = filter.certificate.unwrap_or_default();
+    let actor = filter.actor.unwrap_or_default();
+    let action = filter.action.map(|action| format!("{:?}", action).to_lowercase()).unwrap_or_default();
+    let outcome = filter.outcome.map(|outcome| format!("{:?}", outcome).to_lowercase()).unwrap_or_default();
+    let older = page.continuation.map(|continuation| {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("certificate", &certificate)
+            .append_pair("actor", &actor)
+            .append_pair("action", &action)
+            .append_pair("outcome", &outcome)
+            .append_pair("continuation", &continuation)
+            .finish();
+        format!("/audit?{}", query)
+    });
+
+    let format = format_description::parse_borrowed::<2>("[year]-[month]-[day] [hour]:[minute]:[second] UTC")?;
+    let mut rows = Vec::new();
+    for event in page.documents {
+        rows.push(EventRow {
+            time: event.time.format(&format)?,
+            actor: event.actor.to_string(),
+            action: format!("{:?}", event.action),
+            certificate: event.certificate,
+            failed: event.outcome == Outcome::Failed,
+            outcome: format!("{:?}", event.outcome),
+            error: event.error.unwrap_or_default(),
+            job: event.job.map(|job| job.to_string()),
+            version: event.version.unwrap_or_default(),
+            serial: event.serial.unwrap_or_default(),
+            thumbprint: event.thumbprint.unwrap_or_default(),
+        });
+    }
+
+    let page = AuditPage {
+        title: "Audit log",
+        certificate,
+        actor,
+        action,
+        outcome,
+        actions: &["register", "renew", "revoke", "delete", "policy", "notifications", "deployments"],
+        outcomes: &["succeeded", "failed"],
+        events: rows,
+        older,
+    };
+    render(StatusCode::OK, &page)
+}
@@ -0,0 +1,166 @@
+use super::page::render;
+use crate::{certificate, notify::Event, utils::{app_error::AppError, layers::CsrfToken, principal::{Principal, Role}}, Environment};
+use askama::Template;
+use axum::{extract::{Path, State}, http::StatusCode, response::Response, Extension};
+use time::{format_description, OffsetDateTime};
+
+struct LastJob {
+    id: String,
+    updated: String,
+    result: String,
+}
+
+struct VersionRow {
+    version: String,
+    current: bool,
+    enabled: bool,
+    created: String,
+    not_before: String,
+    expires_on: String,
+}
+
+struct DeploymentRow {
+    target: String,
+    status: String,
+    version: String,
+    updated: String,
+    error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "certificate.html")]
+struct CertificatePage {
+    title: String,
+    heading: String,
+    /// Label and value of every row in the certificate table.
+    certificate: Vec<(&'static str, String)>,
+    renewal: Vec<(&'static str, String)>,
+    last_job: Option<LastJob>,
+    versions: Vec<VersionRow>,
+    deployments: Vec<DeploymentRow>,
+    name: String,
+    /// Every event and whether the certificate notifies about it.
+    events: Vec<(String, bool)>,
+    /// The subscribed channels, empty for every channel.
+    channels: String,
+    csrf_token: String,
+    can_edit: bool,
+}
+
+pub async fn run(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(name): Path<String>,
+    Extension(CsrfToken(csrf_token)): Extension<CsrfToken>,
+) -> Result<Response, AppError> {
+    principal.require(Role::Viewer)?;
+
+    let details = certificate::details(&env, &name).await?;
+    let info = &details.info;
+
+    let format = format_description::parse_borrowed::<2>("[day] [month repr:short] [year] [hour]:[minute] UTC")?;
+    let date = |date: Option<OffsetDateTime>| match date {
+        Some(date) => date.format(&format).unwrap_or_default(),
+        None => "-".to_string(),
+    };
+
+    let mut certificate = match (&details.x509, &details.pending) {
+        (Some(x509), _) => vec![
+            ("Subject", x509.subject.clone()),
+            ("Alternative names", x509.sans.join(", ")),
+            ("Issuer", x509.issuer.clone()),
+            ("Valid from", date(info.not_before)),
+            ("Valid until", date(info.expires_on)),
+            ("Serial", x509.serial.clone()),
+            ("SHA-1 fingerprint", x509.sha1_fingerprint.clone()),
+            ("SHA-256 fingerprint", x509.sha256_fingerprint.clone()),
+            ("Key", format!("{} {}", x509.key_type, x509.key_size)),
+            ("Key Vault version", x509.version.clone()),
+        ],
+        (None, pending) => vec![(
+            "Status",
+            format!("Pending, the certificate is being issued ({})", pending.as_deref().unwrap_or("unknown")),
+        )],
+    };
+    match &details.issued_by {
+        // a pending certificate wasn't issued by anyone yet
+        _ if details.x509.is_none() => {}
+        Some(job) => {
+            certificate.push(("CA", job.ca.clone().unwrap_or_else(|| "-".to_string())));
+            certificate.push(("Profile", job.profile.clone().unwrap_or_else(|| "default".to_string())));
+            certificate.push(("Chain", job.chain.join(" → ")));
+        }
+        None => certificate.push(("CA", "not issued by this app, or issued before jobs were recorded".to_string())),
+    }
+
+    let mut status = format!("{:?}", info.status);
+    if info.attempts > 0 {
+        status += &format!(", {} failed attempts", info.attempts);
+    }
+    let mut renewal = vec![
+        ("Policy", info.renewal_policy.describe()),
+        ("Next renewal", date(info.next_renewal)),
+        ("Status", status),
+    ];
+    if let Some(error) = &info.last_error {
+        renewal.push(("Last error", error.clone()));
+        renewal.push(("Next retry", date(info.next_retry)));
+    }
+    renewal.push(("Notifications", info.notifications.describe()));
+
+    let last_job = details.last_job.as_ref().map(|job| LastJob {
+        id: job.id.to_string(),
+        updated: date(Some(job.updated)),
+        result: match &job.error {
+            Some(error) => format!("{:?}: {}", job.state, error),
+            None => format!("{:?}", job.state),
+        },
+    });
+
+    let versions = details
+        .versions
+        .iter()
+        .map(|version| VersionRow {
+            version: version.version.clone(),
+            current: version.current,
+            enabled: version.enabled,
+            created: date(Some(version.created_on)),
+            not_before: date(version.not_before),
+            expires_on: date(version.expires_on),
+        })
+        .collect();
+
+    let deployments = details
+        .deployments
+        .iter()
+        .map(|deployment| DeploymentRow {
+            target: deployment.target.as_target().describe(),
+            status: match deployment.attempts {
+                0 => format!("{:?}", deployment.status),
+                attempts => format!("{:?}, {} failed attempts, next retry {}", deployment.status, attempts, date(deployment.next_retry)),
+            },
+            version: deployment.version.clone().unwrap_or_else(|| "-".to_string()),
+            updated: date(deployment.updated),
+            error: deployment.last_error.clone(),
+        })
+        .collect();
+
+    let page = CertificatePage {
+        title: info.name.clone(),
+        heading: info.domain.clone().unwrap_or_else(|| info.name.clone()),
+        certificate,
+        renewal,
+        last_job,
+        versions,
+        deployments,
+        name: info.name.clone(),
+        events: Event::ALL
+            .iter()
+            .map(|event| (event.to_string(), info.notifications.events.contains(event)))
+            .collect(),
+        channels: info.notifications.channels.clone().unwrap_or_default().join(", "),
+        csrf_token,
+        can_edit: principal.can(Role::Operator),
+    };
+    render(StatusCode::OK, &page)
+}
@@ -1,20 +1,28 @@
-use axum::{http::StatusCode, extract::{Host, State}, response::{IntoResponse, Redirect, Response}, Form};
-use crate::{utils::app_error::AppError, Environment};
+use axum::{extract::State, http::HeaderMap, response::{IntoResponse, Redirect, Response}, Form};
+use crate::{audit::{Action, Actor, AuditEvent}, certificate, utils::{app_error::AppError, principal::{Principal, Role}, public_url::public_url}, Environment};
 use std::collections::HashMap;
 
 pub async fn run(
     State(env): State<Environment>,
-    Host(hostname): Host,
+    principal: Principal,
+    headers: HeaderMap,
     Form(body): Form<HashMap<String, String>>,
 ) -> Result<Response, AppError> {
+    principal.require(Role::Admin)?;
+
     let cert_name = match body.get("cert_name") {
         Some(domain) => domain,
-        None => { return Ok((StatusCode::BAD_REQUEST, "Please add a domain to the query string of the request").into_response()); }
+        None => { return Err(AppError::Validation("Please add a domain to the query string of the request".to_string())); }
     };
 
-    env.certificate_client.delete(cert_name).await?;
+    let event = AuditEvent::new(Actor::from(&principal), Action::Delete, cert_name);
+    let result = certificate::delete(&env, cert_name).await.map_err(AppError::from);
+    match &result {
+        Ok(cert) => event.succeeded(Some(cert)).record(&env).await,
+        Err(error) => event.failed(error.message()).record(&env).await,
+    }
+    result?;
 
     // Redirect to status page
-    let redirect_url = format!("http://{}", hostname);
-    Ok(Redirect::to(&redirect_url).into_response())
+    Ok(Redirect::to(&public_url(&env, &headers, "/")).into_response())
 }
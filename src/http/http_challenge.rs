@@ -6,8 +6,8 @@ pub async fn run(Path(token): Path<String>, State(env): State<Environment>) -> R
 
     info!("token found in path {}", token);
 
-    let contents = match env.challenge_store.read().unwrap().get(&token) {
-        Some(contents) => contents.to_string(),
+    let contents = match env.challenge_store.get(&token).await? {
+        Some(contents) => contents,
         None => {
             info!("file matching token not found");
             return Ok(StatusCode::NOT_FOUND.into_response());
@@ -0,0 +1,45 @@
+use super::page::render;
+use crate::{acme::job::Job, utils::{app_error::AppError, layers::wants_html, principal::{Principal, Role}}, Environment};
+use askama::Template;
+use axum::{extract::{Path, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}, Json};
+use uuid::Uuid;
+
+#[derive(Template)]
+#[template(path = "job.html")]
+struct JobPage {
+    title: String,
+    domain: String,
+    certificate: String,
+    state: String,
+    error: Option<String>,
+    /// Pages of running jobs refresh themselves.
+    finished: bool,
+}
+
+pub async fn run(
+    State(env): State<Environment>,
+    principal: Principal,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    principal.require(Role::Viewer)?;
+
+    let job = match Job::get(&env, id).await? {
+        Some(job) => job,
+        None => { return Err(AppError::NotFound(format!("Job {} not found", id))); }
+    };
+
+    if !wants_html(&headers) {
+        return Ok((StatusCode::OK, Json(job)).into_response());
+    }
+
+    let page = JobPage {
+        title: format!("Issuing {}", job.domain),
+        state: format!("{:?}", job.state),
+        finished: job.state.is_finished(),
+        domain: job.domain,
+        certificate: job.certificate,
+        error: job.error,
+    };
+    render(StatusCode::OK, &page)
+}
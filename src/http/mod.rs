@@ -1,4 +1,11 @@
+pub mod assets;
+pub mod audit;
+pub mod certificate;
 pub mod delete;
 pub mod http_challenge;
+pub mod job;
 pub mod new;
+pub mod notifications;
+pub mod page;
+pub mod policy;
 pub mod status;
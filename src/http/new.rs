@@ -1,23 +1,45 @@
-use axum::{extract::{Host, State}, http::StatusCode, response::{IntoResponse, Redirect, Response}, Form};
-use crate::{acme::cert_new, utils::app_error::AppError, Environment};
+use super::page::render;
+use askama::Template;
+use axum::{extract::State, http::{header, HeaderMap, StatusCode}, response::Response, Form};
+use crate::{audit::Actor, certificate, renewal::policy::RenewalPolicy, utils::{app_error::AppError, principal::{Principal, Role}, public_url::public_url}, Environment};
 use std::collections::HashMap;
 
+#[derive(Template)]
+#[template(path = "issuing.html")]
+struct Issuing {
+    title: &'static str,
+    job_url: String,
+}
+
 pub async fn run(
     State(env): State<Environment>,
-    Host(hostname): Host,
+    principal: Principal,
+    headers: HeaderMap,
     Form(body): Form<HashMap<String, String>>,
 ) -> Result<Response, AppError> {
+    principal.require(Role::Operator)?;
+
     let domain = match body.get("domain") {
         Some(domain) => domain,
-        None => { return Ok((StatusCode::BAD_REQUEST, "Please add a domain to the query string of the request").into_response()); }
+        None => { return Err(AppError::Validation("Please add a domain to the query string of the request".to_string())); }
     };
 
-    let cert_name = domain.replace('.', "-");
+    // the renewal policy is optional, certificates without one use the default policy
+    let policy = match body.get("renewal_policy").filter(|policy| !policy.trim().is_empty()) {
+        Some(policy) => match policy.parse::<RenewalPolicy>() {
+            Ok(policy) => Some(policy),
+            Err(error) => { return Err(AppError::Validation(error)); }
+        },
+        None => None,
+    };
 
-    // Create new certificate
-    cert_new(domain, cert_name.as_str(), &env).await?;
+    // Create new certificate in the background, the outcome is recorded on the job
+    let job = certificate::issue(&env, domain, policy, Actor::from(&principal)).await?;
+    let job_url = public_url(&env, &headers, &format!("/jobs/{}", job.id));
 
-    // Redirect to status page
-    let redirect_url = format!("http://{}", hostname);
-    Ok(Redirect::to(&redirect_url).into_response())
+    // Send the browser to the job page, which refreshes until the certificate is issued
+    let page = Issuing { title: "Issuing certificate", job_url: job_url.clone() };
+    let mut response = render(StatusCode::ACCEPTED, &page)?;
+    response.headers_mut().insert(header::LOCATION, job_url.parse()?);
+    Ok(response)
 }
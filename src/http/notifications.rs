@@ -0,0 +1,39 @@
+use axum::{extract::State, http::HeaderMap, response::{IntoResponse, Redirect, Response}, Form};
+use crate::{audit::{Action, Actor, AuditEvent}, certificate, notify::{subscription::Subscription, Event}, utils::{app_error::AppError, principal::{Principal, Role}, public_url::public_url}, Environment};
+use std::collections::HashMap;
+
+pub async fn run(
+    State(env): State<Environment>,
+    principal: Principal,
+    headers: HeaderMap,
+    Form(body): Form<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+    principal.require(Role::Operator)?;
+
+    let cert_name = match body.get("cert_name") {
+        Some(cert_name) => cert_name,
+        None => { return Err(AppError::Validation("Please add a certificate name to the request".to_string())); }
+    };
+
+    // every event has its own checkbox, empty channels notify every configured channel
+    let events = Event::ALL
+        .into_iter()
+        .filter(|event| body.contains_key(&format!("event_{}", event)))
+        .collect();
+    let channels: Vec<String> = body
+        .get("channels")
+        .map(|channels| channels.split(',').map(|channel| channel.trim().to_string()).filter(|channel| !channel.is_empty()).collect())
+        .unwrap_or_default();
+    let subscription = Subscription { events, channels: Some(channels).filter(|channels| !channels.is_empty()) };
+    subscription.validate().map_err(AppError::Validation)?;
+
+    let event = AuditEvent::new(Actor::from(&principal), Action::Notifications, cert_name);
+    let result = certificate::set_subscription(&env, cert_name, &subscription).await.map_err(AppError::from);
+    match &result {
+        Ok(()) => event.succeeded(None).record(&env).await,
+        Err(error) => event.failed(error.message()).record(&env).await,
+    }
+    result?;
+
+    Ok(Redirect::to(&public_url(&env, &headers, &format!("/certificates/{}", cert_name))).into_response())
+}
@@ -0,0 +1,9 @@
+use crate::utils::app_error::AppError;
+use askama::Template;
+use axum::{http::StatusCode, response::{Html, IntoResponse, Response}};
+
+/// Renders a page, the templates live in `templates/` and extend `layout.html`.
+/// Askama escapes every value, so nothing from Key Vault or the user ends up as markup.
+pub fn render<T: Template>(status: StatusCode, page: &T) -> Result<Response, AppError> {
+    Ok((status, Html(page.render()?)).into_response())
+}
@@ -0,0 +1,42 @@
+use axum::{extract::State, http::HeaderMap, response::{IntoResponse, Redirect, Response}, Form};
+use crate::{audit::{Action, Actor, AuditEvent}, keyvault::{get_tags, set_tags}, renewal::policy::{RenewalPolicy, POLICY_TAG}, utils::{app_error::AppError, principal::{Principal, Role}, public_url::public_url}, Environment};
+use std::collections::HashMap;
+
+pub async fn run(
+    State(env): State<Environment>,
+    principal: Principal,
+    headers: HeaderMap,
+    Form(body): Form<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+    principal.require(Role::Operator)?;
+
+    let cert_name = match body.get("cert_name") {
+        Some(cert_name) => cert_name,
+        None => { return Err(AppError::Validation("Please add a certificate name to the request".to_string())); }
+    };
+
+    let mut tags = get_tags(&env, cert_name).await?;
+
+    // an empty policy resets the certificate to the default policy
+    match body.get("renewal_policy").map(|policy| policy.trim()).filter(|policy| !policy.is_empty()) {
+        Some(policy) => {
+            let policy: RenewalPolicy = match policy.parse() {
+                Ok(policy) => policy,
+                Err(error) => { return Err(AppError::Validation(error)); }
+            };
+            tags.insert(POLICY_TAG.to_string(), policy.to_string());
+        }
+        None => { tags.remove(POLICY_TAG); }
+    };
+
+    let event = AuditEvent::new(Actor::from(&principal), Action::Policy, cert_name);
+    let result = set_tags(&env, cert_name, &tags).await.map_err(AppError::from);
+    match &result {
+        Ok(()) => event.succeeded(None).record(&env).await,
+        Err(error) => event.failed(error.message()).record(&env).await,
+    }
+    result?;
+
+    // Redirect to status page
+    Ok(Redirect::to(&public_url(&env, &headers, "/")).into_response())
+}
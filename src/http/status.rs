@@ -1,64 +1,119 @@
-use crate::{utils::app_error::AppError, keyvault::cert_name, Environment};
-use axum::{extract::State, http::StatusCode, response::{Html, IntoResponse, Response}};
-use futures::StreamExt;
-use time::format_description;
-use tracing::info;
+use super::page::render;
+use crate::{acme::{job::Job, rate_limit::{Ledger, CERTIFICATES_PER_DOMAIN}}, certificate, utils::{app_error::AppError, layers::CsrfToken, principal::{Principal, Role}}, renewal::state::RenewalStatus, Environment};
+use askama::Template;
+use axum::{extract::State, http::StatusCode, response::Response, Extension};
+use time::{format_description, OffsetDateTime};
+
+struct CertificateRow {
+    name: String,
+    id: String,
+    expiry: String,
+    policy: String,
+    policy_description: String,
+    next_renewal: String,
+}
+
+struct JobRow {
+    id: String,
+    domain: String,
+    state: String,
+    error: String,
+}
+
+/// What is left of the Let's Encrypt rate limits of a registered domain.
+struct BudgetRow {
+    registered_domain: String,
+    certificates_left: usize,
+    failed_validations: usize,
+    next_slot: String,
+}
+
+#[derive(Template)]
+#[template(path = "dashboard.html")]
+struct Dashboard {
+    title: &'static str,
+    certificates: Vec<CertificateRow>,
+    jobs: Vec<JobRow>,
+    budgets: Vec<BudgetRow>,
+    certificates_per_domain: usize,
+    csrf_token: String,
+    /// Who is signed in and with which role.
+    user: String,
+    can_issue: bool,
+    can_delete: bool,
+}
 
 pub async fn run(
     State(env): State<Environment>,
+    principal: Principal,
+    Extension(CsrfToken(csrf_token)): Extension<CsrfToken>,
 ) -> Result<Response, AppError> {
-    let mut table = HTML_START.to_owned()
-        + HEAD_START
-        + BOOTSTRAP_CSS
-        + TITLE
-        + HEAD_END
-        + BODY_START
-        + FORM
-        + TABLE_START;
-
-    let certs = env
-        .certificate_client
-        .list_certificates()
-        .into_stream()
-        .next()
-        .await
-        .ok_or("Certificates not found")??
-        .value;
+    principal.require(Role::Viewer)?;
 
-    info!("{} certificates found", certs.len());
+    let certs = certificate::list(&env).await?;
+    let format = format_description::parse_borrowed::<2>("[day] [month repr:short] [year]")?;
 
+    let mut certificates = Vec::new();
     for cert in certs.iter() {
-        let expiry = cert.attributes.expires_on.ok_or("expiry date not found")?;
-        let format = format_description::parse("[day] [month repr:short] [year]")?;
-        table = table
-            + "<tr><td>"
-            + cert.id.as_str()
-            + "</td><td>"
-            + expiry.format(&format)?.as_str()
-            + "</td><td>"
-            + FORM2
-            + cert_name(cert).unwrap().as_str()
-            + FORM3
-            + "</tr>";
-        cert.attributes.expires_on.unwrap().to_string();
+        let expiry = cert.expires_on.ok_or("expiry date not found")?;
+        let mut next_renewal = match cert.next_renewal {
+            Some(renewal_date) => renewal_date.format(&format)?,
+            None => String::new(),
+        };
+        match cert.status {
+            RenewalStatus::Ok => {}
+            RenewalStatus::Retrying => next_renewal += &format!(" (retrying, {} failed attempts)", cert.attempts),
+            RenewalStatus::Failing => next_renewal += &format!(" (failing, {} failed attempts)", cert.attempts),
+        }
+        certificates.push(CertificateRow {
+            name: cert.name.clone(),
+            id: cert.id.clone(),
+            expiry: expiry.format(&format)?,
+            policy: cert.renewal_policy.to_string(),
+            policy_description: cert.renewal_policy.describe(),
+            next_renewal,
+        });
     }
 
-    table = table + TABLE_END + BODY_END + HTML_END;
-
-    Ok((StatusCode::OK, Html(table)).into_response())
-}
+    let jobs = Job::recent(&env)
+        .await?
+        .into_iter()
+        .map(|job| JobRow {
+            id: job.id.to_string(),
+            domain: job.domain,
+            state: format!("{:?}", job.state),
+            error: job.error.unwrap_or_default(),
+        })
+        .collect();
 
+    let now = OffsetDateTime::now_utc();
+    let slot_format = format_description::parse_borrowed::<2>("[day] [month repr:short] [year] [hour]:[minute] UTC")?;
+    let mut budgets = Vec::new();
+    for ledger in Ledger::list(&env).await? {
+        budgets.push(BudgetRow {
+            registered_domain: ledger.registered_domain.clone(),
+            certificates_left: ledger.certificates_left(now),
+            failed_validations: ledger.recent_failures(now),
+            next_slot: match ledger.next_slot() {
+                Some(slot) => slot.format(&slot_format)?,
+                None => String::new(),
+            },
+        });
+    }
 
-static HTML_START: &str = "<html>";
-static HTML_END: &str = "</html>";
-static BOOTSTRAP_CSS: &str = "<link href='https://cdn.larsen.farm/bootstrap/5.3/css/bootstrap.min.css' rel='stylesheet'>";
-static HEAD_START: &str = "<head><meta charset='utf-8'><meta name='viewport' content='width=device-width, initial-scale=1'>";
-static TITLE: &str = "<title>Let's Encrypt Dashboard</title>";
-static HEAD_END: &str = "</head>";
-static BODY_START: &str = "<body class='container'>";
-static BODY_END: &str = "</body>";
-static TABLE_START: &str = "<table class='table'><tr><th>Certificate Id</th><th>Expiry</th><th>Action</th></tr>";
-static TABLE_END: &str = "</table>";
-static FORM: &str = "<form method='post' action='/register'><label for='domain' class='form-label'>Add New Domain:</label><br><input class='form-control' type='text' id='domain' name='domain'><button type='submit' class='btn btn-primary'>Submit</button></form>";
-static FORM2: &str = "<form method='post' action='/delete'><input type='hidden' name='cert_name' value='";
-static FORM3: &str = "'><button type='submit' class='btn btn-primary'>Delete</button></form>";
\ No newline at end of file
+    let page = Dashboard {
+        title: "Let's Encrypt Dashboard",
+        certificates,
+        jobs,
+        budgets,
+        certificates_per_domain: CERTIFICATES_PER_DOMAIN,
+        csrf_token,
+        user: match principal.role {
+            Some(role) => format!("{} ({})", principal.display_name(), role),
+            None => principal.display_name().to_string(),
+        },
+        can_issue: principal.can(Role::Operator),
+        can_delete: principal.can(Role::Admin),
+    };
+    render(StatusCode::OK, &page)
+}
@@ -1,12 +1,21 @@
-use crate::{acme::util::b64, Environment};
+use crate::{acme::util::{b64, URL_SAFE_ENGINE}, Environment};
+use base64::Engine;
 use azure_security_keyvault::prelude::{
     KeyVaultCertificateBaseIdentifier, KeyVaultGetCertificateResponse,
     KeyVaultGetCertificatesResponse, SignatureAlgorithm,
 };
 use futures::StreamExt;
-use std::error::Error;
+use serde::Deserialize;
+use serde_json::json;
+use std::{collections::HashMap, error::Error};
 use url::Url;
 
+/// The Key Vault REST api version used for the calls the SDK doesn't cover.
+const API_VERSION: &str = "7.4";
+
+/// The tags of a Key Vault certificate.
+pub type Tags = HashMap<String, String>;
+
 pub fn cert_name(cert: &KeyVaultCertificateBaseIdentifier) -> Option<String> {
     let url = match Url::parse(cert.id.as_str()) {
         Ok(url) => url,
@@ -26,6 +35,19 @@ pub fn domain(cert: &KeyVaultGetCertificateResponse) -> Option<&str> {
     }
 }
 
+/// The version of a certificate, the last segment of its id.
+pub fn version(cert: &KeyVaultGetCertificateResponse) -> Option<String> {
+    cert.id.rsplit('/').next().map(|version| version.to_string())
+}
+
+/// The SHA-1 thumbprint of a certificate in upper case hex, as Azure shows it.
+pub fn thumbprint(cert: &KeyVaultGetCertificateResponse) -> Option<String> {
+    URL_SAFE_ENGINE
+        .decode(&cert.x5t)
+        .ok()
+        .map(|thumbprint| thumbprint.iter().map(|byte| format!("{:02X}", byte)).collect())
+}
+
 pub async fn get_certs(
     env: &Environment,
 ) -> Result<KeyVaultGetCertificatesResponse, Box<dyn Error>> {
@@ -50,3 +72,201 @@ where
     let signature = b64(result.signature);
     Ok(signature)
 }
+
+#[derive(Deserialize)]
+struct TaggedCertificate {
+    id: String,
+    #[serde(default)]
+    tags: Option<Tags>,
+}
+
+#[derive(Deserialize)]
+struct TaggedCertificates {
+    value: Vec<TaggedCertificate>,
+    #[serde(rename = "nextLink")]
+    next_link: Option<String>,
+}
+
+/// Builds an authenticated request against the Key Vault REST api. The SDK doesn't
+/// expose certificate or secret tags, so they are read and written through here.
+async fn vault_request(
+    env: &Environment,
+    method: reqwest::Method,
+    url: Url,
+) -> Result<reqwest::RequestBuilder, Box<dyn Error>> {
+    let host = env.keyvault_url.host_str().ok_or("Key Vault url has no host")?;
+    let endpoint = host.split_once('.').map(|(_, endpoint)| endpoint).unwrap_or(host);
+    let scope = format!("{}://{}/.default", env.keyvault_url.scheme(), endpoint);
+    let token = env.credential.get_token(&[scope.as_str()]).await?;
+
+    Ok(reqwest::Client::new()
+        .request(method, url)
+        .bearer_auth(token.token.secret()))
+}
+
+fn certificate_url(env: &Environment, name: &str) -> Result<Url, Box<dyn Error>> {
+    let mut url = env.keyvault_url.join(&format!("certificates/{}", name))?;
+    url.query_pairs_mut().append_pair("api-version", API_VERSION);
+    Ok(url)
+}
+
+/// Returns the tags of every certificate in the vault keyed by certificate name.
+pub async fn list_tags(env: &Environment) -> Result<HashMap<String, Tags>, Box<dyn Error>> {
+    let mut url = env.keyvault_url.join("certificates")?;
+    url.query_pairs_mut().append_pair("api-version", API_VERSION);
+
+    let mut result = HashMap::new();
+    let mut next = Some(url);
+    while let Some(url) = next.take() {
+        let request = vault_request(env, reqwest::Method::GET, url).await?;
+        let page: TaggedCertificates = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        for cert in page.value {
+            let name = Url::parse(&cert.id)?
+                .path_segments()
+                .and_then(|mut segments| segments.nth(1).map(|name| name.to_string()))
+                .ok_or("certificate name not found")?;
+            result.insert(name, cert.tags.unwrap_or_default());
+        }
+
+        next = match page.next_link {
+            Some(link) => Some(Url::parse(&link)?),
+            None => None,
+        };
+    }
+
+    Ok(result)
+}
+
+/// Returns the tags of the current version of a certificate, or no tags if the certificate doesn't exist.
+pub async fn get_tags(env: &Environment, name: &str) -> Result<Tags, Box<dyn Error>> {
+    let url = certificate_url(env, name)?;
+    let request = vault_request(env, reqwest::Method::GET, url).await?;
+    let response = request.send().await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(Tags::new());
+    }
+
+    let cert: TaggedCertificate = response.error_for_status()?.json().await?;
+    Ok(cert.tags.unwrap_or_default())
+}
+
+/// Replaces the tags of the current version of a certificate.
+pub async fn set_tags(env: &Environment, name: &str, tags: &Tags) -> Result<(), Box<dyn Error>> {
+    let url = certificate_url(env, name)?;
+    let request = vault_request(env, reqwest::Method::PATCH, url).await?;
+    request
+        .json(&json!({ "tags": tags }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct SecretAttributes {
+    enabled: bool,
+    /// Expiry as a unix timestamp.
+    exp: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TaggedSecret {
+    id: String,
+    attributes: SecretAttributes,
+    #[serde(default)]
+    tags: Option<Tags>,
+}
+
+#[derive(Deserialize)]
+struct TaggedSecrets {
+    value: Vec<TaggedSecret>,
+    #[serde(rename = "nextLink")]
+    next_link: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SecretValue {
+    value: String,
+}
+
+/// Returns the value of the secret with the Key Vault `id`, e.g. the versioned secret
+/// backing a certificate, which holds its private key if the key is exportable.
+pub async fn get_secret(env: &Environment, id: &str) -> Result<String, Box<dyn Error>> {
+    let mut url = Url::parse(id)?;
+    url.query_pairs_mut().append_pair("api-version", API_VERSION);
+    let request = vault_request(env, reqwest::Method::GET, url).await?;
+    let value: SecretValue = request
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(value.value)
+}
+
+/// An api key stored as a Key Vault secret.
+pub struct ApiKey {
+    /// The secret name.
+    pub name: String,
+    pub value: String,
+    pub tags: Tags,
+}
+
+/// Returns the enabled, unexpired secrets whose name starts with `prefix`, with their values.
+pub async fn list_api_keys(env: &Environment, prefix: &str) -> Result<Vec<ApiKey>, Box<dyn Error>> {
+    let mut url = env.keyvault_url.join("secrets")?;
+    url.query_pairs_mut().append_pair("api-version", API_VERSION);
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    let mut result = Vec::new();
+    let mut next = Some(url);
+    while let Some(url) = next.take() {
+        let request = vault_request(env, reqwest::Method::GET, url).await?;
+        let page: TaggedSecrets = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        for secret in page.value {
+            let name = Url::parse(&secret.id)?
+                .path_segments()
+                .and_then(|mut segments| segments.nth(1).map(|name| name.to_string()))
+                .ok_or("secret name not found")?;
+            let expired = secret.attributes.exp.is_some_and(|exp| exp <= now);
+            if !name.starts_with(prefix) || !secret.attributes.enabled || expired {
+                continue;
+            }
+
+            // the list only has the attributes, the value needs a request of its own
+            let mut url = env.keyvault_url.join(&format!("secrets/{}", name))?;
+            url.query_pairs_mut().append_pair("api-version", API_VERSION);
+            let request = vault_request(env, reqwest::Method::GET, url).await?;
+            let value: SecretValue = request
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            result.push(ApiKey { name, value: value.value, tags: secret.tags.unwrap_or_default() });
+        }
+
+        next = match page.next_link {
+            Some(link) => Some(Url::parse(&link)?),
+            None => None,
+        };
+    }
+
+    Ok(result)
+}
@@ -1,13 +1,28 @@
-use axum::{middleware, http::StatusCode, routing::{get, post}, Router};
+use axum::{middleware, http::StatusCode, routing::{get, post, put}, Router};
+use azure_core::auth::TokenCredential;
 use azure_security_keyvault::prelude::*;
-use utils::layers::auth;
-use std::{collections::HashMap, env::Args, net::SocketAddr, sync::{Arc, RwLock}};
-use azure_data_cosmos::prelude::{AuthorizationToken, CosmosClient, DatabaseClient};
+use certificate::hostname::validate_domain;
+use events::EventSink;
+use notify::{chat::{ChatFormat, ChatNotifier}, email::{EmailNotifier, SmtpSettings, SmtpTls}, webhook::WebhookNotifier, Notifications, Notifier, Outbox};
+use renewal::{policy::RenewalPolicy, state::RetryPolicy};
+use store::{challenge::{ChallengeStore, CosmosChallengeStore, MemoryChallengeStore}, Store};
+use url::Url;
+use utils::{api_auth::{ApiKeys, TokenValidator}, layers::{auth, csrf, error_pages}, principal::RoleMapping};
+use std::{env::Args, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use azure_data_cosmos::prelude::{AuthorizationToken, CloudLocation, CosmosClient, CosmosClientBuilder, DatabaseClient};
 use crate::utils::tracing::cosmos_tracing;
 
 mod acme;
+mod api;
+mod audit;
+mod certificate;
+mod deploy;
+mod events;
 mod http;
 mod keyvault;
+mod notify;
+mod renewal;
+mod store;
 mod timer;
 mod utils;
 
@@ -17,8 +32,29 @@ type Environment = Arc<EnvironmentInner>;
 pub struct EnvironmentInner {
     certificate_client: CertificateClient,
     key_client: KeyClient,
+    keyvault_url: Url,
+    credential: Arc<dyn TokenCredential>,
     account_email: String,
-    challenge_store: RwLock<HashMap<String, String>>
+    challenge_store: Arc<dyn ChallengeStore>,
+    challenge_ttl: Duration,
+    http01_self_check: bool,
+    renewal_policy: RenewalPolicy,
+    renewal_concurrency: usize,
+    renewal_timeout: Duration,
+    retry_policy: RetryPolicy,
+    store: Store,
+    lease_duration: Duration,
+    csrf_key: Vec<u8>,
+    roles: RoleMapping,
+    public_url: Option<String>,
+    token_validator: Option<TokenValidator>,
+    api_keys: ApiKeys,
+    allowed_zones: Vec<String>,
+    notifications: Notifications,
+    event_sinks: Vec<EventSink>,
+    arm_endpoint: Url,
+    export_directory: Option<PathBuf>,
+    export_reload_commands: Vec<String>,
 }
 
 #[tokio::main]
@@ -33,32 +69,242 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let keyvault_url = args.nth(1).expect("Missing KEYVAULT_URL environment variable.");
     let email = args.next().expect("Missing ACCOUNT_EMAIL environment variable.");
 
-    let keyvault_client = KeyvaultClient::new(&keyvault_url, azure_identity::create_credential()?)?;
+    let credential = azure_identity::create_credential()?;
+    let keyvault_client = KeyvaultClient::new(&keyvault_url, credential.clone())?;
 
-    let challenge_store = HashMap::<String, String>::new();
+    // default renewal policy for certificates without a policy of their own
+    let renewal_policy: RenewalPolicy = match std::env::var("RENEWAL_POLICY") {
+        Ok(val) => val.parse().expect("RENEWAL_POLICY is not a valid renewal policy!"),
+        Err(_) => RenewalPolicy::default(),
+    };
+
+    // how many certificates get renewed at once and how long a single renewal may take
+    let renewal_concurrency: usize = match std::env::var("RENEWAL_CONCURRENCY") {
+        Ok(val) => val.parse().expect("RENEWAL_CONCURRENCY is not a number!"),
+        Err(_) => 4,
+    };
+    let renewal_timeout: u64 = match std::env::var("RENEWAL_TIMEOUT_SECONDS") {
+        Ok(val) => val.parse().expect("RENEWAL_TIMEOUT_SECONDS is not a number!"),
+        Err(_) => 120,
+    };
+
+    // failed renewals are retried with exponential backoff until they count as failing
+    let max_attempts: u32 = match std::env::var("RENEWAL_MAX_ATTEMPTS") {
+        Ok(val) => val.parse().expect("RENEWAL_MAX_ATTEMPTS is not a number!"),
+        Err(_) => 5,
+    };
+    let retry_minutes: u64 = match std::env::var("RENEWAL_RETRY_MINUTES") {
+        Ok(val) => val.parse().expect("RENEWAL_RETRY_MINUTES is not a number!"),
+        Err(_) => 60,
+    };
+
+    // how long a lease on a certificate or check run is held before others may take it over
+    let lease_seconds: u64 = match std::env::var("LEASE_SECONDS") {
+        Ok(val) => val.parse().expect("LEASE_SECONDS is not a number!"),
+        Err(_) => 600,
+    };
+
+    // splits settings holding several values separated by commas
+    let list = |val: &str| -> Vec<String> {
+        val.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect()
+    };
+
+    // the app roles or group object ids that grant each role
+    let role_names = |name: &str, default: &str| list(&std::env::var(name).unwrap_or_else(|_| default.to_string()));
+    let roles = RoleMapping {
+        viewer: role_names("VIEWER_ROLES", "Viewer"),
+        operator: role_names("OPERATOR_ROLES", "Operator"),
+        admin: role_names("ADMIN_ROLES", "Admin"),
+    };
+
+    // entra id access tokens are accepted by the api once their audience is configured
+    let token_validator = std::env::var("API_AUDIENCE").ok().map(|audience| {
+        let jwks_url = std::env::var("API_JWKS_URL").expect("API_JWKS_URL is required with API_AUDIENCE!");
+        let issuer = std::env::var("API_ISSUER").expect("API_ISSUER is required with API_AUDIENCE!");
+        TokenValidator::new(Url::parse(&jwks_url).expect("API_JWKS_URL is not a valid url!"), list(&audience), list(&issuer))
+    });
+    // api keys are the secrets whose name starts with this prefix
+    let api_key_prefix = std::env::var("API_KEY_PREFIX").unwrap_or_else(|_| "api-key-".to_string());
+
+    // the zones certificates may be requested for, any domain if none are set
+    let allowed_zones = list(&std::env::var("ALLOWED_ZONES").unwrap_or_default())
+        .iter()
+        .map(|zone| validate_domain(zone, false).expect("ALLOWED_ZONES has an invalid zone!"))
+        .collect();
+
+    // where users reach the app, e.g. behind a custom domain (taken from the request if not set)
+    let public_url = std::env::var("PUBLIC_URL").ok().map(|val| {
+        let url = Url::parse(&val).expect("PUBLIC_URL is not a valid url!");
+        url.as_str().trim_end_matches('/').to_string()
+    });
+
+    // notification channels, each one is enabled by setting its url or host
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    if let Ok(val) = std::env::var("NOTIFY_WEBHOOK_URL") {
+        let url = Url::parse(&val).expect("NOTIFY_WEBHOOK_URL is not a valid url!");
+        let secret = std::env::var("NOTIFY_WEBHOOK_SECRET").ok().map(|secret| secret.into_bytes());
+        notifiers.push(Box::new(WebhookNotifier::new(url, secret)));
+    }
+    if let Ok(host) = std::env::var("NOTIFY_SMTP_HOST") {
+        let settings = SmtpSettings {
+            host,
+            port: std::env::var("NOTIFY_SMTP_PORT").ok().map(|val| val.parse().expect("NOTIFY_SMTP_PORT is not a number!")),
+            tls: match std::env::var("NOTIFY_SMTP_TLS") {
+                Ok(val) => val.parse().expect("NOTIFY_SMTP_TLS is not none, starttls or tls!"),
+                Err(_) => SmtpTls::StartTls,
+            },
+            credentials: std::env::var("NOTIFY_SMTP_USERNAME")
+                .ok()
+                .map(|username| (username, std::env::var("NOTIFY_SMTP_PASSWORD").unwrap_or_default())),
+            from: std::env::var("NOTIFY_EMAIL_FROM").expect("NOTIFY_EMAIL_FROM is required with NOTIFY_SMTP_HOST!"),
+            to: list(&std::env::var("NOTIFY_EMAIL_TO").expect("NOTIFY_EMAIL_TO is required with NOTIFY_SMTP_HOST!")),
+        };
+        notifiers.push(Box::new(EmailNotifier::new(settings).expect("The email notification settings are invalid!")));
+    }
+    for (name, format) in [("NOTIFY_TEAMS_URL", ChatFormat::Teams), ("NOTIFY_SLACK_URL", ChatFormat::Slack)] {
+        if let Ok(val) = std::env::var(name) {
+            let url = Url::parse(&val).unwrap_or_else(|_| panic!("{} is not a valid url!", name));
+            notifiers.push(Box::new(ChatNotifier::new(format, url)));
+        }
+    }
+    let notify_digest: bool = match std::env::var("NOTIFY_DIGEST") {
+        Ok(val) => val.parse().expect("NOTIFY_DIGEST is not true or false!"),
+        Err(_) => false,
+    };
+    let notify_expiry_days: i64 = match std::env::var("NOTIFY_EXPIRY_DAYS") {
+        Ok(val) => val.parse().expect("NOTIFY_EXPIRY_DAYS is not a number!"),
+        Err(_) => 14,
+    };
+
+    // certificate changes are published as cloud events to an http endpoint, an event grid topic or both
+    let mut event_sinks = Vec::new();
+    if let Ok(val) = std::env::var("CLOUDEVENTS_URL") {
+        event_sinks.push(EventSink::Http(Url::parse(&val).expect("CLOUDEVENTS_URL is not a valid url!")));
+    }
+    if let Ok(val) = std::env::var("EVENT_GRID_TOPIC_ENDPOINT") {
+        let endpoint = Url::parse(&val).expect("EVENT_GRID_TOPIC_ENDPOINT is not a valid url!");
+        event_sinks.push(EventSink::EventGrid { endpoint, key: std::env::var("EVENT_GRID_TOPIC_KEY").ok() });
+    }
+
+    // renewed certificates are deployed through azure resource manager, a local stub can stand in for it
+    let arm_endpoint = match std::env::var("ARM_ENDPOINT") {
+        Ok(val) => Url::parse(&val).expect("ARM_ENDPOINT is not a valid url!"),
+        Err(_) => Url::parse("https://management.azure.com")?,
+    };
+
+    // certificates are only exported to files inside this directory, and only these commands may run afterwards
+    let export_directory = std::env::var("EXPORT_DIRECTORY").ok().map(|val| {
+        let directory = PathBuf::from(val);
+        assert!(directory.is_absolute(), "EXPORT_DIRECTORY is not an absolute path!");
+        directory
+    });
+    let export_reload_commands: Vec<String> = std::env::var("EXPORT_RELOAD_COMMANDS")
+        .unwrap_or_default()
+        .split(';')
+        .map(|command| command.trim().to_string())
+        .filter(|command| !command.is_empty())
+        .collect();
+
+    // the dashboard's csrf tokens are derived from this key, instances have to share it.
+    // Only local debug builds may get by with a random key of their own
+    let csrf_key = match std::env::var("CSRF_SECRET") {
+        Ok(val) if !val.is_empty() => val.into_bytes(),
+        _ if cfg!(debug_assertions) => rand::random::<[u8; 32]>().to_vec(),
+        _ => panic!("Missing CSRF_SECRET environment variable."),
+    };
+
+    // connect to cosmos db for logging and shared state (this is optional)
+    let database_client = cosmos_logging(args).await;
+    let store = match &database_client {
+        Some(db) => Store::Cosmos(db.clone()),
+        None => Store::memory(),
+    };
+
+    // challenges have to be shared when the CA's validation request may hit another instance
+    let challenge_store: Arc<dyn ChallengeStore> = match (std::env::var("CHALLENGE_STORE").as_deref(), &database_client) {
+        (Ok("memory"), _) | (Err(_), None) => Arc::new(MemoryChallengeStore::default()),
+        (Ok("cosmos"), Some(db)) | (Err(_), Some(db)) => Arc::new(CosmosChallengeStore::new(db)),
+        (Ok(other), _) => panic!("CHALLENGE_STORE '{}' is not available!", other),
+    };
+    let challenge_ttl: u64 = match std::env::var("CHALLENGE_TTL_SECONDS") {
+        Ok(val) => val.parse().expect("CHALLENGE_TTL_SECONDS is not a number!"),
+        Err(_) => 3600,
+    };
+    let http01_self_check: bool = match std::env::var("HTTP01_SELF_CHECK") {
+        Ok(val) => val.parse().expect("HTTP01_SELF_CHECK is not true or false!"),
+        Err(_) => true,
+    };
 
     let environment_inner = EnvironmentInner {
         certificate_client: keyvault_client.certificate_client(),
         key_client: keyvault_client.key_client(),
+        keyvault_url: Url::parse(&keyvault_url)?,
+        credential,
         account_email: email,
-        challenge_store: RwLock::new(challenge_store)
+        challenge_store,
+        challenge_ttl: Duration::from_secs(challenge_ttl),
+        http01_self_check,
+        renewal_policy,
+        renewal_concurrency: renewal_concurrency.max(1),
+        renewal_timeout: Duration::from_secs(renewal_timeout),
+        retry_policy: RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_secs(retry_minutes * 60),
+            max_delay: Duration::from_secs(24 * 60 * 60),
+        },
+        store,
+        lease_duration: Duration::from_secs(lease_seconds),
+        csrf_key,
+        roles,
+        public_url,
+        token_validator,
+        api_keys: ApiKeys::new(api_key_prefix),
+        allowed_zones,
+        notifications: Notifications {
+            notifiers,
+            digest: notify_digest,
+            expiry_warning: time::Duration::days(notify_expiry_days),
+        },
+        event_sinks,
+        arm_endpoint,
+        export_directory,
+        export_reload_commands,
     };
 
     let environment: Environment = Arc::new(environment_inner);
 
-    // connect to cosmos db for logging (this is optional)
-    let database_client = cosmos_logging(args).await;
     let _ = tracing_log::LogTracer::init();
 
+    // pick up issuances a previous run of the app didn't finish
+    let resume_env = Arc::clone(&environment);
+    tokio::spawn(async move { acme::resume_jobs(&resume_env, &Outbox::Immediate).await });
+
     let app = Router::new()
         .route("/healthCheck", get(StatusCode::OK))
         .route("/checkCertificates", post(timer::check::run))
+        .route("/keyVaultEvents", post(timer::keyvault_events::run).options(timer::keyvault_events::handshake))
         .route("/.well-known/acme-challenge/:token", get(http::http_challenge::run).post(http::http_challenge::run))
         .route("/delete", post(http::delete::run))
-        .route("/register", get(timer::check::run).post(http::new::run))
+        .route("/policy", post(http::policy::run))
+        .route("/notifications", post(http::notifications::run))
+        .route("/jobs/:id", get(http::job::run))
+        .route("/certificates/:name", get(http::certificate::run))
+        .route("/audit", get(http::audit::run))
+        .route("/register", get(timer::check::manual).post(http::new::run))
         .route("/", get(http::status::run))
+        .route("/static/:file", get(http::assets::run))
+        .route("/api/v1/openapi.json", get(api::openapi))
+        .route("/api/v1/certificates", get(api::certificates::list).post(api::certificates::issue))
+        .route("/api/v1/certificates/:name", get(api::certificates::get).delete(api::certificates::delete))
+        .route("/api/v1/certificates/:name/renew", post(api::certificates::renew))
+        .route("/api/v1/certificates/:name/revoke", post(api::certificates::revoke))
+        .route("/api/v1/certificates/:name/notifications", put(api::certificates::notifications))
+        .route("/api/v1/certificates/:name/deployments", get(api::certificates::deployments).put(api::certificates::set_deployments))
+        .route("/api/v1/jobs/:id", get(api::jobs::get))
         .with_state(Arc::clone(&environment))
-        .layer(middleware::from_fn(auth));
+        .layer(middleware::from_fn_with_state(Arc::clone(&environment), csrf))
+        .layer(middleware::from_fn_with_state(Arc::clone(&environment), auth))
+        .layer(middleware::from_fn(error_pages));
 
     // add tracing if connection to cosmos db was successful
     let app = match database_client {
@@ -74,9 +320,165 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     Ok(())
 }
 
+/// Hands out a fixed token, so tests can point the Key Vault url at a local stub.
+#[cfg(test)]
+#[derive(Debug)]
+struct TestCredential;
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl TokenCredential for TestCredential {
+    async fn get_token(&self, _scopes: &[&str]) -> azure_core::Result<azure_core::auth::AccessToken> {
+        Ok(azure_core::auth::AccessToken::new("test-token", time::OffsetDateTime::now_utc() + time::Duration::hours(1)))
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        Ok(())
+    }
+}
+
+/// An environment with the defaults of `main` and a memory store for tests. Its Key Vault
+/// doesn't exist, so only what doesn't reach Azure can be tested with it, unless a test
+/// replaces `keyvault_url` with a stub.
+#[cfg(test)]
+fn test_environment() -> EnvironmentInner {
+    let keyvault_url = "https://test.vault.azure.net/";
+    let credential: Arc<dyn TokenCredential> = Arc::new(TestCredential);
+    let keyvault_client = KeyvaultClient::new(keyvault_url, credential.clone()).unwrap();
+
+    EnvironmentInner {
+        certificate_client: keyvault_client.certificate_client(),
+        key_client: keyvault_client.key_client(),
+        keyvault_url: Url::parse(keyvault_url).unwrap(),
+        credential,
+        account_email: "admin@example.com".to_string(),
+        challenge_store: Arc::new(MemoryChallengeStore::default()),
+        challenge_ttl: Duration::from_secs(3600),
+        http01_self_check: false,
+        renewal_policy: RenewalPolicy::default(),
+        renewal_concurrency: 4,
+        renewal_timeout: Duration::from_secs(120),
+        retry_policy: RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(60 * 60),
+            max_delay: Duration::from_secs(24 * 60 * 60),
+        },
+        store: Store::memory(),
+        lease_duration: Duration::from_secs(600),
+        csrf_key: b"test".to_vec(),
+        roles: RoleMapping {
+            viewer: vec!["Viewer".to_string()],
+            operator: vec!["Operator".to_string()],
+            admin: vec!["Admin".to_string()],
+        },
+        public_url: None,
+        token_validator: None,
+        api_keys: ApiKeys::new("api-key-".to_string()),
+        allowed_zones: Vec::new(),
+        notifications: Notifications {
+            notifiers: Vec::new(),
+            digest: false,
+            expiry_warning: time::Duration::days(14),
+        },
+        event_sinks: Vec::new(),
+        arm_endpoint: Url::parse("https://management.azure.com").unwrap(),
+        export_directory: None,
+        export_reload_commands: Vec::new(),
+    }
+}
+
 async fn cosmos_logging(mut args: Args) -> Option<DatabaseClient> {
     let master_key = args.next()?;
     let authorization_token = AuthorizationToken::primary_key(&master_key).ok()?;
-    let cosmos_client = CosmosClient::new("letsencrypt", authorization_token);
+    // COSMOS_URI points the client at another endpoint, e.g. a local emulator
+    let cosmos_client = match std::env::var("COSMOS_URI") {
+        Ok(uri) => CosmosClientBuilder::with_location(CloudLocation::Custom { uri, auth_token: authorization_token }).build(),
+        Err(_) => CosmosClient::new("letsencrypt", authorization_token),
+    };
     Some(cosmos_client.database_client("letsencrypt"))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    /// The routes of the router in `main` as path and methods, read from this file.
+    fn routes() -> Vec<(String, Vec<&'static str>)> {
+        include_str!("main.rs")
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix(".route(\""))
+            .map(|route| {
+                let (path, handlers) = route.split_once('"').unwrap();
+                let methods = ["get", "post", "put", "delete", "options"]
+                    .into_iter()
+                    .filter(|method| {
+                        handlers.match_indices(&format!("{}(", method)).any(|(index, _)| {
+                            !handlers[..index].ends_with(|c: char| c == ':' || c == '_' || c.is_alphanumeric())
+                        })
+                    })
+                    .collect();
+                (path.to_string(), methods)
+            })
+            .collect()
+    }
+
+    /// The routes the Functions host forwards as route template and methods. Timer triggers
+    /// are posted to the name of the function.
+    fn bindings() -> Vec<(String, Vec<String>)> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut bindings = Vec::new();
+        for entry in std::fs::read_dir(root).unwrap() {
+            let directory = entry.unwrap().path();
+            let Ok(json) = std::fs::read_to_string(directory.join("function.json")) else { continue };
+            let name = directory.file_name().unwrap().to_string_lossy().to_string();
+            let function: serde_json::Value = serde_json::from_str(&json).unwrap();
+            for binding in function["bindings"].as_array().unwrap() {
+                match binding["type"].as_str() {
+                    Some("httpTrigger") => {
+                        let route = binding["route"].as_str().unwrap_or(&name).to_string();
+                        let methods = binding["methods"].as_array().unwrap().iter().map(|method| method.as_str().unwrap().to_string()).collect();
+                        bindings.push((route, methods));
+                    }
+                    Some("timerTrigger") => bindings.push((name.clone(), vec!["post".to_string()])),
+                    _ => {}
+                }
+            }
+        }
+        bindings
+    }
+
+    /// Whether the axum `path` matches the function route `template`.
+    fn matches(template: &str, path: &str) -> bool {
+        let mut template = template.trim_matches('/').split('/').filter(|segment| !segment.is_empty());
+        let mut path = path.trim_matches('/').split('/').filter(|segment| !segment.is_empty());
+        loop {
+            match (template.next(), path.next()) {
+                (None, None) => return true,
+                (Some(segment), Some(_)) if segment.starts_with("{*") => return true,
+                (Some(segment), Some(part)) if segment.starts_with('{') == part.starts_with(':') => {
+                    if !segment.starts_with('{') && !segment.eq_ignore_ascii_case(part) {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    #[test]
+    fn every_route_has_a_function_binding() {
+        let routes = routes();
+        let bindings = bindings();
+        assert!(routes.len() > 10, "{:?}", routes);
+        for (path, methods) in routes {
+            for method in methods {
+                assert!(
+                    bindings.iter().any(|(template, allowed)| matches(template, &path) && allowed.iter().any(|allowed| allowed == method)),
+                    "{} {} has no function.json binding",
+                    method,
+                    path
+                );
+            }
+        }
+    }
+}
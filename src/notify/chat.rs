@@ -0,0 +1,101 @@
+use super::{subject, Notification, Notifier};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::error::Error;
+use url::Url;
+
+/// The message format an incoming webhook expects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatFormat {
+    /// An adaptive card, as Teams workflows post them.
+    Teams,
+    /// A `text` message with Slack's markup.
+    Slack,
+}
+
+/// Posts the notifications to a Teams or Slack incoming webhook.
+#[derive(Debug)]
+pub struct ChatNotifier {
+    format: ChatFormat,
+    url: Url,
+}
+
+/// Slack treats these as markup, everything else is sent as is.
+fn slack_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+impl ChatNotifier {
+    pub fn new(format: ChatFormat, url: Url) -> Self {
+        ChatNotifier { format, url }
+    }
+
+    fn payload(&self, notifications: &[Notification]) -> Value {
+        match self.format {
+            ChatFormat::Teams => {
+                let mut body = vec![json!({
+                    "type": "TextBlock",
+                    "text": subject(notifications),
+                    "weight": "bolder",
+                    "wrap": true,
+                })];
+                if notifications.len() > 1 {
+                    body.extend(notifications.iter().map(|notification| {
+                        json!({ "type": "TextBlock", "text": format!("- {}", notification), "wrap": true })
+                    }));
+                }
+                let actions: Vec<Value> = match notifications {
+                    [Notification { url: Some(url), .. }] => vec![json!({ "type": "Action.OpenUrl", "title": "Open certificate", "url": url })],
+                    _ => Vec::new(),
+                };
+                json!({
+                    "type": "message",
+                    "attachments": [{
+                        "contentType": "application/vnd.microsoft.card.adaptive",
+                        "content": {
+                            "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                            "type": "AdaptiveCard",
+                            "version": "1.4",
+                            "body": body,
+                            "actions": actions,
+                        },
+                    }],
+                })
+            }
+            ChatFormat::Slack => {
+                let line = |notification: &Notification| match &notification.url {
+                    Some(url) => format!("<{}|{}>", url, slack_escape(&notification.to_string())),
+                    None => slack_escape(&notification.to_string()),
+                };
+                let text = match notifications {
+                    [notification] => line(notification),
+                    _ => {
+                        let lines: Vec<String> = notifications.iter().map(|notification| format!("• {}", line(notification))).collect();
+                        format!("*{}*\n{}", slack_escape(&subject(notifications)), lines.join("\n"))
+                    }
+                };
+                json!({ "text": text })
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for ChatNotifier {
+    fn channel(&self) -> &'static str {
+        match self.format {
+            ChatFormat::Teams => "teams",
+            ChatFormat::Slack => "slack",
+        }
+    }
+
+    async fn send(&self, notifications: &[Notification]) -> Result<(), Box<dyn Error>> {
+        reqwest::Client::new()
+            .post(self.url.clone())
+            .json(&self.payload(notifications))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
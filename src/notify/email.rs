@@ -0,0 +1,188 @@
+use super::{subject, Notification, Notifier};
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{error::Error, str::FromStr};
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpTls {
+    /// Plain text, only meant for a local mail catcher.
+    None,
+    /// Upgraded with STARTTLS, usually on port 587.
+    StartTls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+}
+
+impl FromStr for SmtpTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SmtpTls::None),
+            "starttls" => Ok(SmtpTls::StartTls),
+            "tls" => Ok(SmtpTls::Tls),
+            other => Err(format!("'{}' is not none, starttls or tls", other)),
+        }
+    }
+}
+
+/// Where the email notifier connects to and who it sends to.
+#[derive(Debug)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: Option<u16>,
+    pub tls: SmtpTls,
+    pub credentials: Option<(String, String)>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Sends the notifications as plain text emails over SMTP.
+#[derive(Debug)]
+pub struct EmailNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl EmailNotifier {
+    pub fn new(settings: SmtpSettings) -> Result<Self, Box<dyn Error>> {
+        let mut builder = match settings.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host),
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.host)?,
+        };
+        if let Some(port) = settings.port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = settings.credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        let to = settings.to.iter().map(|to| to.parse()).collect::<Result<Vec<Mailbox>, _>>()?;
+        if to.is_empty() {
+            return Err("At least one recipient is needed".into());
+        }
+
+        Ok(EmailNotifier { transport: builder.build(), from: settings.from.parse()?, to })
+    }
+
+    fn body(notifications: &[Notification]) -> String {
+        let mut body = String::new();
+        for notification in notifications {
+            body += &format!("{}\n", notification);
+            if let Some(url) = &notification.url {
+                body += &format!("{}\n", url);
+            }
+            body += "\n";
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    fn channel(&self) -> &'static str {
+        "email"
+    }
+
+    async fn send(&self, notifications: &[Notification]) -> Result<(), Box<dyn Error>> {
+        let mut message = Message::builder().from(self.from.clone()).subject(subject(notifications));
+        for to in self.to.iter() {
+            message = message.to(to.clone());
+        }
+        let message = message.header(ContentType::TEXT_PLAIN).body(Self::body(notifications))?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::Event;
+    use time::macros::datetime;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    /// A mail catcher that accepts one plain text SMTP session and returns the message.
+    async fn catch_mail(listener: TcpListener) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+        let mut message = String::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let command = line.to_uppercase();
+            let reply: &[u8] = if command.starts_with("EHLO") {
+                b"250-localhost\r\n250 8BITMIME\r\n"
+            } else if command.starts_with("DATA") {
+                writer.write_all(b"354 go ahead\r\n").await.unwrap();
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    if line == "." {
+                        break;
+                    }
+                    message += &line;
+                    message += "\n";
+                }
+                b"250 queued\r\n"
+            } else if command.starts_with("QUIT") {
+                writer.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+        message
+    }
+
+    #[tokio::test]
+    async fn sends_notifications_over_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let catcher = tokio::spawn(catch_mail(listener));
+
+        let notifier = EmailNotifier::new(SmtpSettings {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            tls: SmtpTls::None,
+            credentials: None,
+            from: "certificates@example.com".to_string(),
+            to: vec!["ops@example.com".to_string()],
+        })
+        .unwrap();
+        let notification = Notification::new(Event::Expiring, "www-example-com")
+            .domain("www.example.com")
+            .expires_on(Some(datetime!(2030-01-31 12:00 UTC)));
+        notifier.send(&[notification]).await.unwrap();
+
+        let message = catcher.await.unwrap();
+        assert!(message.contains("From: certificates@example.com"), "{}", message);
+        assert!(message.contains("To: ops@example.com"), "{}", message);
+        assert!(message.contains("Subject: Certificate www-example-com (www.example.com) expires on 2030-01-31"), "{}", message);
+        assert!(message.contains("\nCertificate www-example-com (www.example.com) expires on 2030-01-31\n"), "{}", message);
+    }
+
+    #[test]
+    fn needs_a_recipient() {
+        let settings = SmtpSettings {
+            host: "localhost".to_string(),
+            port: None,
+            tls: SmtpTls::None,
+            credentials: None,
+            from: "certificates@example.com".to_string(),
+            to: Vec::new(),
+        };
+        assert!(EmailNotifier::new(settings).is_err());
+    }
+}
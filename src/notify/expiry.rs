@@ -0,0 +1,68 @@
+use crate::keyvault::Tags;
+use time::Duration;
+
+/// The Key Vault certificate tag that holds the last expiry warning a certificate got, as
+/// the threshold in days before expiry. Renewing the certificate removes it.
+pub const NOTIFIED_TAG: &str = "expiry-notified";
+
+/// Days before expiry at which a certificate is warned about again, besides the first
+/// warning `NOTIFY_EXPIRY_DAYS` before.
+const THRESHOLDS: [i64; 2] = [7, 1];
+
+/// Returns the lowest threshold in days a certificate expiring in `remaining` crossed, if
+/// it expires within `warning`.
+fn threshold(remaining: Duration, warning: Duration) -> Option<i64> {
+    let warning_days = warning.whole_days();
+    std::iter::once(warning_days)
+        .chain(THRESHOLDS)
+        .filter(|days| *days <= warning_days && remaining <= Duration::days(*days))
+        .min()
+}
+
+/// Returns the threshold to warn about a certificate expiring in `remaining` at, unless
+/// its `tags` show it was warned at that threshold already.
+pub fn due(tags: Option<&Tags>, remaining: Duration, warning: Duration) -> Option<i64> {
+    let threshold = threshold(remaining, warning)?;
+    let notified = tags
+        .and_then(|tags| tags.get(NOTIFIED_TAG))
+        .and_then(|days| days.parse::<i64>().ok());
+    match notified {
+        Some(notified) if notified <= threshold => None,
+        _ => Some(threshold),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WARNING: Duration = Duration::days(14);
+
+    fn notified(days: &str) -> Tags {
+        Tags::from([(NOTIFIED_TAG.to_string(), days.to_string())])
+    }
+
+    #[test]
+    fn crosses_the_thresholds() {
+        assert_eq!(threshold(Duration::days(30), WARNING), None);
+        assert_eq!(threshold(Duration::days(14), WARNING), Some(14));
+        assert_eq!(threshold(Duration::days(8), WARNING), Some(14));
+        assert_eq!(threshold(Duration::hours(7 * 24 - 1), WARNING), Some(7));
+        assert_eq!(threshold(Duration::hours(12), WARNING), Some(1));
+        assert_eq!(threshold(Duration::days(-2), WARNING), Some(1));
+        assert_eq!(threshold(Duration::days(4), Duration::days(5)), Some(5));
+        assert_eq!(threshold(Duration::days(6), Duration::days(3)), None);
+    }
+
+    #[test]
+    fn warns_once_per_threshold() {
+        assert_eq!(due(None, Duration::days(10), WARNING), Some(14));
+        assert_eq!(due(Some(&Tags::new()), Duration::days(10), WARNING), Some(14));
+        assert_eq!(due(Some(&notified("14")), Duration::days(10), WARNING), None);
+        assert_eq!(due(Some(&notified("14")), Duration::days(6), WARNING), Some(7));
+        assert_eq!(due(Some(&notified("7")), Duration::days(6), WARNING), None);
+        assert_eq!(due(Some(&notified("7")), Duration::hours(20), WARNING), Some(1));
+        assert_eq!(due(Some(&notified("1")), Duration::hours(-20), WARNING), None);
+        assert_eq!(due(Some(&notified("garbage")), Duration::days(10), WARNING), Some(14));
+    }
+}
@@ -0,0 +1,240 @@
+use self::subscription::Subscription;
+use crate::{keyvault::list_tags, Environment};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, str::FromStr, sync::{Arc, Mutex}};
+use time::{Duration, OffsetDateTime};
+use tracing::info;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub mod chat;
+pub mod email;
+pub mod expiry;
+pub mod subscription;
+pub mod webhook;
+
+/// The channels certificates can subscribe to, one per kind of notifier.
+pub const CHANNELS: [&str; 4] = ["webhook", "email", "teams", "slack"];
+
+/// What a notification is about.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// A certificate was issued or renewed.
+    Issued,
+    /// Issuing or renewing a certificate failed.
+    Failed,
+    /// A certificate expires within `NOTIFY_EXPIRY_DAYS` and wasn't renewed yet.
+    Expiring,
+    Revoked,
+}
+
+impl Event {
+    pub const ALL: [Event; 4] = [Event::Issued, Event::Failed, Event::Expiring, Event::Revoked];
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Issued => write!(f, "issued"),
+            Event::Failed => write!(f, "failed"),
+            Event::Expiring => write!(f, "expiring"),
+            Event::Revoked => write!(f, "revoked"),
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Event::ALL
+            .into_iter()
+            .find(|event| event.to_string() == s.trim())
+            .ok_or_else(|| format!("'{}' is not an event, use issued, failed, expiring or revoked", s.trim()))
+    }
+}
+
+/// Something that happened to a certificate that its subscribers are told about.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub event: Event,
+    /// The Key Vault certificate name.
+    pub certificate: String,
+    pub domain: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_on: Option<OffsetDateTime>,
+    pub error: Option<String>,
+    /// The issuance job, for issued and failed certificates.
+    pub job: Option<Uuid>,
+    /// The certificate's page on the dashboard, set if `PUBLIC_URL` is configured.
+    pub url: Option<String>,
+}
+
+impl Notification {
+    pub fn new(event: Event, certificate: &str) -> Self {
+        Notification {
+            event,
+            certificate: certificate.to_string(),
+            domain: None,
+            time: OffsetDateTime::now_utc(),
+            expires_on: None,
+            error: None,
+            job: None,
+            url: None,
+        }
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn expires_on(mut self, expires_on: Option<OffsetDateTime>) -> Self {
+        self.expires_on = expires_on;
+        self
+    }
+
+    pub fn error(mut self, error: &str) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    pub fn job(mut self, job: Uuid) -> Self {
+        self.job = Some(job);
+        self
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match &self.domain {
+            Some(domain) => format!("{} ({})", self.certificate, domain),
+            None => self.certificate.clone(),
+        };
+        match (self.event, self.expires_on) {
+            (Event::Issued, Some(expires_on)) => write!(f, "Certificate {} was issued, it expires on {}", name, expires_on.date()),
+            (Event::Issued, None) => write!(f, "Certificate {} was issued", name),
+            (Event::Failed, _) => write!(f, "Issuing certificate {} failed: {}", name, self.error.as_deref().unwrap_or("unknown error")),
+            (Event::Expiring, Some(expires_on)) => write!(f, "Certificate {} expires on {}", name, expires_on.date()),
+            (Event::Expiring, None) => write!(f, "Certificate {} expires soon", name),
+            (Event::Revoked, _) => write!(f, "Certificate {} was revoked", name),
+        }
+    }
+}
+
+/// The subject of a message carrying `notifications`.
+pub fn subject(notifications: &[Notification]) -> String {
+    match notifications {
+        [notification] => notification.to_string(),
+        _ => format!("Key Vault Let's Encrypt: {} certificate notifications", notifications.len()),
+    }
+}
+
+/// Delivers notifications to one channel, e.g. an email address or a chat.
+#[async_trait]
+pub trait Notifier: Send + Sync + fmt::Debug {
+    /// The channel certificates subscribe to, one of `CHANNELS`.
+    fn channel(&self) -> &'static str;
+
+    /// Sends the notifications as a single message, a digest if there are several.
+    async fn send(&self, notifications: &[Notification]) -> Result<(), Box<dyn Error>>;
+}
+
+/// The configured notifiers and when they are used.
+#[derive(Debug)]
+pub struct Notifications {
+    pub notifiers: Vec<Box<dyn Notifier>>,
+    /// Collect the notifications of a certificate check into one message per channel.
+    pub digest: bool,
+    /// Certificates expiring within this time are reported by the certificate check.
+    pub expiry_warning: Duration,
+}
+
+/// Where notifications go: out right away, or collected into the digest that a
+/// certificate check sends once it's done.
+#[derive(Debug, Clone)]
+pub enum Outbox {
+    Immediate,
+    Digest(Arc<Mutex<Vec<Notification>>>),
+}
+
+impl Outbox {
+    /// The outbox of a certificate check, a digest if `NOTIFY_DIGEST` is set.
+    pub fn for_check(env: &Environment) -> Self {
+        match env.notifications.digest {
+            true => Outbox::Digest(Arc::default()),
+            false => Outbox::Immediate,
+        }
+    }
+
+    pub async fn send(&self, env: &Environment, notification: Notification) {
+        match self {
+            Outbox::Immediate => deliver(env, vec![notification]).await,
+            Outbox::Digest(notifications) => notifications.lock().unwrap().push(notification),
+        }
+    }
+
+    /// Sends the digest, if this is one.
+    pub async fn flush(&self, env: &Environment) {
+        if let Outbox::Digest(notifications) = self {
+            let notifications = std::mem::take(&mut *notifications.lock().unwrap());
+            deliver(env, notifications).await;
+        }
+    }
+}
+
+/// Sends a notification right away.
+pub async fn send(env: &Environment, notification: Notification) {
+    Outbox::Immediate.send(env, notification).await;
+}
+
+/// Sends each notifier the notifications its channel is subscribed to. The notifications
+/// are about things that already happened, so failing to send them is only logged.
+async fn deliver(env: &Environment, mut notifications: Vec<Notification>) {
+    if env.notifications.notifiers.is_empty() || notifications.is_empty() {
+        return;
+    }
+
+    // the subscriptions are in the certificate tags, certificates that failed before they
+    // were ever issued have none and get the default subscription
+    let tags = match list_tags(env).await.map_err(|error| error.to_string()) {
+        Ok(tags) => tags,
+        Err(error) => {
+            info!("Could not read the notification subscriptions: {}", error);
+            Default::default()
+        }
+    };
+    for notification in notifications.iter_mut() {
+        notification.url = env
+            .public_url
+            .as_ref()
+            .map(|public_url| format!("{}/certificates/{}", public_url, notification.certificate));
+    }
+
+    for notifier in env.notifications.notifiers.iter() {
+        let channel = notifier.channel();
+        let subscribed: Vec<Notification> = notifications
+            .iter()
+            .filter(|notification| {
+                let subscription = match tags.get(&notification.certificate) {
+                    Some(tags) => Subscription::from_tags(tags),
+                    None => Subscription::default(),
+                };
+                subscription.wants(notification.event, channel)
+            })
+            .cloned()
+            .collect();
+        if subscribed.is_empty() {
+            continue;
+        }
+
+        match notifier.send(&subscribed).await.map_err(|error| error.to_string()) {
+            Ok(()) => info!("Sent {} notifications to {}", subscribed.len(), channel),
+            Err(error) => info!("Could not send notifications to {}: {}", channel, error),
+        }
+    }
+}
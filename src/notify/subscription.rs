@@ -0,0 +1,95 @@
+use super::{Event, CHANNELS};
+use crate::keyvault::Tags;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// The Key Vault certificate tag that holds the events a certificate notifies about,
+/// separated by commas, or `none`.
+pub const EVENTS_TAG: &str = "notify-events";
+/// The Key Vault certificate tag that holds the channels a certificate notifies, separated
+/// by commas.
+pub const CHANNELS_TAG: &str = "notify-channels";
+
+/// Which notifications of a certificate are sent where.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Subscription {
+    /// The events to notify about, every event by default.
+    pub events: Vec<Event>,
+    /// The channels to notify, every configured channel if not set.
+    pub channels: Option<Vec<String>>,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Subscription { events: Event::ALL.to_vec(), channels: None }
+    }
+}
+
+impl Subscription {
+    /// Reads the subscription from the tags of a certificate. Events and channels that
+    /// can't be parsed are left out.
+    pub fn from_tags(tags: &Tags) -> Self {
+        let events = match tags.get(EVENTS_TAG).map(|events| events.trim()) {
+            Some("none") => Vec::new(),
+            Some(events) => events.split(',').filter_map(|event| event.parse().ok()).collect(),
+            None => Event::ALL.to_vec(),
+        };
+        let channels = tags.get(CHANNELS_TAG).map(|channels| {
+            channels
+                .split(',')
+                .map(|channel| channel.trim().to_string())
+                .filter(|channel| CHANNELS.contains(&channel.as_str()))
+                .collect()
+        });
+        Subscription { events, channels }
+    }
+
+    /// Writes the subscription to the tags, removing the tags the default doesn't need.
+    pub fn write_tags(&self, tags: &mut Tags) {
+        match self.events.as_slice() {
+            [] => {
+                tags.insert(EVENTS_TAG.to_string(), "none".to_string());
+            }
+            events if Event::ALL.iter().all(|event| events.contains(event)) => {
+                tags.remove(EVENTS_TAG);
+            }
+            events => {
+                let events: Vec<String> = events.iter().map(|event| event.to_string()).collect();
+                tags.insert(EVENTS_TAG.to_string(), events.join(","));
+            }
+        }
+        match &self.channels {
+            Some(channels) => {
+                tags.insert(CHANNELS_TAG.to_string(), channels.join(","));
+            }
+            None => {
+                tags.remove(CHANNELS_TAG);
+            }
+        }
+    }
+
+    /// Checks that every channel is one of `CHANNELS`.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.channels.iter().flatten().find(|channel| !CHANNELS.contains(&channel.as_str())) {
+            Some(channel) => Err(format!("'{}' is not a channel, use {}", channel, CHANNELS.join(", "))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn wants(&self, event: Event, channel: &str) -> bool {
+        self.events.contains(&event)
+            && self.channels.as_ref().is_none_or(|channels| channels.iter().any(|subscribed| subscribed == channel))
+    }
+
+    /// A human readable description for the certificate page.
+    pub fn describe(&self) -> String {
+        let events = match self.events.as_slice() {
+            [] => return "none".to_string(),
+            events => events.iter().map(|event| event.to_string()).collect::<Vec<_>>().join(", "),
+        };
+        match &self.channels {
+            Some(channels) => format!("{} to {}", events, channels.join(", ")),
+            None => format!("{} to every channel", events),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use super::{Notification, Notifier};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use std::error::Error;
+use time::OffsetDateTime;
+use url::Url;
+
+/// The header carrying the signature, `t=<unix time>,v1=<hex HMAC-SHA256 of "<t>.<body>">`.
+/// Signing the time lets receivers reject replayed requests.
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Posts the notifications as JSON to any url, signed if a secret is configured.
+#[derive(Debug)]
+pub struct WebhookNotifier {
+    url: Url,
+    secret: Option<Vec<u8>>,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: Url, secret: Option<Vec<u8>>) -> Self {
+        WebhookNotifier { url, secret }
+    }
+
+    fn signature(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        let signature: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("t={},v1={}", timestamp, signature)
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn channel(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn send(&self, notifications: &[Notification]) -> Result<(), Box<dyn Error>> {
+        let body = serde_json::to_vec(&json!({ "notifications": notifications }))?;
+
+        let mut request = reqwest::Client::new()
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+            request = request.header(SIGNATURE_HEADER, Self::signature(secret, timestamp, &body));
+        }
+
+        request.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
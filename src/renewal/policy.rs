@@ -0,0 +1,175 @@
+use crate::keyvault::Tags;
+use serde::{Serialize, Serializer};
+use std::{fmt, str::FromStr};
+use time::{Duration, OffsetDateTime};
+
+/// The Key Vault certificate tag that holds the renewal policy of a certificate.
+pub const POLICY_TAG: &str = "renewal-policy";
+/// The most days before expiry a certificate can be renewed at. Certificates don't live
+/// that long anyway, and larger values would overflow the renewal date.
+pub const MAX_DAYS: i64 = 3650;
+
+/// Decides when a certificate is due for renewal. Stored on the certificate as
+/// `days:<n>` or `lifetime:<fraction>`, e.g. `days:30` or `lifetime:2/3`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenewalPolicy {
+    /// Renew a fixed number of days before the certificate expires.
+    DaysBeforeExpiry(i64),
+    /// Renew once this fraction of the certificate lifetime has elapsed.
+    LifetimeFraction(f64),
+}
+
+impl RenewalPolicy {
+    /// Reads the policy from the tags of a certificate, falling back to `default`
+    /// if the tag is missing or can't be parsed.
+    pub fn from_tags(tags: &Tags, default: RenewalPolicy) -> Self {
+        tags.get(POLICY_TAG)
+            .and_then(|policy| policy.parse().ok())
+            .unwrap_or(default)
+    }
+
+    /// Returns the date at which a certificate valid between `not_before` and
+    /// `expires_on` should be renewed.
+    pub fn renewal_date(&self, not_before: Option<OffsetDateTime>, expires_on: OffsetDateTime) -> OffsetDateTime {
+        match (self, not_before) {
+            (RenewalPolicy::DaysBeforeExpiry(days), _) => expires_on.saturating_sub(Duration::days(*days)),
+            (RenewalPolicy::LifetimeFraction(fraction), Some(not_before)) => {
+                let lifetime = expires_on - not_before;
+                not_before.saturating_add(lifetime * *fraction)
+            }
+            // without a start date the lifetime is unknown, assume the 90 days Let's Encrypt issues
+            (RenewalPolicy::LifetimeFraction(fraction), None) => {
+                expires_on.saturating_sub(Duration::days(90) * (1.0 - *fraction))
+            }
+        }
+    }
+
+    /// A human readable description for the dashboard.
+    pub fn describe(&self) -> String {
+        match self {
+            RenewalPolicy::DaysBeforeExpiry(days) => format!("{} days before expiry", days),
+            RenewalPolicy::LifetimeFraction(fraction) => format!("at {:.0}% of lifetime", fraction * 100.0),
+        }
+    }
+}
+
+impl Default for RenewalPolicy {
+    fn default() -> Self {
+        RenewalPolicy::DaysBeforeExpiry(30)
+    }
+}
+
+impl fmt::Display for RenewalPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenewalPolicy::DaysBeforeExpiry(days) => write!(f, "days:{}", days),
+            RenewalPolicy::LifetimeFraction(fraction) => write!(f, "lifetime:{}", fraction),
+        }
+    }
+}
+
+/// Serialized in the same format as the tag, e.g. `days:30`.
+impl Serialize for RenewalPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for RenewalPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("renewal policy '{}' must look like 'days:30' or 'lifetime:2/3'", s))?;
+
+        match kind {
+            "days" => {
+                let days: i64 = value.parse().map_err(|_| format!("'{}' is not a number of days", value))?;
+                if days <= 0 {
+                    return Err(format!("renewal days must be positive, got {}", days));
+                }
+                if days > MAX_DAYS {
+                    return Err(format!("renewal days can be at most {}, got {}", MAX_DAYS, days));
+                }
+                Ok(RenewalPolicy::DaysBeforeExpiry(days))
+            }
+            "lifetime" => {
+                let fraction = match value.split_once('/') {
+                    Some((numerator, denominator)) => {
+                        let numerator: f64 = numerator.parse().map_err(|_| format!("'{}' is not a fraction", value))?;
+                        let denominator: f64 = denominator.parse().map_err(|_| format!("'{}' is not a fraction", value))?;
+                        numerator / denominator
+                    }
+                    None => value.parse().map_err(|_| format!("'{}' is not a fraction", value))?,
+                };
+                if !(fraction > 0.0 && fraction < 1.0) {
+                    return Err(format!("lifetime fraction must be between 0 and 1, got {}", value));
+                }
+                Ok(RenewalPolicy::LifetimeFraction(fraction))
+            }
+            _ => Err(format!("unknown renewal policy '{}'", kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("days:30".parse(), Ok(RenewalPolicy::DaysBeforeExpiry(30)));
+        assert_eq!(" days:3650 ".parse(), Ok(RenewalPolicy::DaysBeforeExpiry(3650)));
+        assert_eq!("lifetime:2/3".parse(), Ok(RenewalPolicy::LifetimeFraction(2.0 / 3.0)));
+        assert_eq!("lifetime:0.5".parse(), Ok(RenewalPolicy::LifetimeFraction(0.5)));
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        for policy in [
+            "30",
+            "days:",
+            "days:0",
+            "days:-1",
+            "days:3651",
+            "days:1000000000000000",
+            "days:abc",
+            "lifetime:0",
+            "lifetime:1",
+            "lifetime:3/2",
+            "lifetime:1/0",
+            "lifetime:x/3",
+            "weeks:2",
+        ] {
+            assert!(policy.parse::<RenewalPolicy>().is_err(), "{} was accepted", policy);
+        }
+    }
+
+    #[test]
+    fn formats_as_parsed() {
+        for policy in [RenewalPolicy::DaysBeforeExpiry(30), RenewalPolicy::LifetimeFraction(0.75)] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+    }
+
+    #[test]
+    fn renews_days_before_expiry() {
+        let expires_on = datetime!(2025-03-31 12:00 UTC);
+        let policy = RenewalPolicy::DaysBeforeExpiry(30);
+        assert_eq!(policy.renewal_date(None, expires_on), datetime!(2025-03-01 12:00 UTC));
+        assert_eq!(RenewalPolicy::DaysBeforeExpiry(MAX_DAYS).renewal_date(None, expires_on), expires_on - Duration::days(MAX_DAYS));
+    }
+
+    #[test]
+    fn renews_at_fraction_of_lifetime() {
+        let not_before = datetime!(2025-01-01 00:00 UTC);
+        let expires_on = datetime!(2025-04-01 00:00 UTC);
+        let policy = RenewalPolicy::LifetimeFraction(2.0 / 3.0);
+        assert_eq!(policy.renewal_date(Some(not_before), expires_on), datetime!(2025-03-02 00:00 UTC));
+        // without a start date 90 days are assumed
+        assert_eq!(policy.renewal_date(None, expires_on), datetime!(2025-03-02 00:00 UTC));
+    }
+}
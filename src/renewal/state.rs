@@ -0,0 +1,193 @@
+use crate::keyvault::Tags;
+use rand::Rng;
+use serde::Serialize;
+use std::time::Duration;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use utoipa::ToSchema;
+
+/// Key Vault certificate tags holding the outcome of previous renewal attempts.
+pub const STATUS_TAG: &str = "renewal-status";
+pub const ATTEMPTS_TAG: &str = "renewal-attempts";
+pub const LAST_ERROR_TAG: &str = "renewal-last-error";
+pub const NEXT_RETRY_TAG: &str = "renewal-next-retry";
+
+/// Key Vault limits tag values to 256 characters.
+const MAX_TAG_LENGTH: usize = 256;
+
+/// How the renewal of a certificate is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RenewalStatus {
+    #[default]
+    Ok,
+    /// The last renewal failed and is retried with backoff.
+    Retrying,
+    /// Renewal failed too many times in a row and needs attention.
+    Failing,
+}
+
+impl RenewalStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RenewalStatus::Ok => "ok",
+            RenewalStatus::Retrying => "retrying",
+            RenewalStatus::Failing => "failing",
+        }
+    }
+}
+
+/// Settings for retrying failed renewals.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of failed attempts after which a certificate is considered failing.
+    pub max_attempts: u32,
+    /// Delay after the first failure, doubled for every further failure.
+    pub base_delay: Duration,
+    /// Upper bound for the delay between two attempts.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before the next attempt after `attempts` failed ones, doubled for every
+    /// failure with up to 20% jitter so things that failed together don't retry together.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(16);
+        let delay = self.base_delay.saturating_mul(1 << exponent).min(self.max_delay);
+        let jitter = rand::thread_rng().gen_range(0.8..=1.0);
+        delay.mul_f64(jitter)
+    }
+}
+
+/// The failure state of a certificate renewal, persisted in the certificate tags.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RenewalState {
+    pub status: RenewalStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_retry: Option<OffsetDateTime>,
+}
+
+impl RenewalState {
+    pub fn from_tags(tags: &Tags) -> Self {
+        let status = match tags.get(STATUS_TAG).map(String::as_str) {
+            Some("retrying") => RenewalStatus::Retrying,
+            Some("failing") => RenewalStatus::Failing,
+            _ => RenewalStatus::Ok,
+        };
+
+        RenewalState {
+            status,
+            attempts: tags.get(ATTEMPTS_TAG).and_then(|attempts| attempts.parse().ok()).unwrap_or(0),
+            last_error: tags.get(LAST_ERROR_TAG).cloned(),
+            next_retry: tags
+                .get(NEXT_RETRY_TAG)
+                .and_then(|date| OffsetDateTime::parse(date, &Rfc3339).ok()),
+        }
+    }
+
+    /// Writes the state into `tags`, removing the state tags of a healthy certificate.
+    pub fn write_tags(&self, tags: &mut Tags) {
+        Self::clear_tags(tags);
+
+        if self.status == RenewalStatus::Ok {
+            return;
+        }
+
+        tags.insert(STATUS_TAG.to_string(), self.status.as_str().to_string());
+        tags.insert(ATTEMPTS_TAG.to_string(), self.attempts.to_string());
+        if let Some(error) = &self.last_error {
+            tags.insert(LAST_ERROR_TAG.to_string(), error.chars().take(MAX_TAG_LENGTH).collect());
+        }
+        if let Some(next_retry) = self.next_retry.and_then(|date| date.format(&Rfc3339).ok()) {
+            tags.insert(NEXT_RETRY_TAG.to_string(), next_retry);
+        }
+    }
+
+    /// Removes all renewal state tags, e.g. after a successful renewal.
+    pub fn clear_tags(tags: &mut Tags) {
+        for tag in [STATUS_TAG, ATTEMPTS_TAG, LAST_ERROR_TAG, NEXT_RETRY_TAG] {
+            tags.remove(tag);
+        }
+    }
+
+    /// Returns true while the certificate is backing off from a failed attempt.
+    pub fn is_backing_off(&self, now: OffsetDateTime) -> bool {
+        self.next_retry.is_some_and(|next_retry| next_retry > now)
+    }
+
+    /// Records a failed attempt and schedules the next one with exponential backoff and jitter.
+    pub fn record_failure(&mut self, error: &str, retry: &RetryPolicy) {
+        self.attempts += 1;
+        self.last_error = Some(error.to_string());
+        self.status = if self.attempts >= retry.max_attempts {
+            RenewalStatus::Failing
+        } else {
+            RenewalStatus::Retrying
+        };
+
+        self.next_retry = Some(OffsetDateTime::now_utc() + retry.delay(self.attempts));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETRY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_secs(60 * 60),
+        max_delay: Duration::from_secs(24 * 60 * 60),
+    };
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum() {
+        for attempts in 1..40 {
+            let delay = RETRY.delay(attempts);
+            let full = RETRY.base_delay.saturating_mul(1 << (attempts - 1).min(16)).min(RETRY.max_delay);
+            assert!(delay <= full && delay >= full.mul_f64(0.8), "{:?} after {} attempts", delay, attempts);
+        }
+        assert!(RETRY.delay(2) >= Duration::from_secs(96 * 60));
+        assert!(RETRY.delay(30) <= RETRY.max_delay);
+    }
+
+    #[test]
+    fn failures_back_off_and_become_failing() {
+        let mut state = RenewalState::default();
+        let now = OffsetDateTime::now_utc();
+        assert!(!state.is_backing_off(now));
+
+        state.record_failure("connection refused", &RETRY);
+        assert_eq!(state.status, RenewalStatus::Retrying);
+        assert_eq!(state.attempts, 1);
+        assert_eq!(state.last_error.as_deref(), Some("connection refused"));
+        assert!(state.is_backing_off(now));
+        assert!(!state.is_backing_off(now + time::Duration::hours(2)));
+
+        state.record_failure("timed out", &RETRY);
+        state.record_failure("timed out", &RETRY);
+        assert_eq!(state.status, RenewalStatus::Failing);
+        assert_eq!(state.attempts, 3);
+    }
+
+    #[test]
+    fn round_trips_through_tags() {
+        let mut state = RenewalState::default();
+        state.record_failure(&"x".repeat(1000), &RETRY);
+
+        let mut tags = Tags::new();
+        tags.insert("renewal-policy".to_string(), "days:30".to_string());
+        state.write_tags(&mut tags);
+        assert_eq!(tags[LAST_ERROR_TAG].len(), MAX_TAG_LENGTH);
+
+        let read = RenewalState::from_tags(&tags);
+        assert_eq!(read.status, RenewalStatus::Retrying);
+        assert_eq!(read.attempts, 1);
+        assert_eq!(read.next_retry.map(|date| date.unix_timestamp()), state.next_retry.map(|date| date.unix_timestamp()));
+
+        // a healthy certificate has no state tags, other tags are kept
+        RenewalState::default().write_tags(&mut tags);
+        assert_eq!(tags.len(), 1);
+        assert_eq!(RenewalState::from_tags(&tags).status, RenewalStatus::Ok);
+    }
+}
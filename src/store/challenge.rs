@@ -0,0 +1,174 @@
+use async_trait::async_trait;
+use super::StorageError;
+use azure_data_cosmos::{prelude::*, CosmosEntity};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error, fmt::Debug, sync::RwLock, time::Duration};
+use time::OffsetDateTime;
+
+/// Holds the HTTP-01 key authorizations served under `/.well-known/acme-challenge/{token}`
+/// until the CA validated them. Tokens expire after their ttl even if nobody removes them.
+#[async_trait]
+pub trait ChallengeStore: Send + Sync + Debug {
+    async fn put(&self, token: &str, key_authorization: &str, ttl: Duration) -> Result<(), Box<dyn Error>>;
+
+    async fn get(&self, token: &str) -> Result<Option<String>, Box<dyn Error>>;
+
+    async fn remove(&self, token: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// Keeps challenges in process memory. Only works as long as the CA's validation request
+/// reaches the instance that started the order.
+#[derive(Debug, Default)]
+pub struct MemoryChallengeStore {
+    challenges: RwLock<HashMap<String, (String, OffsetDateTime)>>,
+}
+
+#[async_trait]
+impl ChallengeStore for MemoryChallengeStore {
+    async fn put(&self, token: &str, key_authorization: &str, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        let now = OffsetDateTime::now_utc();
+        let mut challenges = self.challenges.write().unwrap();
+
+        // drop whatever expired in the meantime
+        challenges.retain(|_, (_, expires_at)| *expires_at > now);
+        challenges.insert(token.to_string(), (key_authorization.to_string(), now + ttl));
+        Ok(())
+    }
+
+    async fn get(&self, token: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let now = OffsetDateTime::now_utc();
+        Ok(self
+            .challenges
+            .read()
+            .unwrap()
+            .get(token)
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(key_authorization, _)| key_authorization.clone()))
+    }
+
+    async fn remove(&self, token: &str) -> Result<(), Box<dyn Error>> {
+        self.challenges.write().unwrap().remove(token);
+        Ok(())
+    }
+}
+
+const CONTAINER: &str = "challenges";
+
+/// Keeps challenges in a Cosmos DB container shared by all instances. The container is
+/// partitioned by `/id` and should have time to live enabled, so Cosmos removes expired
+/// tokens by itself.
+#[derive(Debug)]
+pub struct CosmosChallengeStore {
+    collection: CollectionClient,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChallengeDocument {
+    id: String,
+    key_authorization: String,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+    /// Time to live in seconds, honored by Cosmos if the container has ttl enabled.
+    ttl: u64,
+}
+
+impl CosmosEntity for ChallengeDocument {
+    type Entity = String;
+
+    fn partition_key(&self) -> Self::Entity {
+        self.id.clone()
+    }
+}
+
+impl CosmosChallengeStore {
+    pub fn new(db: &DatabaseClient) -> Self {
+        Self { collection: db.collection_client(CONTAINER) }
+    }
+}
+
+#[async_trait]
+impl ChallengeStore for CosmosChallengeStore {
+    async fn put(&self, token: &str, key_authorization: &str, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        let document = ChallengeDocument {
+            id: token.to_string(),
+            key_authorization: key_authorization.to_string(),
+            expires_at: OffsetDateTime::now_utc() + ttl,
+            ttl: ttl.as_secs().max(1),
+        };
+
+        self.collection.create_document(document).is_upsert(true).await.map_err(StorageError)?;
+        Ok(())
+    }
+
+    async fn get(&self, token: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let client = self.collection.document_client(token, &token).map_err(StorageError)?;
+        let response = client.get_document::<ChallengeDocument>().await.map_err(StorageError)?;
+
+        Ok(match response {
+            GetDocumentResponse::Found(found) if found.document.document.expires_at > OffsetDateTime::now_utc() => {
+                Some(found.document.document.key_authorization)
+            }
+            _ => None,
+        })
+    }
+
+    async fn remove(&self, token: &str) -> Result<(), Box<dyn Error>> {
+        let client = self.collection.document_client(token, &token).map_err(StorageError)?;
+        let result = client.delete_document().await;
+        match result {
+            Ok(_) => Ok(()),
+            // already removed, e.g. by the container ttl
+            Err(error) if error.as_http_error().is_some_and(|error| error.status() == azure_core::StatusCode::NotFound) => Ok(()),
+            Err(error) => Err(Box::new(StorageError(error))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azure_data_cosmos::prelude::{AuthorizationToken, CloudLocation, CosmosClientBuilder};
+
+    /// The fixed key of the Cosmos DB emulator.
+    const EMULATOR_KEY: &str = "C2y6yDjf5/R+ob0N8A7Cgv30VRDJIWEHLM+4QDU5DE2nQ9nDuVTqobD4b8mGGyPMbIZnqyMsEcaGQy67XIw/Jw==";
+
+    async fn exercise(store: &dyn ChallengeStore) {
+        let token = uuid::Uuid::new_v4().to_string();
+        assert_eq!(store.get(&token).await.unwrap(), None);
+
+        store.put(&token, "token.thumbprint", Duration::from_secs(60)).await.unwrap();
+        assert_eq!(store.get(&token).await.unwrap().as_deref(), Some("token.thumbprint"));
+
+        store.remove(&token).await.unwrap();
+        assert_eq!(store.get(&token).await.unwrap(), None);
+        // removing twice is fine, the ttl may have removed it already
+        store.remove(&token).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_store_keeps_challenges_until_removed() {
+        exercise(&MemoryChallengeStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn memory_store_drops_expired_challenges() {
+        let store = MemoryChallengeStore::default();
+        store.put("expired", "expired.thumbprint", Duration::ZERO).await.unwrap();
+        assert_eq!(store.get("expired").await.unwrap(), None);
+    }
+
+    /// Runs against the emulator at `COSMOS_URI`, e.g. `http://localhost:8081` for one
+    /// started with `--protocol http`. The database and container are created if needed.
+    #[tokio::test]
+    #[ignore = "needs a Cosmos DB emulator at COSMOS_URI"]
+    async fn cosmos_store_keeps_challenges_until_removed() {
+        let uri = std::env::var("COSMOS_URI").expect("COSMOS_URI is not set");
+        let auth_token = AuthorizationToken::primary_key(EMULATOR_KEY).unwrap();
+        let client = CosmosClientBuilder::with_location(CloudLocation::Custom { uri, auth_token }).build();
+        let _ = client.create_database("letsencrypt").await;
+        let db = client.database_client("letsencrypt");
+        let _ = db.create_collection(CONTAINER, "/id").await;
+
+        exercise(&CosmosChallengeStore::new(&db)).await;
+    }
+}
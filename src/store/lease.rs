@@ -0,0 +1,128 @@
+use crate::Environment;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, future::Future, time::Duration};
+use tokio::time::Instant;
+use time::OffsetDateTime;
+use tracing::info;
+use uuid::Uuid;
+
+const CONTAINER: &str = "leases";
+
+/// A time limited lock shared by all instances of the function app. A lease that isn't
+/// released, e.g. because its holder crashed, can be taken over once it expired.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Lease {
+    #[serde(skip)]
+    name: String,
+    holder: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+}
+
+/// Returned when a lease is held by someone else.
+#[derive(Debug)]
+pub struct LeaseTaken(pub String);
+
+impl fmt::Display for LeaseTaken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is already in progress on another instance", self.0)
+    }
+}
+
+impl Error for LeaseTaken {}
+
+impl Lease {
+    /// Takes the lease `name` for `duration`. Returns `None` if someone else holds it.
+    pub async fn acquire(env: &Environment, name: &str, duration: Duration) -> Result<Option<Lease>, Box<dyn Error>> {
+        let lease = Lease {
+            name: name.to_string(),
+            holder: Uuid::new_v4(),
+            expires_at: OffsetDateTime::now_utc() + duration,
+        };
+
+        if env.store.insert(CONTAINER, name, &lease).await? {
+            info!("Acquired lease {}", name);
+            return Ok(Some(lease));
+        }
+
+        // the lease exists, take it over if its holder let it expire
+        let (current, version): (Lease, String) = match env.store.get_versioned(CONTAINER, name).await? {
+            Some(current) => current,
+            // released in the meantime, the next attempt will get it
+            None => return Ok(None),
+        };
+
+        if current.expires_at > OffsetDateTime::now_utc() {
+            info!("Lease {} is held until {}", name, current.expires_at);
+            return Ok(None);
+        }
+
+        if env.store.replace(CONTAINER, name, &lease, &version).await? {
+            info!("Took over expired lease {}", name);
+            Ok(Some(lease))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Runs `task` while holding the lease, renewing it every third of `duration` so it
+    /// doesn't expire while the task takes longer than expected.
+    pub async fn hold<F: Future>(&mut self, env: &Environment, duration: Duration, task: F) -> F::Output {
+        tokio::pin!(task);
+        let mut renewals = tokio::time::interval_at(Instant::now() + duration / 3, duration / 3);
+        loop {
+            tokio::select! {
+                output = &mut task => return output,
+                _ = renewals.tick() => self.renew(env, duration).await,
+            }
+        }
+    }
+
+    /// Extends the lease by `duration` from now, unless it was already taken over.
+    async fn renew(&mut self, env: &Environment, duration: Duration) {
+        let expires_at = OffsetDateTime::now_utc() + duration;
+        let renewed = async {
+            let (current, version) = match env.store.get_versioned::<Lease>(CONTAINER, &self.name).await? {
+                Some(current) => current,
+                None => return Ok(false),
+            };
+            if current.holder != self.holder {
+                return Ok(false);
+            }
+            let lease = Lease { name: self.name.clone(), holder: self.holder, expires_at };
+            env.store.replace(CONTAINER, &self.name, &lease, &version).await
+        }
+        .await
+        .map_err(|error: Box<dyn Error>| error.to_string());
+
+        match renewed {
+            Ok(true) => {
+                self.expires_at = expires_at;
+                info!("Renewed lease {} until {}", self.name, expires_at);
+            }
+            Ok(false) => info!("Lease {} was taken over by another instance", self.name),
+            Err(error) => info!("Could not renew lease {}: {}", self.name, error),
+        }
+    }
+
+    /// Releases the lease unless it was already taken over by someone else.
+    pub async fn release(self, env: &Environment) {
+        // an unreleased lease only blocks others until it expires, so errors are just logged
+        let version = match env.store.get_versioned::<Lease>(CONTAINER, &self.name).await {
+            Ok(Some((current, version))) if current.holder == self.holder => version,
+            Ok(_) => return,
+            Err(error) => {
+                info!("Could not read lease {}: {}", self.name, error);
+                return;
+            }
+        };
+
+        // only the version that was read is deleted, the lease may have expired and been
+        // taken over in between
+        match env.store.delete_versioned(CONTAINER, &self.name, &version).await {
+            Ok(true) => info!("Released lease {}", self.name),
+            Ok(false) => info!("Lease {} was taken over before it could be released", self.name),
+            Err(error) => info!("Could not release lease {}: {}", self.name, error),
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use azure_core::{prelude::{IfMatchCondition, MaxItemCount}, StatusCode};
+use azure_data_cosmos::{prelude::*, CosmosEntity};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, error::Error, fmt, sync::Mutex};
+
+use self::query::{Page, Query};
+
+pub mod challenge;
+pub mod lease;
+pub mod query;
+
+/// Storage for the documents the app keeps besides the certificates themselves. Backed
+/// by Cosmos DB when a connection is configured, otherwise documents only live in memory
+/// which is fine for a single instance but lost on restart.
+///
+/// Every Cosmos container used here is partitioned by `/id`.
+pub enum Store {
+    Memory(Mutex<HashMap<(String, String), (Value, u64)>>),
+    Cosmos(DatabaseClient),
+}
+
+impl fmt::Debug for Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Store::Memory(_) => f.write_str("Store::Memory"),
+            Store::Cosmos(db) => write!(f, "Store::Cosmos({})", db.database_name()),
+        }
+    }
+}
+
+/// An error of the Cosmos DB backend. Cosmos and Key Vault share the same SDK error type,
+/// so storage errors are wrapped to tell them apart.
+#[derive(Debug)]
+pub struct StorageError(pub azure_core::Error);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Storage error: {}", self.0)
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// A document wrapped with the partition key Cosmos needs to store it.
+#[derive(Serialize)]
+struct Entity {
+    #[serde(flatten)]
+    document: Value,
+    #[serde(skip)]
+    id: String,
+}
+
+impl CosmosEntity for Entity {
+    type Entity = String;
+
+    fn partition_key(&self) -> Self::Entity {
+        self.id.clone()
+    }
+}
+
+fn entity<T: Serialize>(id: &str, document: &T) -> Result<Entity, Box<dyn Error>> {
+    let mut document = serde_json::to_value(document)?;
+    document
+        .as_object_mut()
+        .ok_or("only objects can be stored")?
+        .insert("id".to_string(), Value::String(id.to_string()));
+    Ok(Entity { document, id: id.to_string() })
+}
+
+fn has_status(error: &azure_core::Error, status: StatusCode) -> bool {
+    error.as_http_error().is_some_and(|error| error.status() == status)
+}
+
+impl Store {
+    pub fn memory() -> Self {
+        Store::Memory(Mutex::new(HashMap::new()))
+    }
+
+    /// Reads a document, returning `None` if it doesn't exist.
+    pub async fn get<T: DeserializeOwned>(&self, container: &str, id: &str) -> Result<Option<T>, Box<dyn Error>> {
+        Ok(self.get_versioned(container, id).await?.map(|(document, _)| document))
+    }
+
+    /// Reads a document together with its version, which can be passed to `replace` to
+    /// make sure nobody changed the document in between.
+    pub async fn get_versioned<T: DeserializeOwned>(
+        &self,
+        container: &str,
+        id: &str,
+    ) -> Result<Option<(T, String)>, Box<dyn Error>> {
+        let (document, version) = match self {
+            Store::Memory(documents) => {
+                match documents.lock().unwrap().get(&(container.to_string(), id.to_string())) {
+                    Some((document, version)) => (document.clone(), version.to_string()),
+                    None => return Ok(None),
+                }
+            }
+            Store::Cosmos(db) => {
+                let client = db
+                    .collection_client(container.to_string())
+                    .document_client(id, &id)
+                    .map_err(StorageError)?;
+                match client.get_document::<Value>().await.map_err(StorageError)? {
+                    GetDocumentResponse::Found(found) => (found.document.document, found.etag),
+                    GetDocumentResponse::NotFound(_) => return Ok(None),
+                }
+            }
+        };
+
+        Ok(Some((serde_json::from_value(document)?, version)))
+    }
+
+    /// Creates or overwrites a document.
+    pub async fn upsert<T: Serialize>(&self, container: &str, id: &str, document: &T) -> Result<(), Box<dyn Error>> {
+        let entity = entity(id, document)?;
+        match self {
+            Store::Memory(documents) => {
+                let mut documents = documents.lock().unwrap();
+                let key = (container.to_string(), id.to_string());
+                let version = documents.get(&key).map(|(_, version)| version + 1).unwrap_or(0);
+                documents.insert(key, (entity.document, version));
+            }
+            Store::Cosmos(db) => {
+                db.collection_client(container.to_string())
+                    .create_document(entity)
+                    .is_upsert(true)
+                    .await
+                    .map_err(StorageError)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a document unless one with the same id exists. Returns false if it exists.
+    pub async fn insert<T: Serialize>(&self, container: &str, id: &str, document: &T) -> Result<bool, Box<dyn Error>> {
+        let entity = entity(id, document)?;
+        match self {
+            Store::Memory(documents) => {
+                let mut documents = documents.lock().unwrap();
+                let key = (container.to_string(), id.to_string());
+                if documents.contains_key(&key) {
+                    return Ok(false);
+                }
+                documents.insert(key, (entity.document, 0));
+                Ok(true)
+            }
+            Store::Cosmos(db) => {
+                let result = db.collection_client(container.to_string()).create_document(entity).await;
+                match result {
+                    Ok(_) => Ok(true),
+                    Err(error) if has_status(&error, StatusCode::Conflict) => Ok(false),
+                    Err(error) => Err(Box::new(StorageError(error))),
+                }
+            }
+        }
+    }
+
+    /// Overwrites a document if it's still at `version`. Returns false if it was changed in between.
+    pub async fn replace<T: Serialize>(
+        &self,
+        container: &str,
+        id: &str,
+        document: &T,
+        version: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let entity = entity(id, document)?;
+        match self {
+            Store::Memory(documents) => {
+                let mut documents = documents.lock().unwrap();
+                let key = (container.to_string(), id.to_string());
+                match documents.get(&key) {
+                    Some((_, current)) if current.to_string() == version => {
+                        let current = *current;
+                        documents.insert(key, (entity.document, current + 1));
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }
+            Store::Cosmos(db) => {
+                let client = db
+                    .collection_client(container.to_string())
+                    .document_client(id, &id)
+                    .map_err(StorageError)?;
+                let result = client
+                    .replace_document(entity)
+                    .if_match_condition(IfMatchCondition::Match(version.to_string()))
+                    .await;
+                match result {
+                    Ok(_) => Ok(true),
+                    Err(error) if has_status(&error, StatusCode::PreconditionFailed) => Ok(false),
+                    Err(error) if has_status(&error, StatusCode::NotFound) => Ok(false),
+                    Err(error) => Err(Box::new(StorageError(error))),
+                }
+            }
+        }
+    }
+
+    /// Deletes a document, deleting a missing document is not an error.
+    pub async fn delete(&self, container: &str, id: &str) -> Result<(), Box<dyn Error>> {
+        match self {
+            Store::Memory(documents) => {
+                documents.lock().unwrap().remove(&(container.to_string(), id.to_string()));
+            }
+            Store::Cosmos(db) => {
+                let client = db
+                    .collection_client(container.to_string())
+                    .document_client(id, &id)
+                    .map_err(StorageError)?;
+                let result = client.delete_document().await;
+                match result {
+                    Ok(_) => {}
+                    Err(error) if has_status(&error, StatusCode::NotFound) => {}
+                    Err(error) => return Err(Box::new(StorageError(error))),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes a document if it's still at `version`. Returns false if it was changed or
+    /// deleted in between.
+    pub async fn delete_versioned(&self, container: &str, id: &str, version: &str) -> Result<bool, Box<dyn Error>> {
+        match self {
+            Store::Memory(documents) => {
+                let mut documents = documents.lock().unwrap();
+                let key = (container.to_string(), id.to_string());
+                match documents.get(&key) {
+                    Some((_, current)) if current.to_string() == version => {
+                        documents.remove(&key);
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }
+            Store::Cosmos(db) => {
+                let client = db
+                    .collection_client(container.to_string())
+                    .document_client(id, &id)
+                    .map_err(StorageError)?;
+                let result = client
+                    .delete_document()
+                    .if_match_condition(IfMatchCondition::Match(version.to_string()))
+                    .await;
+                match result {
+                    Ok(_) => Ok(true),
+                    Err(error) if has_status(&error, StatusCode::PreconditionFailed) => Ok(false),
+                    Err(error) if has_status(&error, StatusCode::NotFound) => Ok(false),
+                    Err(error) => Err(Box::new(StorageError(error))),
+                }
+            }
+        }
+    }
+
+    /// Reads the documents of a container that match `query`, a page at a time if it has
+    /// a limit.
+    pub async fn query<T: DeserializeOwned>(&self, container: &str, query: &Query) -> Result<Page<T>, Box<dyn Error>> {
+        // one document more than the limit tells whether there is another page
+        let fetch = query.page_size().map(|limit| limit + 1);
+        let documents: Vec<Value> = match self {
+            Store::Memory(documents) => documents
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|((name, _), (document, _))| name == container && query.matches(document))
+                .map(|(_, (document, _))| document.clone())
+                .collect(),
+            Store::Cosmos(db) => {
+                let collection = db.collection_client(container.to_string());
+                // the gateway only sorts within a partition key range, so an ordered query
+                // runs on every range and the results are merged
+                let ranges = match query.is_ordered() {
+                    true => collection
+                        .get_partition_key_ranges()
+                        .await
+                        .map_err(StorageError)?
+                        .partition_key_ranges
+                        .into_iter()
+                        .map(|range| Some(range.id))
+                        .collect(),
+                    false => vec![None],
+                };
+
+                let mut documents = Vec::new();
+                for range in ranges {
+                    let mut request = collection.query_documents(query.to_cosmos()).query_cross_partition(true);
+                    if let Some(range) = range {
+                        request = request.partition_range_id(PartitionRangeId::new(range));
+                    }
+                    if let Some(fetch) = fetch {
+                        request = request.max_item_count(MaxItemCount::new(fetch as i32));
+                    }
+
+                    let mut pages = request.into_stream::<Value>();
+                    let mut found = 0;
+                    while let Some(page) = pages.next().await {
+                        let page = page.map_err(StorageError)?;
+                        found += page.results.len();
+                        documents.extend(page.documents().cloned());
+                        if fetch.is_some_and(|fetch| found >= fetch) {
+                            break;
+                        }
+                    }
+                }
+                documents
+            }
+        };
+
+        let page = query.page(documents);
+        Ok(Page {
+            documents: page.documents.into_iter().map(serde_json::from_value).collect::<Result<_, _>>()?,
+            continuation: page.continuation,
+        })
+    }
+
+    /// Reads every document of a container.
+    pub async fn list<T: DeserializeOwned>(&self, container: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        let documents: Vec<Value> = match self {
+            Store::Memory(documents) => documents
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|((name, _), _)| name == container)
+                .map(|(_, (document, _))| document.clone())
+                .collect(),
+            Store::Cosmos(db) => {
+                let mut pages = db
+                    .collection_client(container.to_string())
+                    .query_documents("SELECT * FROM c")
+                    .query_cross_partition(true)
+                    .into_stream::<Value>();
+
+                let mut documents = Vec::new();
+                while let Some(page) = pages.next().await {
+                    documents.extend(page.map_err(StorageError)?.documents().cloned());
+                }
+                documents
+            }
+        };
+
+        Ok(documents
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()?)
+    }
+}
@@ -0,0 +1,347 @@
+use azure_data_cosmos::prelude::{Param, Query as CosmosQuery};
+use base64::Engine;
+use crate::acme::util::URL_SAFE_ENGINE;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// A condition on a document field. Fields are paths like `updated` or `actor.name` and
+/// always come from the code, only the values may come from users.
+#[derive(Debug, Clone)]
+enum Condition {
+    Equals(&'static str, Value),
+    NotIn(&'static str, Vec<Value>),
+    LessThan(&'static str, Value),
+    GreaterThan(&'static str, Value),
+    /// One of the fields contains the text, ignoring case.
+    Contains(&'static [&'static str], String),
+    /// Comes after the document with the field value and id in the order of a query whose
+    /// ties are ordered by id, descending if the flag is set.
+    After(&'static str, Value, String, bool),
+}
+
+/// Selects documents of a container, run by `Store::query`. Cosmos runs the conditions,
+/// order and limit, the memory store evaluates them the same way.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    conditions: Vec<Condition>,
+    /// The field to sort by and whether the order is descending.
+    order: Option<(&'static str, bool)>,
+    /// Whether documents with the same value in the order field are ordered by id, which
+    /// continuing after the last document of a page needs.
+    by_id: bool,
+    limit: Option<usize>,
+}
+
+/// One page of documents, `continuation` selects the next page if there is one.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub documents: Vec<T>,
+    pub continuation: Option<String>,
+}
+
+fn field<'a>(document: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(document, |value, name| value.get(name))
+}
+
+/// Compares values the way Cosmos does, values of different types don't compare.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+impl Condition {
+    fn matches(&self, document: &Value) -> bool {
+        match self {
+            Condition::Equals(path, value) => field(document, path) == Some(value),
+            Condition::NotIn(path, values) => field(document, path).is_some_and(|field| !values.contains(field)),
+            Condition::LessThan(path, value) => {
+                field(document, path).and_then(|field| compare(field, value)) == Some(Ordering::Less)
+            }
+            Condition::GreaterThan(path, value) => {
+                field(document, path).and_then(|field| compare(field, value)) == Some(Ordering::Greater)
+            }
+            Condition::Contains(paths, text) => paths.iter().any(|path| {
+                field(document, path)
+                    .and_then(Value::as_str)
+                    .is_some_and(|field| field.to_lowercase().contains(&text.to_lowercase()))
+            }),
+            Condition::After(path, value, id, descending) => {
+                let ordering = match field(document, path).and_then(|field| compare(field, value)) {
+                    Some(Ordering::Equal) => field(document, "id").and_then(Value::as_str).map(|own| own.cmp(id.as_str())),
+                    ordering => ordering,
+                };
+                ordering == Some(if *descending { Ordering::Less } else { Ordering::Greater })
+            }
+        }
+    }
+
+    fn sql(&self, params: &mut Vec<Param>) -> String {
+        let mut param = |value: Value| {
+            let name = format!("@p{}", params.len());
+            params.push(Param::new(name.clone(), value));
+            name
+        };
+        match self {
+            Condition::Equals(path, value) => format!("c.{} = {}", path, param(value.clone())),
+            Condition::NotIn(path, values) => {
+                let names: Vec<String> = values.iter().map(|value| param(value.clone())).collect();
+                format!("NOT (c.{} IN ({}))", path, names.join(", "))
+            }
+            Condition::LessThan(path, value) => format!("c.{} < {}", path, param(value.clone())),
+            Condition::GreaterThan(path, value) => format!("c.{} > {}", path, param(value.clone())),
+            Condition::Contains(paths, text) => {
+                let name = param(Value::String(text.clone()));
+                let any: Vec<String> = paths.iter().map(|path| format!("CONTAINS(c.{}, {}, true)", path, name)).collect();
+                format!("({})", any.join(" OR "))
+            }
+            Condition::After(path, value, id, descending) => {
+                let operator = if *descending { "<" } else { ">" };
+                let value = param(value.clone());
+                let id = param(Value::String(id.clone()));
+                format!("(c.{0} {1} {2} OR (c.{0} = {2} AND c.id {1} {3}))", path, operator, value, id)
+            }
+        }
+    }
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Query::default()
+    }
+
+    pub fn equals(mut self, field: &'static str, value: impl Into<Value>) -> Self {
+        self.conditions.push(Condition::Equals(field, value.into()));
+        self
+    }
+
+    pub fn not_in<V: Into<Value>>(mut self, field: &'static str, values: impl IntoIterator<Item = V>) -> Self {
+        self.conditions.push(Condition::NotIn(field, values.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    pub fn less_than(mut self, field: &'static str, value: impl Into<Value>) -> Self {
+        self.conditions.push(Condition::LessThan(field, value.into()));
+        self
+    }
+
+    pub fn greater_than(mut self, field: &'static str, value: impl Into<Value>) -> Self {
+        self.conditions.push(Condition::GreaterThan(field, value.into()));
+        self
+    }
+
+    /// Matches documents where one of `fields` contains `text`, ignoring case.
+    pub fn contains(mut self, fields: &'static [&'static str], text: &str) -> Self {
+        self.conditions.push(Condition::Contains(fields, text.to_string()));
+        self
+    }
+
+    pub fn order_by_desc(mut self, field: &'static str) -> Self {
+        self.order = Some((field, true));
+        self
+    }
+
+    /// Orders documents with the same value in the order field by their id, in the same
+    /// direction. Cosmos needs a composite index on both fields for this.
+    pub fn then_by_id(mut self) -> Self {
+        self.by_id = true;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit.max(1));
+        self
+    }
+
+    /// Continues after the page `continuation` was returned with. Paging goes by the value
+    /// of the order field and the id of the last document, so documents added in the
+    /// meantime don't shift the pages and documents with the same value aren't skipped.
+    pub fn continuation(mut self, continuation: &str) -> Result<Self, String> {
+        let (field, descending) = match self.order {
+            Some(order) if self.by_id => order,
+            _ => return Err("Only queries ordered by a field and the id can be continued".to_string()),
+        };
+        let (value, id): (Value, String) = URL_SAFE_ENGINE
+            .decode(continuation)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| format!("'{}' is not a valid continuation", continuation))?;
+        self.conditions.push(Condition::After(field, value, id, descending));
+        Ok(self)
+    }
+
+    pub(super) fn is_ordered(&self) -> bool {
+        self.order.is_some()
+    }
+
+    pub(super) fn page_size(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub(super) fn matches(&self, document: &Value) -> bool {
+        self.conditions.iter().all(|condition| condition.matches(document))
+    }
+
+    pub(super) fn to_cosmos(&self) -> CosmosQuery {
+        let mut params = Vec::new();
+        let mut sql = "SELECT * FROM c".to_string();
+        if !self.conditions.is_empty() {
+            let conditions: Vec<String> = self.conditions.iter().map(|condition| condition.sql(&mut params)).collect();
+            sql += &format!(" WHERE {}", conditions.join(" AND "));
+        }
+        if let Some((field, descending)) = self.order {
+            let direction = if descending { "DESC" } else { "ASC" };
+            sql += &format!(" ORDER BY c.{} {}", field, direction);
+            if self.by_id {
+                sql += &format!(", c.id {}", direction);
+            }
+        }
+        CosmosQuery::with_params(sql, params)
+    }
+
+    /// Orders and limits the matching documents, which may come from several partitions,
+    /// and returns the page with its continuation.
+    pub(super) fn page(&self, mut documents: Vec<Value>) -> Page<Value> {
+        if let Some((path, descending)) = self.order {
+            documents.sort_by(|a, b| {
+                let mut ordering = match (field(a, path), field(b, path)) {
+                    (Some(a), Some(b)) => compare(a, b).unwrap_or(Ordering::Equal),
+                    (a, b) => a.is_some().cmp(&b.is_some()),
+                };
+                if self.by_id {
+                    ordering = ordering.then_with(|| field(a, "id").and_then(Value::as_str).cmp(&field(b, "id").and_then(Value::as_str)));
+                }
+                if descending { ordering.reverse() } else { ordering }
+            });
+        }
+
+        let mut continuation = None;
+        if let Some(limit) = self.limit {
+            if documents.len() > limit {
+                documents.truncate(limit);
+                continuation = self
+                    .order
+                    .filter(|_| self.by_id)
+                    .and_then(|(path, _)| {
+                        let last = documents.last()?;
+                        Some(serde_json::json!([field(last, path)?, field(last, "id")?]))
+                    })
+                    .map(|key| URL_SAFE_ENGINE.encode(key.to_string()));
+            }
+        }
+        Page { documents, continuation }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn jobs() -> Vec<Value> {
+        (0..5)
+            .map(|index| {
+                json!({
+                    "id": format!("job-{}", index),
+                    "updated": format!("2025-01-0{}T00:00:00Z", index + 1),
+                    "state": if index % 2 == 0 { "done" } else { "validating" },
+                    "actor": { "id": "timer", "name": if index == 4 { Value::from("Jane Doe") } else { Value::Null } },
+                })
+            })
+            .collect()
+    }
+
+    fn ids(page: &Page<Value>) -> Vec<&str> {
+        page.documents.iter().map(|document| document["id"].as_str().unwrap()).collect()
+    }
+
+    fn run(query: &Query) -> Page<Value> {
+        let matching = jobs().into_iter().filter(|job| query.matches(job)).collect();
+        query.page(matching)
+    }
+
+    #[test]
+    fn filters_orders_and_limits() {
+        let query = Query::new().equals("state", "done").order_by_desc("updated").then_by_id().limit(2);
+        let page = run(&query);
+        assert_eq!(ids(&page), ["job-4", "job-2"]);
+        assert!(page.continuation.is_some());
+
+        let next = run(&query.clone().continuation(page.continuation.as_deref().unwrap()).unwrap());
+        assert_eq!(ids(&next), ["job-0"]);
+        assert_eq!(next.continuation, None);
+
+        let query = Query::new().greater_than("updated", "2025-01-02").less_than("updated", "2025-01-05").order_by_desc("updated");
+        let page = run(&query);
+        assert_eq!(ids(&page), ["job-3", "job-2", "job-1"]);
+        assert_eq!(page.continuation, None);
+
+        let query = Query::new().not_in("state", ["done", "failed"]).order_by_desc("updated");
+        assert_eq!(ids(&run(&query)), ["job-3", "job-1"]);
+
+        let query = Query::new().contains(&["actor.id", "actor.name"], "jane");
+        assert_eq!(ids(&run(&query)), ["job-4"]);
+    }
+
+    #[test]
+    fn continues_after_documents_with_the_same_value() {
+        // five events recorded at once and one before them
+        let mut events: Vec<Value> = ["b", "e", "a", "d", "c"]
+            .into_iter()
+            .map(|id| json!({ "id": id, "time": "2025-01-02T00:00:00Z" }))
+            .collect();
+        events.push(json!({ "id": "z", "time": "2025-01-01T00:00:00Z" }));
+        let query = Query::new().order_by_desc("time").then_by_id().limit(2);
+
+        let mut seen = Vec::new();
+        let mut continuation: Option<String> = None;
+        for _ in 0..events.len() {
+            let query = match &continuation {
+                Some(continuation) => query.clone().continuation(continuation).unwrap(),
+                None => query.clone(),
+            };
+            let page = query.page(events.iter().filter(|event| query.matches(event)).cloned().collect());
+            seen.extend(ids(&page).into_iter().map(str::to_string));
+            continuation = page.continuation;
+            if continuation.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen, ["e", "d", "c", "b", "a", "z"]);
+    }
+
+    #[test]
+    fn renders_parameterized_sql() {
+        let query = Query::new()
+            .not_in("state", ["done", "failed"])
+            .contains(&["actor.id", "actor.name"], "jane")
+            .order_by_desc("created")
+            .to_cosmos();
+        assert_eq!(
+            query.query(),
+            "SELECT * FROM c WHERE NOT (c.state IN (@p0, @p1)) AND (CONTAINS(c.actor.id, @p2, true) OR CONTAINS(c.actor.name, @p2, true)) ORDER BY c.created DESC"
+        );
+        assert_eq!(query.params().len(), 3);
+
+        let continuation = URL_SAFE_ENGINE.encode(r#"["2025-01-02T00:00:00Z","b"]"#);
+        let query = Query::new().order_by_desc("time").then_by_id().continuation(&continuation).unwrap().to_cosmos();
+        assert_eq!(
+            query.query(),
+            "SELECT * FROM c WHERE (c.time < @p0 OR (c.time = @p0 AND c.id < @p1)) ORDER BY c.time DESC, c.id DESC"
+        );
+        assert_eq!(query.params().len(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_continuations() {
+        let valid = URL_SAFE_ENGINE.encode(r#"["2025", "job-1"]"#);
+        assert!(Query::new().order_by_desc("updated").then_by_id().continuation("not json").is_err());
+        assert!(Query::new().order_by_desc("updated").then_by_id().continuation(&URL_SAFE_ENGINE.encode("\"2025\"")).is_err());
+        assert!(Query::new().order_by_desc("updated").then_by_id().continuation(&valid).is_ok());
+        assert!(Query::new().order_by_desc("updated").continuation(&valid).is_err());
+        assert!(Query::new().continuation(&valid).is_err());
+    }
+}
@@ -1,21 +1,106 @@
-use crate::acme::cert_new;
-use crate::utils::app_error::AppError;
-use crate::keyvault::domain;
+use crate::acme::{cert_new, rate_limit::RateLimited, resume_jobs};
+use crate::audit::{Action, Actor, AuditEvent};
+use crate::certificate::renewal_job;
+use crate::deploy;
+use crate::events::{self, CloudEvent, EventType};
+use crate::notify::{expiry, Event, Notification, Outbox};
+use crate::utils::{app_error::AppError, principal::{Principal, Role}};
+use crate::renewal::{policy::RenewalPolicy, state::{RenewalState, RenewalStatus}};
+use crate::store::lease::{Lease, LeaseTaken};
 use crate::{
-    keyvault::{cert_name, get_certs},
+    keyvault::{cert_name, get_certs, get_tags, list_tags, set_tags},
     Environment,
 };
-use azure_security_keyvault::prelude::KeyVaultCertificateBaseIdentifier;
-use tracing::info;
-use std::{cmp::Ordering, error::Error};
-use time::{Duration, OffsetDateTime};
-use axum::{http::StatusCode, extract::State, response::{IntoResponse, Response}};
+use futures::StreamExt;
+use serde::Serialize;
+use tracing::{info, instrument::WithSubscriber};
+use std::error::Error;
+use time::OffsetDateTime;
+use axum::{http::StatusCode, extract::State, response::{IntoResponse, Response}, Json};
+use azure_security_keyvault::prelude::KeyVaultGetCertificateResponse;
 
+/// The result of a certificate check run, returned by the check endpoint.
+#[derive(Debug, Default, Serialize)]
+pub struct CheckSummary {
+    /// Set if another instance was already checking the certificates.
+    pub skipped: bool,
+    pub checked: usize,
+    pub renewed: usize,
+    pub failed: usize,
+    pub certificates: Vec<CertificateResult>,
+}
+
+/// What happened to a single certificate during a check run.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    NotDue,
+    /// Due, but waiting for the backoff of a previous failure to pass.
+    BackingOff,
+    /// Due, but another instance is already renewing it.
+    Busy,
+    /// Due, but renewing it now would exceed one of the CA's rate limits.
+    RateLimited,
+    Renewed,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CertificateResult {
+    pub name: String,
+    pub outcome: Outcome,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_renewal: Option<OffsetDateTime>,
+    pub error: Option<String>,
+    pub status: RenewalStatus,
+    pub attempts: u32,
+    pub duration_ms: u128,
+}
+
+/// Runs the check on request of a signed-in user rather than the timer.
+pub async fn manual(principal: Principal, state: State<Environment>) -> Result<Response, AppError> {
+    principal.require(Role::Operator)?;
+    check_as(state, Actor::from(&principal)).await
+}
+
+pub async fn run(state: State<Environment>) -> Result<Response, AppError> {
+    check_as(state, Actor::timer()).await
+}
 
-pub async fn run(State(env): State<Environment>) -> Result<Response, AppError> {
+/// Checks the certificates, renewals are recorded in the audit log as done by `actor`.
+async fn check_as(State(env): State<Environment>, actor: Actor) -> Result<Response, AppError> {
     info!("{}", "Checking certificates");
 
-    let certs = match get_certs(&env).await {
+    // the timer and manual triggers may fire on several instances, one check is enough
+    let mut lease = match Lease::acquire(&env, "check-run", env.lease_duration).await? {
+        Some(lease) => lease,
+        None => {
+            info!("{}", "Certificates are already being checked by another instance");
+            let summary = CheckSummary { skipped: true, ..Default::default() };
+            return Ok((StatusCode::OK, Json(summary)).into_response());
+        }
+    };
+
+    // resuming jobs, deploying and renewing may take longer than the lease lasts
+    let task = async { check(&env, actor).await.map_err(|error| error.to_string()) };
+    let response = lease.hold(&env, env.lease_duration, task).await;
+
+    lease.release(&env).await;
+
+    Ok(response?)
+}
+
+async fn check(env: &Environment, actor: Actor) -> Result<Response, Box<dyn Error>> {
+    // everything the check run notifies about goes out at the end in digest mode
+    let outbox = Outbox::for_check(env);
+
+    // finish interrupted issuances first, a certificate they merge is no longer due
+    resume_jobs(env, &outbox).await;
+    // and deployments that failed or never ran
+    deploy::retry(env).await;
+
+    let certs = match get_certs(env).await {
         Ok(certs) => certs.value,
         Err(error) => {
             info!("{}", error.to_string());
@@ -25,43 +110,228 @@ pub async fn run(State(env): State<Environment>) -> Result<Response, AppError> {
 
     info!("{} certificates found", certs.len());
 
+    let tags = list_tags(env).await?;
+
+    let mut due = Vec::new();
+    let mut expiring = Vec::new();
+    let mut summary = CheckSummary { checked: certs.len(), ..Default::default() };
+
     for cert in certs.iter() {
+        let name = cert_name(cert).ok_or("certificate name not found")?;
         let expires_on = cert.attributes.expires_on.ok_or("expiry date not found")?;
+        let (policy, state) = match tags.get(&name) {
+            Some(tags) => (RenewalPolicy::from_tags(tags, env.renewal_policy), RenewalState::from_tags(tags)),
+            None => (env.renewal_policy, RenewalState::default()),
+        };
+        let renewal_date = policy.renewal_date(cert.attributes.not_before, expires_on);
         let now = OffsetDateTime::now_utc();
-        if expires_on.saturating_sub(Duration::days(30)).cmp(&now) == Ordering::Less {
-            info!("{} expires in less than 30 days.", cert.id);
-            match update_cert(cert, &env).await {
-                Ok(_) => info!("{}", "New Certificate Issued"),
-                Err(error) => {
-                    info!("An error occurred updating certificate: {error:?}")
-                }
-            };
+        if let Some(threshold) = expiry::due(tags.get(&name), expires_on - now, env.notifications.expiry_warning) {
+            expiring.push((name.clone(), expires_on, threshold));
+        }
+        if renewal_date > now {
+            info!("{} next renewal planned for {} ({})", cert.id, renewal_date.date(), policy.describe());
+            summary.certificates.push(CertificateResult {
+                name,
+                outcome: Outcome::NotDue,
+                next_renewal: Some(renewal_date),
+                error: None,
+                status: state.status,
+                attempts: state.attempts,
+                duration_ms: 0,
+            });
+        } else if state.is_backing_off(now) {
+            info!("{} is due for renewal but backing off after {} failed attempts", cert.id, state.attempts);
+            summary.certificates.push(CertificateResult {
+                name,
+                outcome: Outcome::BackingOff,
+                next_renewal: state.next_retry,
+                error: state.last_error,
+                status: state.status,
+                attempts: state.attempts,
+                duration_ms: 0,
+            });
         } else {
-            info!("{} expires in more than 30 days", cert.id);
+            info!("{} is due for renewal ({}).", cert.id, policy.describe());
+            due.push(name);
+        }
+    }
+
+    // renew the due certificates concurrently, each in its own task so a failing or
+    // slow certificate can't take the others down with it
+    let renewals: Vec<CertificateResult> = futures::stream::iter(due)
+        .map(|name| {
+            let env = env.clone();
+            let actor = actor.clone();
+            let outbox = outbox.clone();
+            async move {
+                let task = tokio::spawn(renew(name.clone(), env, actor, outbox).with_current_subscriber());
+                task.await.unwrap_or_else(|error| CertificateResult {
+                    name,
+                    outcome: Outcome::Failed,
+                    next_renewal: None,
+                    error: Some(error.to_string()),
+                    status: RenewalStatus::default(),
+                    attempts: 0,
+                    duration_ms: 0,
+                })
+            }
+        })
+        .buffer_unordered(env.renewal_concurrency)
+        .collect()
+        .await;
+
+    // a certificate that was just renewed doesn't expire anymore
+    let mut warned = Vec::new();
+    for (name, expires_on, threshold) in expiring {
+        let renewed = renewals.iter().any(|result| result.name == name && result.outcome == Outcome::Renewed);
+        if !renewed {
+            outbox.send(env, Notification::new(Event::Expiring, &name).expires_on(Some(expires_on))).await;
+            warned.push((name, threshold));
+        }
+    }
+    outbox.flush(env).await;
+
+    // every threshold is only warned about once, until a renewal removes the tag
+    for (name, threshold) in warned {
+        if let Err(error) = record_warning(&name, threshold, env).await.map_err(|error| error.to_string()) {
+            info!("Could not record the expiry warning of {}: {}", name, error);
+        }
+    }
+
+    for result in renewals {
+        match result.outcome {
+            Outcome::Renewed => summary.renewed += 1,
+            Outcome::Failed | Outcome::TimedOut => summary.failed += 1,
+            Outcome::NotDue | Outcome::BackingOff | Outcome::Busy | Outcome::RateLimited => {}
         }
+        summary.certificates.push(result);
     }
 
-    Ok(StatusCode::OK.into_response())
+    info!("{} certificates renewed, {} failed", summary.renewed, summary.failed);
+
+    Ok((StatusCode::OK, Json(summary)).into_response())
 }
 
+/// Renews a single certificate within the configured deadline.
+pub async fn renew(name: String, env: Environment, actor: Actor, outbox: Outbox) -> CertificateResult {
+    let start = std::time::Instant::now();
+    let mut issued = None;
+
+    let (outcome, error) = match tokio::time::timeout(env.renewal_timeout, update_cert(&name, &env, &outbox)).await {
+        Ok(Ok(cert)) => {
+            info!("{} New Certificate Issued", name);
+            issued = Some(cert);
+            (Outcome::Renewed, None)
+        }
+        Ok(Err(error)) if error.is::<LeaseTaken>() => {
+            info!("{}", error);
+            return CertificateResult {
+                name,
+                outcome: Outcome::Busy,
+                next_renewal: None,
+                error: None,
+                status: RenewalStatus::default(),
+                attempts: 0,
+                duration_ms: start.elapsed().as_millis(),
+            };
+        }
+        // nothing went wrong, the renewal waits until the CA allows it again
+        Ok(Err(error)) if error.is::<RateLimited>() => {
+            info!("{} is due for renewal but {}", name, error);
+            return CertificateResult {
+                name,
+                outcome: Outcome::RateLimited,
+                next_renewal: error.downcast_ref::<RateLimited>().and_then(|limited| limited.retry_after),
+                error: Some(error.to_string()),
+                status: RenewalStatus::default(),
+                attempts: 0,
+                duration_ms: start.elapsed().as_millis(),
+            };
+        }
+        Ok(Err(error)) => {
+            info!("An error occurred updating certificate {}: {error:?}", name);
+            (Outcome::Failed, Some(error.to_string()))
+        }
+        Err(_) => {
+            info!("Renewing certificate {} timed out after {:?}", name, env.renewal_timeout);
+            (Outcome::TimedOut, Some(format!("timed out after {} seconds", env.renewal_timeout.as_secs())))
+        }
+    };
+
+    // the job was dropped along with its notification and event
+    if let (Outcome::TimedOut, Some(error)) = (outcome, &error) {
+        outbox.send(&env, Notification::new(Event::Failed, &name).error(error)).await;
+        events::publish(&env, CloudEvent::new(&env, EventType::RenewalFailed, &name).error(error)).await;
+    }
+
+    let event = AuditEvent::new(actor, Action::Renew, &name);
+    match &error {
+        Some(error) => event.failed(error),
+        None => event.succeeded(issued.as_ref()),
+    }
+    .record(&env)
+    .await;
+
+    // remember failures so the next attempt backs off
+    let mut state = RenewalState::default();
+    if let Some(error) = &error {
+        match record_failure(&name, error, &env).await {
+            Ok(recorded) => state = recorded,
+            Err(error) => info!("Could not record renewal failure of {}: {}", name, error),
+        }
+    }
+
+    CertificateResult {
+        name,
+        outcome,
+        next_renewal: state.next_retry,
+        error,
+        status: state.status,
+        attempts: state.attempts,
+        duration_ms: start.elapsed().as_millis(),
+    }
+}
+
+/// Remembers in the certificate tags that the certificate was warned about expiring at `threshold`.
+async fn record_warning(name: &str, threshold: i64, env: &Environment) -> Result<(), Box<dyn Error>> {
+    let mut tags = get_tags(env, name).await?;
+    tags.insert(expiry::NOTIFIED_TAG.to_string(), threshold.to_string());
+    set_tags(env, name, &tags).await
+}
+
+/// Persists a failed renewal attempt in the certificate tags and returns the new state.
+async fn record_failure(name: &str, error: &str, env: &Environment) -> Result<RenewalState, Box<dyn Error>> {
+    let mut tags = get_tags(env, name).await?;
+    let mut state = RenewalState::from_tags(&tags);
+    state.record_failure(error, &env.retry_policy);
+    state.write_tags(&mut tags);
+    set_tags(env, name, &tags).await?;
+
+    info!("Renewal of {} failed {} times, status {:?}, next retry {:?}", name, state.attempts, state.status, state.next_retry);
+
+    Ok(state)
+}
+
+/// Renews a certificate, the outcome is sent to its subscribers through `outbox`.
 pub async fn update_cert(
-    cert_base: &KeyVaultCertificateBaseIdentifier,
+    cert_name: &str,
     env: &Environment,
-) -> Result<(), Box<dyn Error>> {
-    let cert_name = cert_name(cert_base).ok_or("certificate name not found")?;
-    let cert = env.certificate_client.get(cert_name.clone()).await?;
-    let domain = domain(&cert).ok_or("could not extract domain from subject")?;
-
-    // remove pending operation if exists
-    match env.certificate_client.get_operation(&cert_name).await {
-        Ok(_) => {
-            info!("Pending certificate operation exists");
-            let _ = env.certificate_client.delete_operation(&cert_name).await?;
-            info!("Pending certificate operation deleted");
+    outbox: &Outbox,
+) -> Result<KeyVaultGetCertificateResponse, Box<dyn Error>> {
+    // a renewal that fails before its job runs is notified here, running jobs notify themselves
+    // and publish their own events
+    let job = match renewal_job(env, cert_name).await {
+        Ok(job) => Ok(job),
+        Err(error) if error.is::<RateLimited>() => return Err(error),
+        Err(error) => Err(error.to_string()),
+    };
+    let mut job = match job {
+        Ok(job) => job,
+        Err(error) => {
+            outbox.send(env, Notification::new(Event::Failed, cert_name).error(&error)).await;
+            events::publish(env, CloudEvent::new(env, EventType::RenewalFailed, cert_name).error(&error)).await;
+            return Err(error.into());
         }
-        Err(_) => info!("No certificate operation pending"),
     };
-
-    cert_new(domain, &cert_name, env).await?;
-    Ok(())
+    cert_new(&mut job, env, outbox).await
 }
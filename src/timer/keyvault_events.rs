@@ -0,0 +1,259 @@
+use super::check::renew;
+use crate::audit::Actor;
+use crate::keyvault::{self, get_tags};
+use crate::notify::Outbox;
+use crate::renewal::state::RenewalState;
+use crate::utils::app_error::AppError;
+use crate::Environment;
+use axum::{extract::{rejection::JsonRejection, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+use tracing::{info, instrument::WithSubscriber};
+
+/// Event Grid sends this before delivering anything, the endpoint has to echo the code.
+const SUBSCRIPTION_VALIDATION: &str = "Microsoft.EventGrid.SubscriptionValidationEvent";
+/// The Key Vault events that make a certificate due for renewal right away.
+const RENEWAL_EVENTS: [&str; 2] = ["Microsoft.KeyVault.CertificateNearExpiry", "Microsoft.KeyVault.CertificateExpired"];
+
+/// An event in the Event Grid schema, or the CloudEvents schema which names the type differently.
+#[derive(Debug, Deserialize)]
+pub struct GridEvent {
+    #[serde(rename = "eventType", alias = "type")]
+    event_type: String,
+    #[serde(default)]
+    data: Value,
+}
+
+/// Event Grid delivers events in batches, CloudEvents may come one at a time.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Delivery {
+    Batch(Vec<GridEvent>),
+    Single(GridEvent),
+}
+
+/// The data of Key Vault's certificate events.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CertificateEventData {
+    vault_name: String,
+    object_name: String,
+    version: Option<String>,
+}
+
+/// Returns the certificates in the vault `vault_name` the renewal events are about, each
+/// once, Event Grid may report a certificate several times in a batch.
+fn due_certificates(events: Vec<GridEvent>, vault_name: &str) -> Vec<CertificateEventData> {
+    let mut due: Vec<CertificateEventData> = Vec::new();
+    for event in events {
+        if !RENEWAL_EVENTS.contains(&event.event_type.as_str()) {
+            continue;
+        }
+        match serde_json::from_value::<CertificateEventData>(event.data) {
+            // a subscription on another vault was pointed here by mistake, its certificates aren't ours
+            Ok(data) if !data.vault_name.eq_ignore_ascii_case(vault_name) => {
+                info!("Ignoring {} for {} in vault {}", event.event_type, data.object_name, data.vault_name);
+            }
+            Ok(data) if due.iter().any(|other| other.object_name == data.object_name) => {}
+            Ok(data) => {
+                info!("{} for {}", event.event_type, data.object_name);
+                due.push(data);
+            }
+            Err(error) => info!("Ignoring {} with unexpected data: {}", event.event_type, error),
+        }
+    }
+    due
+}
+
+/// Answers the CloudEvents webhook handshake, Event Grid asks before delivering events in
+/// the CloudEvents schema.
+pub async fn handshake(headers: HeaderMap) -> Response {
+    match headers.get("webhook-request-origin") {
+        Some(origin) => (StatusCode::OK, [("webhook-allowed-origin", origin.clone())]).into_response(),
+        None => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+/// Receives the events of a Key Vault Event Grid subscription and renews the certificates
+/// Key Vault reports as near expiry or expired. The renewals run in the background, Event
+/// Grid only waits 30 seconds for an answer and retries otherwise.
+pub async fn run(
+    State(env): State<Environment>,
+    body: Result<Json<Delivery>, JsonRejection>,
+) -> Result<Response, AppError> {
+    let Json(delivery) = body.map_err(|rejection| AppError::Validation(rejection.body_text()))?;
+    let events = match delivery {
+        Delivery::Batch(events) => events,
+        Delivery::Single(event) => vec![event],
+    };
+
+    if let Some(validation) = events.iter().find(|event| event.event_type == SUBSCRIPTION_VALIDATION) {
+        let code = validation
+            .data
+            .get("validationCode")
+            .and_then(|code| code.as_str())
+            .ok_or_else(|| AppError::Validation("The subscription validation has no code".to_string()))?;
+        info!("Validated the Event Grid subscription");
+        return Ok(Json(json!({ "validationResponse": code })).into_response());
+    }
+
+    let vault_name = env.keyvault_url.host_str().and_then(|host| host.split('.').next()).unwrap_or_default();
+    let due = due_certificates(events, vault_name);
+
+    let task_env = env.clone();
+    tokio::spawn(
+        async move {
+            for data in due {
+                // the event may be about a version that was renewed in the meantime
+                let current = task_env.certificate_client.get(&data.object_name).await.map_err(|error| error.to_string());
+                match current.map(|cert| keyvault::version(&cert)) {
+                    Ok(current) if data.version.is_some() && current != data.version => {
+                        info!("{} was already renewed, the event is about an older version", data.object_name);
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        info!("Could not get certificate {}: {}", data.object_name, error);
+                        continue;
+                    }
+                }
+
+                // failed renewals back off the same way the timer does
+                let tags = get_tags(&task_env, &data.object_name).await.map_err(|error| error.to_string());
+                match tags.map(|tags| RenewalState::from_tags(&tags)) {
+                    Ok(state) if state.is_backing_off(OffsetDateTime::now_utc()) => {
+                        info!("{} is backing off after {} failed attempts", data.object_name, state.attempts);
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(error) => info!("Could not get the renewal state of {}: {}", data.object_name, error),
+                }
+
+                let result = renew(data.object_name, task_env.clone(), Actor::key_vault(), Outbox::Immediate).await;
+                info!("Renewal of {} after a Key Vault event: {:?}", result.name, result.outcome);
+            }
+        }
+        .with_current_subscriber(),
+    );
+
+    Ok(StatusCode::OK.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_environment;
+    use axum::{body::to_bytes, http::HeaderValue};
+    use std::sync::Arc;
+
+    /// A Key Vault event as Event Grid delivers it in its own schema.
+    fn grid_event(event_type: &str, vault: &str, name: &str, version: &str) -> Value {
+        json!({
+            "id": "00000000-0000-0000-0000-000000000001",
+            "topic": format!("/subscriptions/sub/resourceGroups/rg/providers/Microsoft.KeyVault/vaults/{}", vault),
+            "subject": name,
+            "eventType": event_type,
+            "eventTime": "2030-01-01T04:00:00Z",
+            "data": {
+                "Id": format!("https://{}.vault.azure.net/certificates/{}/{}", vault, name, version),
+                "VaultName": vault,
+                "ObjectType": "Certificate",
+                "ObjectName": name,
+                "Version": version,
+                "NBF": 1_700_000_000,
+                "EXP": 1_900_000_000,
+            },
+            "dataVersion": "1",
+            "metadataVersion": "1",
+        })
+    }
+
+    fn delivery(events: Value) -> Delivery {
+        serde_json::from_value(events).unwrap()
+    }
+
+    fn events(delivery: Delivery) -> Vec<GridEvent> {
+        match delivery {
+            Delivery::Batch(events) => events,
+            Delivery::Single(event) => vec![event],
+        }
+    }
+
+    async fn body(response: Response) -> Value {
+        serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn echoes_the_subscription_validation() {
+        let env = Arc::new(test_environment());
+        let validation = delivery(json!([{
+            "id": "2d1781af-3a4c-4d7c-bd0c-e34b19da4e66",
+            "topic": "/subscriptions/sub/resourceGroups/rg/providers/Microsoft.KeyVault/vaults/test",
+            "subject": "",
+            "data": {
+                "validationCode": "512d38b6-c7b8-40c8-89fe-f46f9e9622b6",
+                "validationUrl": "https://rp-eastus2.eventgrid.azure.net:553/eventsubscriptions/certificates/validate?id=512d38b6",
+            },
+            "eventType": SUBSCRIPTION_VALIDATION,
+            "eventTime": "2030-01-01T04:00:00Z",
+            "metadataVersion": "1",
+            "dataVersion": "2",
+        }]));
+        let response = run(State(env.clone()), Ok(Json(validation))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, json!({ "validationResponse": "512d38b6-c7b8-40c8-89fe-f46f9e9622b6" }));
+
+        let without_code = delivery(json!([{ "eventType": SUBSCRIPTION_VALIDATION, "data": {} }]));
+        assert!(run(State(env), Ok(Json(without_code))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_the_cloudevents_handshake() {
+        let mut headers = HeaderMap::new();
+        headers.insert("webhook-request-origin", HeaderValue::from_static("eventemitter.example.com"));
+        let response = handshake(headers).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["webhook-allowed-origin"], "eventemitter.example.com");
+
+        assert_eq!(handshake(HeaderMap::new()).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn accepts_events_of_other_vaults_without_renewing() {
+        let env = Arc::new(test_environment());
+        let other = delivery(json!([grid_event("Microsoft.KeyVault.CertificateNearExpiry", "other", "www-example-com", "v1")]));
+        assert_eq!(run(State(env), Ok(Json(other))).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn renews_each_certificate_of_the_vault_once() {
+        let batch = delivery(json!([
+            grid_event("Microsoft.KeyVault.CertificateNearExpiry", "test", "www-example-com", "v1"),
+            grid_event("Microsoft.KeyVault.CertificateExpired", "TEST", "www-example-com", "v1"),
+            grid_event("Microsoft.KeyVault.CertificateNearExpiry", "other", "api-example-com", "v1"),
+            grid_event("Microsoft.KeyVault.CertificateNewVersionCreated", "test", "shop-example-com", "v2"),
+            { "eventType": "Microsoft.KeyVault.CertificateExpired", "data": { "unexpected": true } },
+            grid_event("Microsoft.KeyVault.CertificateExpired", "test", "mail-example-com", "v3"),
+        ]));
+        let due = due_certificates(events(batch), "test");
+        let names: Vec<&str> = due.iter().map(|data| data.object_name.as_str()).collect();
+        assert_eq!(names, ["www-example-com", "mail-example-com"]);
+        assert_eq!(due[1].version.as_deref(), Some("v3"));
+    }
+
+    #[test]
+    fn reads_single_cloud_events() {
+        let cloud_event = delivery(json!({
+            "specversion": "1.0",
+            "id": "00000000-0000-0000-0000-000000000002",
+            "source": "/subscriptions/sub/resourceGroups/rg/providers/Microsoft.KeyVault/vaults/test",
+            "subject": "www-example-com",
+            "type": "Microsoft.KeyVault.CertificateNearExpiry",
+            "time": "2030-01-01T04:00:00Z",
+            "data": { "VaultName": "test", "ObjectType": "Certificate", "ObjectName": "www-example-com", "Version": "v1" },
+        }));
+        assert!(matches!(cloud_event, Delivery::Single(_)));
+        assert_eq!(due_certificates(events(cloud_event), "test").len(), 1);
+    }
+}
@@ -1 +1,2 @@
 pub mod check;
+pub mod keyvault_events;
@@ -0,0 +1,366 @@
+use super::{app_error::AppError, principal::Principal};
+use crate::{keyvault::list_api_keys, Environment};
+use axum::http::{header, HeaderMap, StatusCode};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, error::Error, sync::RwLock, time::{Duration, Instant}};
+use url::Url;
+
+/// The header api clients send their api key in.
+const API_KEY_HEADER: &str = "x-api-key";
+/// How long the signing keys are used before they are fetched again.
+const JWKS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Tokens signed with an unknown key fetch the keys again, but not more often than this,
+/// so made up tokens can't keep the app busy fetching.
+const JWKS_REFRESH: Duration = Duration::from_secs(5 * 60);
+/// How long api keys are cached, a new or disabled key takes effect after this.
+const API_KEYS_TTL: Duration = Duration::from_secs(5 * 60);
+
+fn unauthorized(message: impl Into<String>) -> AppError {
+    AppError::Auth { status: StatusCode::UNAUTHORIZED, message: message.into() }
+}
+
+/// Validates Entra ID access tokens against the tenant's signing keys.
+#[derive(Debug)]
+pub struct TokenValidator {
+    jwks_url: Url,
+    audiences: Vec<String>,
+    issuers: Vec<String>,
+    jwks: RwLock<Option<(Instant, JwkSet)>>,
+}
+
+/// The claims of an access token the app looks at.
+#[derive(Debug, Deserialize)]
+struct TokenClaims {
+    sub: String,
+    /// The object id of the user or service principal.
+    oid: Option<String>,
+    name: Option<String>,
+    /// The client id of the caller, in v2 and v1 tokens.
+    azp: Option<String>,
+    appid: Option<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl TokenValidator {
+    pub fn new(jwks_url: Url, audiences: Vec<String>, issuers: Vec<String>) -> Self {
+        TokenValidator { jwks_url, audiences, issuers, jwks: RwLock::new(None) }
+    }
+
+    async fn fetch_jwks(&self) -> Result<JwkSet, Box<dyn Error>> {
+        let jwks = reqwest::get(self.jwks_url.clone()).await?.error_for_status()?.json().await?;
+        Ok(jwks)
+    }
+
+    /// Returns the signing key with the id `kid`, fetching the keys if they are stale or
+    /// the key is new.
+    async fn key(&self, kid: &str) -> Result<Option<DecodingKey>, AppError> {
+        let cached = match &*self.jwks.read().unwrap() {
+            Some((fetched, jwks)) if fetched.elapsed() < JWKS_TTL => match jwks.find(kid) {
+                Some(jwk) => return Ok(Some(DecodingKey::from_jwk(jwk)?)),
+                None => Some(*fetched),
+            },
+            _ => None,
+        };
+        if cached.is_some_and(|fetched| fetched.elapsed() < JWKS_REFRESH) {
+            return Ok(None);
+        }
+
+        let jwks = match self.fetch_jwks().await {
+            Ok(jwks) => jwks,
+            Err(error) => {
+                let message = format!("Could not fetch the token signing keys: {}", error);
+                return Err(AppError::Auth { status: StatusCode::BAD_GATEWAY, message });
+            }
+        };
+        let key = jwks.find(kid).map(DecodingKey::from_jwk).transpose()?;
+        *self.jwks.write().unwrap() = Some((Instant::now(), jwks));
+        Ok(key)
+    }
+
+    async fn validate(&self, env: &Environment, token: &str) -> Result<Principal, AppError> {
+        let token_header = decode_header(token).map_err(|error| unauthorized(format!("Invalid bearer token: {}", error)))?;
+        let kid = token_header.kid.ok_or_else(|| unauthorized("The bearer token has no key id"))?;
+        let key = self
+            .key(&kid)
+            .await?
+            .ok_or_else(|| unauthorized("The bearer token is signed with an unknown key"))?;
+
+        // Entra ID signs access tokens with RS256, anything else is not from Entra ID
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&self.audiences);
+        validation.set_issuer(&self.issuers);
+        let claims = decode::<TokenClaims>(token, &key, &validation)
+            .map_err(|error| unauthorized(format!("Invalid bearer token: {}", error)))?
+            .claims;
+
+        let values = [claims.roles, claims.groups].concat();
+        Ok(Principal {
+            id: claims.oid.unwrap_or(claims.sub),
+            name: claims.name.or(claims.azp).or(claims.appid),
+            role: env.roles.role_of(&values),
+        })
+    }
+}
+
+/// The callers of the api keys, keyed by the SHA-256 hash of the key so the keys
+/// themselves aren't kept around.
+type KeyPrincipals = HashMap<Vec<u8>, Principal>;
+
+/// Api keys stored as Key Vault secrets, found by their name prefix.
+#[derive(Debug)]
+pub struct ApiKeys {
+    prefix: String,
+    keys: RwLock<Option<(Instant, KeyPrincipals)>>,
+}
+
+impl ApiKeys {
+    pub fn new(prefix: String) -> Self {
+        ApiKeys { prefix, keys: RwLock::new(None) }
+    }
+
+    async fn validate(&self, env: &Environment, key: &str) -> Result<Principal, AppError> {
+        let hash = Sha256::digest(key.as_bytes()).to_vec();
+
+        if let Some((fetched, keys)) = &*self.keys.read().unwrap() {
+            if fetched.elapsed() < API_KEYS_TTL {
+                return keys.get(&hash).cloned().ok_or_else(|| unauthorized("Invalid api key"));
+            }
+        }
+
+        // the app roles of a key are in its roles tag, separated by commas
+        let mut keys = HashMap::new();
+        for api_key in list_api_keys(env, &self.prefix).await? {
+            let values: Vec<String> = match api_key.tags.get("roles") {
+                Some(roles) => roles.split(',').map(|role| role.trim().to_string()).collect(),
+                None => Vec::new(),
+            };
+            let principal = Principal {
+                id: format!("api-key:{}", api_key.name),
+                name: Some(api_key.name),
+                role: env.roles.role_of(&values),
+            };
+            keys.insert(Sha256::digest(api_key.value.as_bytes()).to_vec(), principal);
+        }
+
+        let principal = keys.get(&hash).cloned();
+        *self.keys.write().unwrap() = Some((Instant::now(), keys));
+        principal.ok_or_else(|| unauthorized("Invalid api key"))
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+}
+
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers.get(API_KEY_HEADER).and_then(|key| key.to_str().ok())
+}
+
+/// Returns true if the request carries a bearer token or api key. Browsers never add
+/// those on their own, so such requests can't be forged by another site.
+pub fn has_credentials(headers: &HeaderMap) -> bool {
+    bearer_token(headers).is_some() || api_key(headers).is_some()
+}
+
+/// Authenticates api clients by their bearer token or api key. None if the request has
+/// neither, it is then up to App Service authentication.
+pub async fn authenticate(env: &Environment, headers: &HeaderMap) -> Option<Result<Principal, AppError>> {
+    if let Some(token) = bearer_token(headers) {
+        return Some(match &env.token_validator {
+            Some(validator) => validator.validate(env, token).await,
+            None => Err(unauthorized("Bearer tokens aren't accepted, API_AUDIENCE isn't configured")),
+        });
+    }
+    if let Some(key) = api_key(headers) {
+        return Some(env.api_keys.validate(env, key).await);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_environment, EnvironmentInner};
+    use axum::{extract::{Path, State}, routing::get, Json, Router};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+    const KEY: &[u8] = include_bytes!("testdata/token.pem");
+    const JWKS: &str = include_str!("testdata/jwks.json");
+    const AUDIENCE: &str = "api://certificates";
+    const ISSUER: &str = "https://login.microsoftonline.com/tenant/v2.0";
+
+    /// Serves `routes` on a free local port and returns its url.
+    async fn serve(routes: Router) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", listener.local_addr().unwrap().port())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, routes).await });
+        url
+    }
+
+    /// Starts a JWKS endpoint with the test key and returns a validator using it, with the
+    /// number of times the keys were fetched.
+    async fn validator() -> (TokenValidator, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let routes = Router::new()
+            .route(
+                "/keys",
+                get(|State(fetches): State<Arc<AtomicUsize>>| async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    Json(serde_json::from_str::<Value>(JWKS).unwrap())
+                }),
+            )
+            .with_state(fetches.clone());
+        let url = serve(routes).await.join("keys").unwrap();
+        (TokenValidator::new(url, vec![AUDIENCE.to_string()], vec![ISSUER.to_string()]), fetches)
+    }
+
+    fn claims() -> Value {
+        json!({
+            "sub": "subject",
+            "oid": "00000000-0000-0000-0000-000000000042",
+            "azp": "deploy-pipeline",
+            "aud": AUDIENCE,
+            "iss": ISSUER,
+            "exp": time::OffsetDateTime::now_utc().unix_timestamp() + 3600,
+            "roles": ["Operator"],
+        })
+    }
+
+    fn token(kid: &str, claims: &Value) -> String {
+        let header = Header { kid: Some(kid.to_string()), ..Header::new(Algorithm::RS256) };
+        encode(&header, claims, &EncodingKey::from_rsa_pem(KEY).unwrap()).unwrap()
+    }
+
+    fn rejected(result: Result<Principal, AppError>) -> StatusCode {
+        match result {
+            Ok(principal) => panic!("{:?} was accepted", principal),
+            Err(AppError::Auth { status, .. }) => status,
+            Err(error) => panic!("{:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_tokens_of_the_tenant() {
+        let env = Arc::new(test_environment());
+        let (validator, _) = validator().await;
+        let principal = validator.validate(&env, &token("test-key", &claims())).await.unwrap();
+        assert_eq!(principal.id, "00000000-0000-0000-0000-000000000042");
+        assert_eq!(principal.display_name(), "deploy-pipeline");
+        assert_eq!(principal.role, Some(crate::utils::principal::Role::Operator));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_tokens() {
+        let env = Arc::new(test_environment());
+        let (validator, _) = validator().await;
+        let with = |key: &str, value: Value| {
+            let mut claims = claims();
+            claims[key] = value;
+            token("test-key", &claims)
+        };
+        let expired = time::OffsetDateTime::now_utc().unix_timestamp() - 3600;
+        let hs256 = encode(
+            &Header { kid: Some("test-key".to_string()), ..Header::new(Algorithm::HS256) },
+            &claims(),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        for token in [
+            with("aud", json!("api://other")),
+            with("iss", json!("https://login.microsoftonline.com/other/v2.0")),
+            with("exp", json!(expired)),
+            hs256,
+            token("unknown-key", &claims()),
+            "not a token".to_string(),
+        ] {
+            assert_eq!(rejected(validator.validate(&env, &token).await), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_the_keys_again_once_for_unknown_keys() {
+        let env = Arc::new(test_environment());
+        let (validator, fetches) = validator().await;
+        // keys fetched a while ago, before the tenant added the test key
+        let stale = Instant::now().checked_sub(JWKS_REFRESH * 2).unwrap();
+        *validator.jwks.write().unwrap() = Some((stale, JwkSet { keys: Vec::new() }));
+
+        assert!(validator.validate(&env, &token("test-key", &claims())).await.is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        for _ in 0..3 {
+            rejected(validator.validate(&env, &token("unknown-key", &claims())).await);
+        }
+        assert!(validator.validate(&env, &token("test-key", &claims())).await.is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    type Fetched = Arc<std::sync::Mutex<Vec<String>>>;
+
+    /// Answers like Key Vault with a few api key secrets and records which values were read.
+    async fn key_vault() -> (Environment, Fetched) {
+        let fetched = Fetched::default();
+        let list = |State(_): State<Fetched>| async move {
+            let secret = |name: &str, enabled: bool, exp: Option<i64>, roles: Option<&str>| {
+                json!({
+                    "id": format!("https://test.vault.azure.net/secrets/{}", name),
+                    "attributes": { "enabled": enabled, "exp": exp },
+                    "tags": roles.map(|roles| json!({ "roles": roles })),
+                })
+            };
+            Json(json!({
+                "value": [
+                    secret("api-key-deploy", true, None, Some("Operator, Bogus")),
+                    secret("api-key-bogus", true, None, Some("Bogus,,")),
+                    secret("api-key-untagged", true, None, None),
+                    secret("api-key-disabled", false, None, Some("Admin")),
+                    secret("api-key-expired", true, Some(1_000_000_000), Some("Admin")),
+                    secret("database-password", true, None, Some("Admin")),
+                ],
+                "nextLink": null,
+            }))
+        };
+        let value = |State(fetched): State<Fetched>, Path(name): Path<String>| async move {
+            fetched.lock().unwrap().push(name.clone());
+            Json(json!({ "value": format!("value-of-{}", name) }))
+        };
+        let routes = Router::new()
+            .route("/secrets", get(list))
+            .route("/secrets/:name", get(value))
+            .with_state(fetched.clone());
+        let env = Arc::new(EnvironmentInner { keyvault_url: serve(routes).await, ..test_environment() });
+        (env, fetched)
+    }
+
+    #[tokio::test]
+    async fn maps_the_roles_of_api_keys() {
+        let (env, _) = key_vault().await;
+        let principal = env.api_keys.validate(&env, "value-of-api-key-deploy").await.unwrap();
+        assert_eq!(principal.id, "api-key:api-key-deploy");
+        assert_eq!(principal.role, Some(crate::utils::principal::Role::Operator));
+
+        // keys without a known role authenticate, but may do nothing
+        for key in ["value-of-api-key-bogus", "value-of-api-key-untagged"] {
+            assert_eq!(env.api_keys.validate(&env, key).await.unwrap().role, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_disabled_and_expired_keys() {
+        let (env, fetched) = key_vault().await;
+        for key in ["value-of-api-key-disabled", "value-of-api-key-expired", "value-of-database-password", "made up"] {
+            assert_eq!(rejected(env.api_keys.validate(&env, key).await), StatusCode::UNAUTHORIZED);
+        }
+        // the values of the ignored secrets are never read, and the keys are cached
+        assert_eq!(*fetched.lock().unwrap(), ["api-key-deploy", "api-key-bogus", "api-key-untagged"]);
+    }
+}
@@ -1,25 +1,192 @@
-use axum::{http::StatusCode, response::{IntoResponse, Response}};
+use crate::{
+    acme::{chain::ChainError, rate_limit::RateLimited, self_check::SelfCheckError, util::AcmeError},
+    certificate::hostname::DomainError,
+    store::{lease::LeaseTaken, StorageError},
+};
+use axum::{http::{header, StatusCode}, response::{IntoResponse, Response}, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
 
-pub struct AppError(Box<dyn std::error::Error>);
+/// The errors handlers answer with. Every kind maps to its own status code and renders
+/// as `application/problem+json`, the `error_pages` layer turns it into a page for browsers.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request is invalid, e.g. a missing field or a malformed domain.
+    Validation(String),
+    NotFound(String),
+    /// Someone else is working on the same thing, e.g. another instance issuing the certificate.
+    Conflict(String),
+    /// The caller isn't allowed in, or the app couldn't authenticate itself against Azure.
+    Auth { status: StatusCode, message: String },
+    /// Key Vault refused a request, `status` is what Key Vault answered.
+    KeyVault { status: Option<StatusCode>, message: String },
+    /// The ACME server refused a request or the issued certificate is unusable.
+    Acme { error_type: Option<String>, status: StatusCode, message: String },
+    Storage(String),
+    Internal(String),
+}
+
+/// An RFC 9457 problem details document.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    /// Which part of the app failed, e.g. `key_vault` or `acme`.
+    #[schema(value_type = String)]
+    pub error: &'static str,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Auth { status, .. } => *status,
+            AppError::KeyVault { status, .. } => match status {
+                Some(StatusCode::NOT_FOUND) => StatusCode::NOT_FOUND,
+                Some(StatusCode::CONFLICT) => StatusCode::CONFLICT,
+                Some(StatusCode::TOO_MANY_REQUESTS) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            AppError::Acme { status, .. } => *status,
+            AppError::Storage(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Auth { .. } => "auth",
+            AppError::KeyVault { .. } => "key_vault",
+            AppError::Acme { .. } => "acme",
+            AppError::Storage(_) => "storage",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::Validation(message)
+            | AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::Auth { message, .. }
+            | AppError::KeyVault { message, .. }
+            | AppError::Acme { message, .. }
+            | AppError::Storage(message)
+            | AppError::Internal(message) => message,
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let status = self.status();
+        let problem_type = match self {
+            AppError::Acme { error_type: Some(error_type), .. } => error_type.clone(),
+            _ => "about:blank".to_string(),
+        };
+
+        Problem {
+            problem_type,
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: self.message().to_string(),
+            error: self.kind(),
+        }
+    }
+}
+
+/// Maps the status of an ACME error to the status the app answers with. Rejected
+/// identifiers are the caller's fault, rate limits are passed on, everything else is
+/// the CA failing.
+fn acme_status(error: &AcmeError) -> StatusCode {
+    match error.kind() {
+        "rateLimited" => StatusCode::TOO_MANY_REQUESTS,
+        "rejectedIdentifier" | "unsupportedIdentifier" | "caa" | "dns" => StatusCode::BAD_REQUEST,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        tracing::error!("{}", self.0);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
+        let problem = self.problem();
+        match self.status().is_server_error() {
+            true => tracing::error!("{}: {}", self.kind(), self.message()),
+            false => tracing::info!("{}: {}", self.kind(), self.message()),
+        }
+
+        let mut response = (
+            self.status(),
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            Json(problem.clone()),
         )
-            .into_response()
+            .into_response();
+        // picked up by the error_pages layer to render a page instead
+        response.extensions_mut().insert(problem);
+        response
     }
 }
 
-// This enables using `?` on functions
+// This enables using `?` on functions, the error is sorted into its kind by its type
 impl<E> From<E> for AppError
 where
     E: Into<Box<dyn std::error::Error>>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let err = err.into();
+        let message = err.to_string();
+
+        if let Some(error) = err.downcast_ref::<AcmeError>() {
+            return AppError::Acme {
+                error_type: Some(error.error_type.clone()).filter(|error_type| !error_type.is_empty()),
+                status: acme_status(error),
+                message,
+            };
+        }
+        if err.is::<SelfCheckError>() || err.is::<ChainError>() {
+            return AppError::Acme { error_type: None, status: StatusCode::BAD_GATEWAY, message };
+        }
+        if err.is::<DomainError>() {
+            return AppError::Validation(message);
+        }
+        // the app's own rate limit budget refuses what the CA would refuse
+        if err.is::<RateLimited>() {
+            return AppError::Acme { error_type: None, status: StatusCode::TOO_MANY_REQUESTS, message };
+        }
+        if err.is::<LeaseTaken>() {
+            return AppError::Conflict(message);
+        }
+        if err.is::<StorageError>() {
+            return AppError::Storage(message);
+        }
+        // everything else from the Azure SDK comes from Key Vault
+        if let Some(error) = err.downcast_ref::<azure_core::Error>() {
+            if let azure_core::error::ErrorKind::Credential = error.kind() {
+                return AppError::Auth { status: StatusCode::BAD_GATEWAY, message };
+            }
+            let status = error
+                .as_http_error()
+                .and_then(|error| StatusCode::from_u16(u16::from(error.status())).ok());
+            return AppError::KeyVault { status, message };
+        }
+        // the Key Vault REST calls the SDK doesn't cover
+        if let Some(error) = err.downcast_ref::<reqwest::Error>() {
+            let is_key_vault = error
+                .url()
+                .and_then(|url| url.host_str())
+                .is_some_and(|host| host.contains(".vault."));
+            if is_key_vault {
+                let status = error.status().and_then(|status| StatusCode::from_u16(status.as_u16()).ok());
+                return AppError::KeyVault { status, message };
+            }
+        }
+
+        AppError::Internal(message)
     }
-}
\ No newline at end of file
+}
@@ -1,36 +1,333 @@
-use axum::{extract::Request, middleware::Next, response::Response};
-#[cfg(not(debug_assertions))]
-use std::ops::Not;
-#[cfg(not(debug_assertions))]
-use axum::response::IntoResponse;
+use super::{api_auth::{authenticate, has_credentials}, app_error::{AppError, Problem}, principal::Principal, public_url::public_url};
+use crate::{acme::util::{b64, URL_SAFE_ENGINE}, Environment};
+use askama::Template;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
+/// Returns true for the routes nobody signs in to: the CA fetching challenges, the
+/// functions host's timer and health check, and the key vault events, which the functions
+/// host guards with a function key. Only these exact paths, the other routes have
+/// segments users choose.
+fn unauthenticated(path: &str) -> bool {
+    matches!(path, "/healthCheck" | "/checkCertificates" | "/keyVaultEvents") || path.starts_with("/.well-known/acme-challenge/")
+}
+
+/// Sends users who aren't signed in to the login page and hands the signed-in user with
+/// their role to the handlers, which decide what the role may do. Api clients sign in with
+/// a bearer token or api key instead.
 pub async fn auth(
-    request: Request,
+    State(env): State<Environment>,
+    mut request: Request,
     next: Next,
 ) -> Response {
 
-    if request.uri().path().contains("/.well-known/acme-challenge/") || 
-        request.uri().path().contains("/checkCertificates") || 
-        request.uri().path().contains("/healthCheck") 
-    {
+    if unauthenticated(request.uri().path()) {
         return next.run(request).await;
     }
 
+    let principal = match authenticate(&env, request.headers()).await {
+        Some(Ok(principal)) => Some(principal),
+        Some(Err(error)) => return error.into_response(),
+        None => Principal::from_headers(request.headers(), &env.roles),
+    };
+    let principal = match principal {
+        Some(principal) => principal,
+        // App Service authentication only exists in azure, locally the developer is the admin
+        None if cfg!(debug_assertions) => Principal::local(),
+        None => return login(&env, &request),
+    };
+    request.extensions_mut().insert(principal);
+
+    next.run(request).await
+}
+
+/// Redirects the user to the login page, which sends them back to where they were.
+fn login(env: &Environment, request: &Request) -> Response {
+    let return_url = public_url(env, request.headers(), request.uri().path());
+    let return_url: String = url::form_urlencoded::byte_serialize(return_url.as_bytes()).collect();
+    let login_url = format!("/.auth/login/aad?post_login_redirect_url={}", return_url);
+    if request.uri().path().starts_with("/api/") {
+        // api clients can't log in, they need a token or key
+        let message = "Please authenticate with a bearer token or api key".to_string();
+        AppError::Auth { status: StatusCode::UNAUTHORIZED, message }.into_response()
+    } else if request.headers().contains_key("hx-request") {
+        // tell htmx to redirect to login page
+        let message = "Please log in to continue".to_string();
+        let mut response = AppError::Auth { status: StatusCode::UNAUTHORIZED, message }.into_response();
+        response.headers_mut().insert("HX-Redirect", login_url.parse().unwrap());
+        response
+    } else {
+        // tell browser to redirect to login page
+        Redirect::to(&login_url).into_response()
+    }
+}
+
+/// The cookie holding the random session id the csrf tokens are derived from.
+const CSRF_COOKIE: &str = "csrf-session";
+/// The form field the dashboard's forms send the token in.
+const CSRF_FIELD: &str = "csrf_token";
+/// Scripts can send the token in this header instead.
+const CSRF_HEADER: &str = "x-csrf-token";
+/// Forms are small, anything larger isn't coming from the dashboard.
+const CSRF_BODY_LIMIT: usize = 64 * 1024;
 
-    #[cfg(not(debug_assertions))]
-    if request.headers().contains_key("x-ms-client-principal-id").not() {
-        // the user is not logged in
-        let login_url = format!("/.auth/login/aad?post_login_redirect_url=https://letsencrypt.larsen.farm{}", request.uri().path());
-        if request.headers().contains_key("hx-request") {
-            // tell htmx to redirect to login page
-            let mut headers = axum::http::HeaderMap::new();
-            headers.insert("HX-Redirect", login_url.parse().unwrap());
-            return (axum::http::StatusCode::UNAUTHORIZED, headers).into_response();
-        } else {
-            // tell browser to redirect to login page
-            return axum::response::Redirect::to(&login_url).into_response();
+/// The csrf token of the current session, handlers rendering forms put it in a hidden field.
+#[derive(Clone, Debug)]
+pub struct CsrfToken(pub String);
+
+/// Keys the session id and the logged in user, so a token is useless in any other session.
+fn csrf_mac(key: &[u8], session: &str, principal: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(session.as_bytes());
+    mac.update(b"\0");
+    mac.update(principal.as_bytes());
+    mac
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn csrf_rejected() -> Response {
+    let message = "The form has expired or didn't come from this site, please reload the page and try again".to_string();
+    AppError::Auth { status: StatusCode::FORBIDDEN, message }.into_response()
+}
+
+/// Protects the dashboard's forms from cross-site requests. Every browser session gets a
+/// random id in a cookie and the pages get a token derived from it, requests changing
+/// anything have to send the token back. Api clients authenticating with a bearer token
+/// or api key aren't affected, browsers never attach those on their own.
+pub async fn csrf(
+    State(env): State<Environment>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if unauthenticated(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    if has_credentials(request.headers()) {
+        return next.run(request).await;
+    }
+
+    let principal = request
+        .headers()
+        .get("x-ms-client-principal-id")
+        .and_then(|principal| principal.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let session = cookie(request.headers(), CSRF_COOKIE).map(|session| session.to_string());
+
+    let safe = matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !safe {
+        let Some(session) = &session else {
+            return csrf_rejected();
+        };
+
+        let (parts, body) = request.into_parts();
+        let body = match to_bytes(body, CSRF_BODY_LIMIT).await {
+            Ok(body) => body,
+            Err(_) => return AppError::Validation("The request body is too large".to_string()).into_response(),
+        };
+
+        let is_form = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/x-www-form-urlencoded"));
+        let token = match parts.headers.get(CSRF_HEADER).and_then(|token| token.to_str().ok()) {
+            Some(token) => Some(token.to_string()),
+            None if is_form => url::form_urlencoded::parse(&body)
+                .find(|(key, _)| key == CSRF_FIELD)
+                .map(|(_, token)| token.into_owned()),
+            None => None,
+        };
+        let valid = token
+            .and_then(|token| URL_SAFE_ENGINE.decode(token).ok())
+            .is_some_and(|token| csrf_mac(&env.csrf_key, session, &principal).verify_slice(&token).is_ok());
+        if !valid {
+            return csrf_rejected();
         }
+
+        request = Request::from_parts(parts, Body::from(body));
     }
 
-    next.run(request).await
+    // start a session for browsers that don't have one yet
+    let (session, new_session) = match session {
+        Some(session) => (session, false),
+        None => (b64(rand::random::<[u8; 32]>()), true),
+    };
+    let token = b64(csrf_mac(&env.csrf_key, &session, &principal).finalize().into_bytes());
+    request.extensions_mut().insert(CsrfToken(token));
+
+    let mut response = next.run(request).await;
+    if new_session {
+        let secure = if cfg!(debug_assertions) { "" } else { "; Secure" };
+        let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax{}", CSRF_COOKIE, session, secure);
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            response.headers_mut().append(header::SET_COOKIE, cookie);
+        }
+    }
+    response
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorPage {
+    title: String,
+    detail: String,
+}
+
+/// Returns true if the request comes from a browser rather than an api client.
+pub fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// Replaces the problem+json body of errors with a page when a browser is asking, so
+/// the dashboard shows a readable message instead of a json document.
+pub async fn error_pages(
+    request: Request,
+    next: Next,
+) -> Response {
+    let html = wants_html(request.headers());
+    let response = next.run(request).await;
+
+    let problem = match (html, response.extensions().get::<Problem>()) {
+        (true, Some(problem)) => problem.clone(),
+        _ => return response,
+    };
+
+    let page = ErrorPage { title: problem.title, detail: problem.detail };
+    match page.render() {
+        Ok(page) => (response.status(), Html(page)).into_response(),
+        Err(_) => response,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_environment;
+    use axum::{middleware::from_fn_with_state, routing::get, Extension, Router};
+    use std::sync::Arc;
+
+    const SESSION: &str = "session";
+    const FORM: &str = "application/x-www-form-urlencoded";
+
+    /// Starts a router with a form behind the csrf layer and returns its url. Getting the
+    /// form returns the token, posting it returns "changed".
+    async fn start() -> String {
+        let env: Environment = Arc::new(test_environment());
+        let app = Router::new()
+            .route("/form", get(|Extension(CsrfToken(token)): Extension<CsrfToken>| async move { token }).post(|| async { "changed" }))
+            .layer(from_fn_with_state(env, csrf));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://127.0.0.1:{}/form", listener.local_addr().unwrap().port());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    /// The token of `session` and `principal` with the key of the test environment.
+    fn token(session: &str, principal: &str) -> String {
+        b64(csrf_mac(b"test", session, principal).finalize().into_bytes())
+    }
+
+    fn post(url: &str, session: &str) -> reqwest::RequestBuilder {
+        reqwest::Client::new()
+            .post(url)
+            .header(header::COOKIE, format!("{}={}", CSRF_COOKIE, session))
+            .header(header::CONTENT_TYPE, FORM)
+    }
+
+    #[tokio::test]
+    async fn starts_a_session_on_get() {
+        let url = start().await;
+        let response = reqwest::get(&url).await.unwrap();
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap().to_string();
+        let session = cookie.strip_prefix("csrf-session=").unwrap().split(';').next().unwrap().to_string();
+        assert!(cookie.contains("HttpOnly") && cookie.contains("SameSite=Lax"), "{}", cookie);
+        assert_eq!(response.text().await.unwrap(), token(&session, ""));
+
+        // a browser with a session keeps it
+        let response = reqwest::Client::new().get(&url).header(header::COOKIE, format!("{}={}", CSRF_COOKIE, SESSION)).send().await.unwrap();
+        assert!(!response.headers().contains_key(header::SET_COOKIE));
+        assert_eq!(response.text().await.unwrap(), token(SESSION, ""));
+    }
+
+    #[tokio::test]
+    async fn rejects_posts_without_a_valid_token() {
+        let url = start().await;
+        let no_session = reqwest::Client::new().post(&url).body(format!("csrf_token={}", token(SESSION, ""))).header(header::CONTENT_TYPE, FORM);
+        let wrong_token = post(&url, SESSION).body("csrf_token=AAAA");
+        let other_session = post(&url, SESSION).body(format!("csrf_token={}", token("other", "")));
+        let other_principal = post(&url, SESSION).header("x-ms-client-principal-id", "bob").body(format!("csrf_token={}", token(SESSION, "alice")));
+        for request in [no_session, post(&url, SESSION), wrong_token, other_session, other_principal] {
+            assert_eq!(request.send().await.unwrap().status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_the_token_in_the_form_or_header() {
+        let url = start().await;
+        let form = post(&url, SESSION).header("x-ms-client-principal-id", "alice").body(format!("name=x&csrf_token={}", token(SESSION, "alice")));
+        let header = post(&url, SESSION).header(CSRF_HEADER, token(SESSION, ""));
+        for request in [form, header] {
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.text().await.unwrap(), "changed");
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_the_body() {
+        let url = start().await;
+        let body = format!("csrf_token={}&name={}", token(SESSION, ""), "x".repeat(CSRF_BODY_LIMIT));
+        let response = post(&url, SESSION).body(body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn exempts_api_clients() {
+        let url = start().await;
+        let bearer = reqwest::Client::new().post(&url).header(header::AUTHORIZATION, "Bearer token");
+        let api_key = reqwest::Client::new().post(&url).header("x-api-key", "key");
+        for request in [bearer, api_key] {
+            assert_eq!(request.send().await.unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn skips_only_the_exact_routes() {
+        for path in ["/healthCheck", "/checkCertificates", "/keyVaultEvents", "/.well-known/acme-challenge/token"] {
+            assert!(unauthenticated(path), "{} isn't skipped", path);
+        }
+        for path in [
+            "/api/v1/certificates/healthCheck",
+            "/certificates/keyVaultEvents",
+            "/jobs/checkCertificates",
+            "/api/v1/certificates/x/.well-known/acme-challenge/token",
+            "/healthCheck/x",
+            "/",
+        ] {
+            assert!(!unauthenticated(path), "{} is skipped", path);
+        }
+    }
 }
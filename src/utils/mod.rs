@@ -1,3 +1,6 @@
+pub mod api_auth;
 pub mod app_error;
 pub mod layers;
+pub mod principal;
+pub mod public_url;
 pub mod tracing;
\ No newline at end of file
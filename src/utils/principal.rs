@@ -0,0 +1,237 @@
+use super::app_error::AppError;
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::{request::Parts, HeaderMap, StatusCode}};
+use base64::{engine, Engine};
+use serde::Deserialize;
+use std::fmt;
+
+/// What a user may do, every role includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Sees certificates and jobs.
+    Viewer,
+    /// Issues and renews certificates and changes their renewal policy.
+    Operator,
+    /// Deletes and revokes certificates and manages the ACME account.
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// The Entra ID app roles or group object ids that grant each role.
+#[derive(Debug, Clone, Default)]
+pub struct RoleMapping {
+    pub viewer: Vec<String>,
+    pub operator: Vec<String>,
+    pub admin: Vec<String>,
+}
+
+impl RoleMapping {
+    /// Returns the highest role any of the user's app roles or groups grant.
+    pub fn role_of(&self, values: &[String]) -> Option<Role> {
+        let grants = |names: &[String]| values.iter().any(|value| names.contains(value));
+        if grants(&self.admin) {
+            Some(Role::Admin)
+        } else if grants(&self.operator) {
+            Some(Role::Operator)
+        } else if grants(&self.viewer) {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+}
+
+/// The claims blob App Service authentication passes on in `X-MS-CLIENT-PRINCIPAL`.
+#[derive(Debug, Deserialize)]
+struct ClientPrincipal {
+    #[serde(default)]
+    claims: Vec<Claim>,
+    role_typ: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claim {
+    typ: String,
+    val: String,
+}
+
+/// The signed-in user, the auth layer adds it to every request it lets through.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub id: String,
+    pub name: Option<String>,
+    /// None if the user is signed in but none of their app roles or groups is mapped.
+    pub role: Option<Role>,
+}
+
+impl Principal {
+    /// Reads the user App Service authentication signed in, None if nobody is signed in.
+    pub fn from_headers(headers: &HeaderMap, roles: &RoleMapping) -> Option<Self> {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let id = header("x-ms-client-principal-id")?.to_string();
+        let name = header("x-ms-client-principal-name").map(|name| name.to_string());
+
+        // the claims blob is base64 encoded json
+        let claims = header("x-ms-client-principal")
+            .and_then(|blob| engine::general_purpose::STANDARD.decode(blob).ok())
+            .and_then(|json| serde_json::from_slice::<ClientPrincipal>(&json).ok());
+        let values: Vec<String> = match claims {
+            Some(principal) => {
+                let role_typ = principal.role_typ.unwrap_or_else(|| "roles".to_string());
+                principal
+                    .claims
+                    .into_iter()
+                    .filter(|claim| claim.typ == role_typ || claim.typ == "roles" || claim.typ == "groups")
+                    .map(|claim| claim.val)
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        Some(Principal { id, name, role: roles.role_of(&values) })
+    }
+
+    /// The developer running the app locally, where there is no authentication.
+    pub fn local() -> Self {
+        Principal { id: "local".to_string(), name: None, role: Some(Role::Admin) }
+    }
+
+    /// The user's name, or their id if App Service didn't pass a name on.
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.id)
+    }
+
+    pub fn can(&self, role: Role) -> bool {
+        self.role.is_some_and(|own| own >= role)
+    }
+
+    /// Fails with 403 unless the user has at least `role`.
+    pub fn require(&self, role: Role) -> Result<(), AppError> {
+        match self.can(role) {
+            true => Ok(()),
+            false => Err(AppError::Auth {
+                status: StatusCode::FORBIDDEN,
+                message: format!("This requires the {} role", role),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Principal
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Principal>().cloned().ok_or_else(|| AppError::Auth {
+            status: StatusCode::UNAUTHORIZED,
+            message: "Please log in to continue".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const ROLE_TYP: &str = "http://schemas.microsoft.com/ws/2008/06/identity/claims/role";
+
+    fn mapping() -> RoleMapping {
+        RoleMapping {
+            viewer: vec!["Certificates.Read".to_string()],
+            operator: vec!["Certificates.Write".to_string(), "3f8e1c2a-0000-4000-8000-000000000001".to_string()],
+            admin: vec!["Certificates.Admin".to_string()],
+        }
+    }
+
+    fn headers(blob: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ms-client-principal-id", HeaderValue::from_static("00000000-0000-0000-0000-000000000042"));
+        headers.insert("x-ms-client-principal-name", HeaderValue::from_static("alice@example.com"));
+        if let Some(blob) = blob {
+            headers.insert("x-ms-client-principal", HeaderValue::from_str(blob).unwrap());
+        }
+        headers
+    }
+
+    fn blob(json: serde_json::Value) -> String {
+        engine::general_purpose::STANDARD.encode(json.to_string())
+    }
+
+    #[test]
+    fn reads_the_app_service_principal() {
+        // as App Service authentication sends it for an Entra ID user
+        let claims = blob(serde_json::json!({
+            "auth_typ": "aad",
+            "claims": [
+                { "typ": "aud", "val": "11111111-1111-1111-1111-111111111111" },
+                { "typ": "name", "val": "Alice" },
+                { "typ": ROLE_TYP, "val": "Certificates.Read" },
+                { "typ": ROLE_TYP, "val": "Certificates.Write" },
+            ],
+            "name_typ": "http://schemas.xmlsoap.org/ws/2005/05/identity/claims/emailaddress",
+            "role_typ": ROLE_TYP,
+        }));
+        let principal = Principal::from_headers(&headers(Some(&claims)), &mapping()).unwrap();
+        assert_eq!(principal.id, "00000000-0000-0000-0000-000000000042");
+        assert_eq!(principal.display_name(), "alice@example.com");
+        assert_eq!(principal.role, Some(Role::Operator));
+    }
+
+    #[test]
+    fn maps_roles_and_groups_claims() {
+        let roles = blob(serde_json::json!({ "claims": [{ "typ": "roles", "val": "Certificates.Admin" }] }));
+        assert_eq!(Principal::from_headers(&headers(Some(&roles)), &mapping()).unwrap().role, Some(Role::Admin));
+
+        let groups = blob(serde_json::json!({ "claims": [{ "typ": "groups", "val": "3f8e1c2a-0000-4000-8000-000000000001" }] }));
+        assert_eq!(Principal::from_headers(&headers(Some(&groups)), &mapping()).unwrap().role, Some(Role::Operator));
+
+        // other claims don't grant anything, even with a mapped value
+        let other = blob(serde_json::json!({ "claims": [{ "typ": "name", "val": "Certificates.Admin" }] }));
+        assert_eq!(Principal::from_headers(&headers(Some(&other)), &mapping()).unwrap().role, None);
+    }
+
+    #[test]
+    fn ignores_malformed_claims() {
+        let not_json = engine::general_purpose::STANDARD.encode("not json");
+        for blob in ["not base64!", not_json.as_str()] {
+            let principal = Principal::from_headers(&headers(Some(blob)), &mapping()).unwrap();
+            assert_eq!(principal.role, None);
+        }
+        assert_eq!(Principal::from_headers(&headers(None), &mapping()).unwrap().role, None);
+        assert!(Principal::from_headers(&HeaderMap::new(), &mapping()).is_none());
+    }
+
+    #[test]
+    fn grants_the_highest_mapped_role() {
+        let values = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        assert_eq!(mapping().role_of(&values(&["Certificates.Read", "Certificates.Admin", "Certificates.Write"])), Some(Role::Admin));
+        assert_eq!(mapping().role_of(&values(&["Certificates.Read", "Certificates.Write"])), Some(Role::Operator));
+        assert_eq!(mapping().role_of(&values(&["Certificates.Read"])), Some(Role::Viewer));
+        assert_eq!(mapping().role_of(&values(&["Other", "certificates.admin"])), None);
+        assert_eq!(mapping().role_of(&[]), None);
+    }
+
+    #[test]
+    fn roles_include_the_lower_ones() {
+        assert!(Role::Viewer < Role::Operator && Role::Operator < Role::Admin);
+        let principal = |role| Principal { id: "id".to_string(), name: None, role };
+        assert!(principal(Some(Role::Admin)).require(Role::Operator).is_ok());
+        assert!(principal(Some(Role::Operator)).require(Role::Operator).is_ok());
+        assert!(principal(Some(Role::Operator)).require(Role::Admin).is_err());
+        assert!(principal(Some(Role::Viewer)).require(Role::Operator).is_err());
+        assert!(principal(None).require(Role::Viewer).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::Environment;
+use axum::http::{header, HeaderMap};
+use url::Url;
+
+fn forwarded<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        // proxies append themselves, the first entry is what the user asked for
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+/// Returns the url users reach the app at, without a trailing slash. `PUBLIC_URL` wins if
+/// it is set, otherwise the url is taken from the headers the Functions front end forwards.
+/// Empty if the headers don't make up a valid url, so links stay relative.
+pub fn base_url(env: &Environment, headers: &HeaderMap) -> String {
+    if let Some(public_url) = &env.public_url {
+        return public_url.clone();
+    }
+
+    // locally the functions host serves plain http, in azure the front end terminates tls
+    let default_proto = if cfg!(debug_assertions) { "http" } else { "https" };
+    let proto = match forwarded(headers, "x-forwarded-proto") {
+        Some(proto @ ("http" | "https")) => proto,
+        _ => default_proto,
+    };
+    let host = forwarded(headers, "x-forwarded-host")
+        .or_else(|| headers.get(header::HOST).and_then(|host| host.to_str().ok()));
+
+    match host.map(|host| Url::parse(&format!("{}://{}", proto, host))) {
+        // a host with a path, query or credentials in it isn't a host
+        Some(Ok(url)) if url.path() == "/" && url.query().is_none() && url.username().is_empty() => {
+            url.as_str().trim_end_matches('/').to_string()
+        }
+        _ => String::new(),
+    }
+}
+
+/// Returns the public url of `path`, which has to start with a slash.
+pub fn public_url(env: &Environment, headers: &HeaderMap, path: &str) -> String {
+    format!("{}{}", base_url(env, headers), path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_environment, EnvironmentInner};
+    use axum::http::HeaderValue;
+    use std::sync::Arc;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (header::HeaderName::from_static(name), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn prefers_the_configured_url() {
+        let env = Arc::new(EnvironmentInner { public_url: Some("https://certs.example.com".to_string()), ..test_environment() });
+        let headers = headers(&[("host", "app.azurewebsites.net")]);
+        assert_eq!(public_url(&env, &headers, "/jobs/1"), "https://certs.example.com/jobs/1");
+    }
+
+    #[test]
+    fn takes_the_url_from_forwarded_headers() {
+        let env = Arc::new(test_environment());
+        let forwarded = headers(&[
+            ("host", "localhost:7071"),
+            ("x-forwarded-host", "certs.example.com, proxy.internal"),
+            ("x-forwarded-proto", "https"),
+        ]);
+        assert_eq!(public_url(&env, &forwarded, "/audit"), "https://certs.example.com/audit");
+
+        let host = headers(&[("host", "app.azurewebsites.net:8443"), ("x-forwarded-proto", "http")]);
+        assert_eq!(base_url(&env, &host), "http://app.azurewebsites.net:8443");
+    }
+
+    #[test]
+    fn ignores_unknown_protocols() {
+        let env = Arc::new(test_environment());
+        let headers = headers(&[("host", "certs.example.com"), ("x-forwarded-proto", "javascript")]);
+        let proto = if cfg!(debug_assertions) { "http" } else { "https" };
+        assert_eq!(base_url(&env, &headers), format!("{}://certs.example.com", proto));
+    }
+
+    #[test]
+    fn stays_relative_without_a_valid_host() {
+        let env = Arc::new(test_environment());
+        assert_eq!(public_url(&env, &HeaderMap::new(), "/"), "/");
+        for host in ["evil.example.com/path", "user@evil.example.com", "evil.example.com?x=1", "exa mple.com"] {
+            let headers = headers(&[("x-forwarded-host", host)]);
+            assert_eq!(base_url(&env, &headers), "", "{} was accepted", host);
+        }
+    }
+}
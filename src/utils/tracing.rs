@@ -5,6 +5,7 @@ use time::OffsetDateTime;
 use uuid::Uuid;
 use tracing::instrument::WithSubscriber;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
+use time::ext::InstantExt;
 use std::sync::{Arc, Mutex};
 use tracing::Level;
 use tracing_subscriber::Layer;
@@ -25,12 +26,12 @@ pub async fn cosmos_tracing(
     let method = request.method().to_string();
 
     let time = OffsetDateTime::now_utc();
-    let start = time::Instant::now();
+    let start = std::time::Instant::now();
 
     // run handler
     let response = next.run(request).with_subscriber(subscriber).await;
 
-    let duration = start.elapsed();
+    let duration = std::time::Instant::now().signed_duration_since(start);
 
     // saving traces to db
     let collection = env.collection_client("traces");
@@ -118,11 +119,11 @@ struct CustomVisitor(Vec<String>);
 
 impl CustomVisitor {
     fn new() -> Self {
-        Self { 0: Vec::new() }
+        Self(Vec::new())
     }
 }
 
-impl<'a> tracing::field::Visit for CustomVisitor {
+impl tracing::field::Visit for CustomVisitor {
     fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
         self.0.push(format!("{}: {} ", field.name(), value));
     }